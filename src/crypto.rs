@@ -1,75 +1,532 @@
 extern crate ring;
+extern crate untrusted;
+extern crate argon2;
+extern crate x25519_dalek;
+extern crate flate2;
+extern crate data_encoding;
 
 use std;
+use std::cell::{Cell, RefCell};
 use std::io::{Read, Write};
+use std::sync::atomic;
+use std::time::{Duration, Instant};
 
-use self::ring::{aead, digest, pbkdf2};
+use self::ring::{aead, agreement, constant_time, digest, hmac, pbkdf2};
 use self::ring::rand::{SecureRandom, SystemRandom};
+use self::flate2::Compression;
+use self::flate2::read::DeflateDecoder;
+use self::flate2::write::DeflateEncoder;
+
+use bincode;
 
 use error::{Error, Result};
+use shamir;
 
-#[derive(Debug, Clone, PartialEq)]
+/// Version of the `Encrypted` envelope layout written by this code
+pub const ENVELOPE_VERSION: u8 = 3;
+
+/// Envelope flag, the plaintext was deflated before sealing. The deflated
+/// bytes follow the plaintext length, inflating stops there.
+pub const FLAG_COMPRESSED: u8 = 1 << 0;
+/// Envelope flag, the plaintext was padded to a size bucket before sealing
+pub const FLAG_PADDED: u8 = 1 << 1;
+
+/// Padded plaintexts are never smaller than this
+const MIN_PAD_BUCKET: usize = 256;
+
+/// Identifies the key a ciphertext was sealed with without revealing the key
+pub type KeyId = [u8; 64 / 8];
+
+/// Key material is wiped when dropped and never shown by `Debug`
+#[derive(Clone, PartialEq, Eq)]
 pub struct MasterKey([u8; 256 / 8]);
 
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MasterKey(id: {:?})", self.id())
+    }
+}
+
+impl MasterKey {
+    /// A key derived from a shared secret that is not a key itself, such as
+    /// the key hashing a log's ref names. `purpose` keeps keys derived from
+    /// the same secret for different uses apart.
+    pub fn from_secret(secret: &[u8], purpose: &[u8]) -> Self {
+        let signing_key = hmac::SigningKey::new(&digest::SHA256, secret);
+        let mut key = MasterKey([0u8; 256 / 8]);
+        key.0.copy_from_slice(hmac::sign(&signing_key, purpose).as_ref());
+        key
+    }
+
+    /// A fresh random key, used as the data key behind a `KeyRecord`
+    pub fn generate() -> Result<Self> {
+        Ok(MasterKey(rand_256()?))
+    }
+
+    /// Derives the id of this key, every device holding the key agrees on it.
+    pub fn id(&self) -> KeyId {
+        let mut id = [0u8; 64 / 8];
+        id.copy_from_slice(&self.derive(b"hermitdb key id")[..64 / 8]);
+        id
+    }
+
+    /// A keyed hash (HMAC-SHA256) of `data`. Equal inputs hash equally, so
+    /// the hash can stand in for a secret name wherever lookups are needed.
+    ///
+    /// The hash key is derived from the master key rather than being the
+    /// master key itself, keeping it apart from the encryption key.
+    pub fn keyed_hash(&self, data: &[u8]) -> Vec<u8> {
+        let hash_key = hmac::SigningKey::new(&digest::SHA256, &self.derive(b"hermitdb keyed hash"));
+        hmac::sign(&hash_key, data).as_ref().to_vec()
+    }
+
+    /// Lets a password be checked without decrypting anything with the key
+    pub fn check_value(&self) -> Vec<u8> {
+        self.derive(b"hermitdb key check")
+    }
+
+    fn derive(&self, purpose: &[u8]) -> Vec<u8> {
+        let key = hmac::SigningKey::new(&digest::SHA256, &self.0);
+        hmac::sign(&key, purpose).as_ref().to_vec()
+    }
+}
+
+/// Version of the `KdfHeader` layout written by this code
+pub const KDF_HEADER_VERSION: u8 = 1;
+
+/// The password hashing algorithms a `MasterKey` can be derived with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfAlgo {
+    Pbkdf2Sha256 {
+        iters: u32
+    },
+    /// Memory-hard, prefer this for new keys
+    Argon2id {
+        /// Memory cost in KiB
+        mem_cost: u32,
+        time_cost: u32,
+        lanes: u32
+    }
+}
+
+impl KdfAlgo {
+    /// Argon2id with 64 MiB of memory and 3 passes
+    pub fn recommended() -> Self {
+        KdfAlgo::Argon2id {
+            mem_cost: 64 * 1024,
+            time_cost: 3,
+            lanes: 1
+        }
+    }
+}
+
+/// Everything besides the password and entropy needed to re-derive a
+/// `MasterKey`, this is safe to store alongside the data it protects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfHeader {
+    pub version: u8,
+    pub algo: KdfAlgo,
+    pub salt: [u8; 256 / 8]
+}
+
+impl KdfHeader {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let header: KdfHeader = bincode::deserialize(bytes)?;
+        if header.version != KDF_HEADER_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported kdf header version: {}", header.version)
+            ));
+        }
+        Ok(header)
+    }
+}
+
 pub struct KDF {
-    pub pbkdf2_iters: u32,
+    pub algo: KdfAlgo,
     pub salt: [u8; 256 / 8],
     pub entropy: [u8; 256 / 8]
 }
 
 impl KDF {
-    pub fn master_key(&self, pass: &[u8]) -> MasterKey {
+    /// A KDF with the recommended algorithm and a fresh salt
+    pub fn new(entropy: [u8; 256 / 8]) -> Result<Self> {
+        Ok(KDF {
+            algo: KdfAlgo::recommended(),
+            salt: rand_256()?,
+            entropy
+        })
+    }
+
+    /// Rebuild the KDF described by a header
+    pub fn from_header(header: &KdfHeader, entropy: [u8; 256 / 8]) -> Self {
+        KDF {
+            algo: header.algo.clone(),
+            salt: header.salt,
+            entropy
+        }
+    }
+
+    pub fn header(&self) -> KdfHeader {
+        KdfHeader {
+            version: KDF_HEADER_VERSION,
+            algo: self.algo.clone(),
+            salt: self.salt
+        }
+    }
+
+    /// Move to new KDF parameters, a fresh salt is generated.
+    ///
+    /// The upgraded KDF derives a different key from the same password, so
+    /// anything sealed under the old key must be rotated to the new one,
+    /// see `Session::rotate_to`.
+    pub fn upgrade(&self, algo: KdfAlgo) -> Result<KDF> {
+        Ok(KDF {
+            algo,
+            salt: rand_256()?,
+            entropy: self.entropy
+        })
+    }
+
+    pub fn master_key(&self, pass: &[u8]) -> Result<MasterKey> {
         let mut salt: Vec<u8> = Vec::with_capacity(512 / 8);
         salt.extend_from_slice(&self.entropy);
         salt.extend_from_slice(&self.salt);
 
         let mut master_key = MasterKey([0u8; 256 / 8]);
-        pbkdf2::derive(
-            &digest::SHA256,
-            self.pbkdf2_iters,
-            &salt,
-            &pass,
-            &mut master_key.0
-        );
-        master_key
+        match self.algo {
+            KdfAlgo::Pbkdf2Sha256 { iters } => {
+                pbkdf2::derive(
+                    &digest::SHA256,
+                    iters,
+                    &salt,
+                    pass,
+                    &mut master_key.0
+                );
+            },
+            KdfAlgo::Argon2id { mem_cost, time_cost, lanes } => {
+                let config = argon2::Config {
+                    variant: argon2::Variant::Argon2id,
+                    version: argon2::Version::Version13,
+                    mem_cost,
+                    time_cost,
+                    lanes,
+                    thread_mode: argon2::ThreadMode::Sequential,
+                    secret: &[],
+                    ad: &[],
+                    hash_length: 256 / 8
+                };
+                let hash = argon2::hash_raw(pass, &salt, &config)
+                    .map_err(|e| Error::Crypto(format!("Argon2 failed: {}", e)))?;
+                master_key.0.copy_from_slice(&hash);
+            }
+        }
+        Ok(master_key)
     }
 }
 
-#[derive(Debug)]
+/// The keys of an unlocked database.
+///
+/// Keys are wiped from memory when the session is locked or dropped. A
+/// session opened from a `Keyfile` can be unlocked again with the password.
+/// With an idle timeout set, a session that hasn't handed out a key for
+/// that long wipes its keys the moment a key is asked for, and refuses to
+/// hand any out until it's unlocked again. Keys are handed out as copies,
+/// which are wiped when dropped.
 pub struct Session {
     pub actor: u128,
-    pub master_key: MasterKey
+    /// New ciphertexts are sealed with this key, None while locked
+    master_key: RefCell<Option<MasterKey>>,
+    /// Keys we were rotated away from, they are only used to decrypt
+    /// ciphertexts that have not been re-encrypted yet.
+    old_keys: RefCell<Vec<MasterKey>>,
+    /// How plaintexts are prepared before they are sealed
+    pub seal_opts: SealOptions,
+    unlocker: Option<Unlocker>,
+    idle_timeout: Option<Duration>,
+    last_used: Cell<Instant>
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("actor", &self.actor)
+            .field("locked", &self.is_locked())
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+/// What a locked session re-derives its key from
+enum Unlocker {
+    Keyfile(Keyfile),
+    /// The record and the entropy its key encryption key is derived with
+    KeyRecord(KeyRecord, [u8; 256 / 8])
+}
+
+/// Plaintext preparation applied by `Plaintext::encrypt`.
+///
+/// Padding rounds the sealed size up to a power of two so that ciphertext
+/// lengths only reveal a size bucket. Compression shrinks data before it's
+/// padded, but its output size depends on content so it's off by default,
+/// only turn it on when attacker supplied data isn't sealed alongside
+/// secrets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealOptions {
+    pub compress: bool,
+    pub pad: bool
+}
+
+impl Default for SealOptions {
+    fn default() -> Self {
+        SealOptions {
+            compress: false,
+            pad: true
+        }
+    }
+}
+
+impl Session {
+    pub fn new(actor: u128, master_key: MasterKey) -> Self {
+        Session {
+            actor,
+            master_key: RefCell::new(Some(master_key)),
+            old_keys: RefCell::new(Vec::new()),
+            seal_opts: SealOptions::default(),
+            unlocker: None,
+            idle_timeout: None,
+            last_used: Cell::new(Instant::now())
+        }
+    }
+
+    /// Lets `unlock` re-derive the key from a password
+    pub fn with_keyfile(mut self, keyfile: Keyfile) -> Self {
+        self.unlocker = Some(Unlocker::Keyfile(keyfile));
+        self
+    }
+
+    /// Lets `unlock` unseal the data key from `record` with a password
+    pub fn with_key_record(mut self, record: KeyRecord, entropy: [u8; 256 / 8]) -> Self {
+        self.set_key_record(record, entropy);
+        self
+    }
+
+    /// Unlock with `record` from now on, e.g. after a passphrase change
+    pub fn set_key_record(&mut self, record: KeyRecord, entropy: [u8; 256 / 8]) {
+        self.unlocker = Some(Unlocker::KeyRecord(record, entropy));
+    }
+
+    /// Also decrypt with `old_keys`, as if the session had been rotated away
+    /// from them
+    pub fn with_old_keys(self, old_keys: &[MasterKey]) -> Self {
+        for old_key in old_keys.iter() {
+            self.keep_old_key(old_key.clone());
+        }
+        self
+    }
+
+    /// Lock the session once it goes unused for `timeout`, None disables it
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+        self.last_used.set(Instant::now());
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.master_key.borrow().is_none() || self.is_idle()
+    }
+
+    /// Wipe every key, they are needed again before anything is sealed or opened
+    pub fn lock(&mut self) {
+        self.wipe_keys();
+    }
+
+    /// Wipe the keys if the idle timeout passed, returns true if locked
+    pub fn lock_if_idle(&mut self) -> bool {
+        if self.is_idle() {
+            self.wipe_keys();
+        }
+        self.master_key.borrow().is_none()
+    }
+
+    /// Re-derive the key from the password, Err(WrongPassword) if it's wrong
+    pub fn unlock(&mut self, pass: &[u8]) -> Result<()> {
+        let master_key = match self.unlocker {
+            Some(Unlocker::Keyfile(ref keyfile)) => keyfile.master_key(pass)?,
+            Some(Unlocker::KeyRecord(ref record, entropy)) => record.unseal(entropy, pass)?,
+            None => return Err(Error::State("Session has no keyfile to unlock with".into()))
+        };
+        *self.master_key.borrow_mut() = Some(master_key);
+        self.last_used.set(Instant::now());
+        Ok(())
+    }
+
+    /// New ciphertexts are sealed with this key, Err(Locked) while locked.
+    /// The keys are wiped here if the session went idle.
+    pub fn master_key(&self) -> Result<MasterKey> {
+        if self.is_idle() {
+            self.wipe_keys();
+            return Err(Error::Locked);
+        }
+        match *self.master_key.borrow() {
+            Some(ref key) => {
+                self.last_used.set(Instant::now());
+                Ok(key.clone())
+            },
+            None => Err(Error::Locked)
+        }
+    }
+
+    /// Keys we were rotated away from and still decrypt with
+    pub fn old_keys(&self) -> Result<Vec<MasterKey>> {
+        self.master_key()?;
+        Ok(self.old_keys.borrow().clone())
+    }
+
+    /// Keyed hash under the current master key, see `MasterKey::keyed_hash`
+    pub fn keyed_hash(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.master_key()?.keyed_hash(data))
+    }
+
+    /// Start sealing with `new_key`, the current key is kept for decryption
+    /// until `retire_old_keys` is called.
+    pub fn rotate_to(&mut self, new_key: MasterKey) -> Result<()> {
+        let old_key = self.master_key()?;
+        *self.master_key.borrow_mut() = Some(new_key);
+        self.keep_old_key(old_key);
+        Ok(())
+    }
+
+    /// Forget the old keys once everything is re-encrypted under the current key
+    pub fn retire_old_keys(&mut self) {
+        self.old_keys.borrow_mut().clear();
+    }
+
+    /// Every key this session can decrypt with, current key first
+    pub fn keys(&self) -> Result<Vec<MasterKey>> {
+        let mut keys = vec![self.master_key()?];
+        keys.extend(self.old_keys.borrow().iter().cloned());
+        Ok(keys)
+    }
+
+    fn key_by_id(&self, id: &KeyId) -> Result<Option<MasterKey>> {
+        Ok(self.keys()?.into_iter().find(|key| &key.id() == id))
+    }
+
+    fn keep_old_key(&self, old_key: MasterKey) {
+        let is_current = self.master_key.borrow().as_ref() == Some(&old_key);
+        let mut old_keys = self.old_keys.borrow_mut();
+        if !is_current && !old_keys.contains(&old_key) {
+            old_keys.push(old_key);
+        }
+    }
+
+    fn wipe_keys(&self) {
+        *self.master_key.borrow_mut() = None;
+        self.old_keys.borrow_mut().clear();
+    }
+
+    fn is_idle(&self) -> bool {
+        match self.idle_timeout {
+            Some(timeout) => self.last_used.get().elapsed() >= timeout,
+            None => false
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Plaintext(pub Vec<u8>);
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Where a ciphertext belongs. It's authenticated along with the envelope
+/// so a ciphertext only opens in the context it was sealed for, one that is
+/// replayed, reordered or moved to another key fails to decrypt.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Context {
+    /// Version of the layout of the sealed data
+    pub schema: u8,
+    /// The actor who sealed it
+    pub actor: Vec<u8>,
+    /// Where in the actor's history it was sealed, a sequence number or the
+    /// id of the commit it follows
+    pub position: Vec<u8>,
+    /// The key or purpose it's stored under
+    pub name: Vec<u8>
+}
+
+impl Context {
+    pub fn named(schema: u8, name: &[u8]) -> Self {
+        Context {
+            schema,
+            name: name.to_vec(),
+            ..Context::default()
+        }
+    }
+
+    pub fn with_actor(mut self, actor: &[u8]) -> Self {
+        self.actor = actor.to_vec();
+        self
+    }
+
+    pub fn at(mut self, position: &[u8]) -> Self {
+        self.position = position.to_vec();
+        self
+    }
+
+    /// Fields are length prefixed so that no two contexts encode the same
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.schema];
+        for field in [&self.actor, &self.position, &self.name].iter() {
+            bytes.extend_from_slice(&u32_to_bytes(field.len() as u32));
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Encrypted{
+    pub version: u8,
+    /// `FLAG_*` bits describing how the plaintext was prepared
+    pub flags: u8,
+    pub key_id: KeyId,
     pub nonce: [u8; 96/8],
     pub ciphertext: Vec<u8>,
 }
 
 impl Plaintext {
-    pub fn encrypt(&mut self, sess: &Session) -> Result<Encrypted> {
-        // TAI: compress before encrypt
+    pub fn encrypt(&mut self, sess: &Session, ctx: &Context) -> Result<Encrypted> {
+        let (flags, prepared) = prepare(&self.0, &sess.seal_opts)?;
+
         let aead_algo = &aead::CHACHA20_POLY1305;
-        let seal_key = aead::SealingKey::new(aead_algo, &sess.master_key.0)
+        let master_key = sess.master_key()?;
+        let seal_key = aead::SealingKey::new(aead_algo, &master_key.0)
             .map_err(|_| Error::Crypto("Failed to generate a sealing key".into()))?;
 
 
         let mut cryptic = Encrypted {
+            version: ENVELOPE_VERSION,
+            flags,
+            key_id: master_key.id(),
             nonce: rand_96()?,
-            ciphertext: Vec::with_capacity(self.0.len() + aead_algo.tag_len())
+            ciphertext: Vec::with_capacity(prepared.len() + aead_algo.tag_len())
         };
         
-        cryptic.ciphertext.extend(&self.0);
+        cryptic.ciphertext.extend(&prepared);
         cryptic.ciphertext.extend(vec![0u8; aead_algo.tag_len()]);
 
+        let ad = cryptic.associated_data(ctx);
         aead::seal_in_place(
             &seal_key,               // key
             &cryptic.nonce,          // nonce
-            &cryptic.nonce,          // ad
+            &ad,                     // ad
             &mut cryptic.ciphertext, // plaintext
             seal_key.algorithm().tag_len()
         ).map_err(|_| Error::Crypto("Failed to encrypt with AEAD".into()))?;
@@ -79,9 +536,23 @@ impl Plaintext {
 }
 
 impl Encrypted {
-    pub fn decrypt(&self, sess: &Session) -> Result<Plaintext> {
+    pub fn decrypt(&self, sess: &Session, ctx: &Context) -> Result<Plaintext> {
+        if self.version != ENVELOPE_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported ciphertext envelope version: {}", self.version)
+            ));
+        }
+        if self.flags & !(FLAG_COMPRESSED | FLAG_PADDED) != 0 {
+            return Err(Error::Version(
+                format!("Unsupported ciphertext envelope flags: {:#x}", self.flags)
+            ));
+        }
+
+        let key = sess.key_by_id(&self.key_id)?
+            .ok_or(Error::Crypto("Session has no key matching the ciphertext key id".into()))?;
+
         let aead_algo = &aead::CHACHA20_POLY1305;
-        let opening_key = aead::OpeningKey::new(aead_algo, &sess.master_key.0)
+        let opening_key = aead::OpeningKey::new(aead_algo, &key.0)
             .map_err(|_| Error::Crypto("Failed to create key when decrypting".into()))?;
 
         let mut in_out = Vec::with_capacity(self.ciphertext.len());
@@ -90,15 +561,638 @@ impl Encrypted {
         let plain = aead::open_in_place(
             &opening_key,
             &self.nonce,
-            &self.nonce,
+            &self.associated_data(ctx),
             0,
             &mut in_out
         ).map_err(|_| Error::Crypto("Failed to decrypt".into()))?;
 
-        Ok(Plaintext(plain.to_vec()))
+        Ok(Plaintext(unprepare(self.flags, plain)?))
+    }
+
+    /// True if this was sealed with the session's current key
+    pub fn is_current(&self, sess: &Session) -> bool {
+        sess.master_key()
+            .map(|key| key.id() == self.key_id)
+            .unwrap_or(false)
+    }
+
+    /// Seal the plaintext again under the session's current key, this is how
+    /// ciphertexts are carried over during a key rotation.
+    pub fn reencrypt(&self, sess: &Session, ctx: &Context) -> Result<Encrypted> {
+        self.decrypt(sess, ctx)?.encrypt(sess, ctx)
+    }
+
+    /// The envelope header and the context are authenticated along with
+    /// the ciphertext
+    fn associated_data(&self, ctx: &Context) -> Vec<u8> {
+        let mut ad = Vec::with_capacity(2 + self.key_id.len() + self.nonce.len());
+        ad.push(self.version);
+        ad.push(self.flags);
+        ad.extend_from_slice(&self.key_id);
+        ad.extend_from_slice(&self.nonce);
+        ad.extend(ctx.to_bytes());
+        ad
+    }
+}
+
+/// Compress and pad a plaintext as the options ask, returns the envelope flags
+fn prepare(plain: &[u8], opts: &SealOptions) -> Result<(u8, Vec<u8>)> {
+    let mut flags = 0;
+    let mut bytes = plain.to_vec();
+
+    if opts.compress && plain.len() <= u32::MAX as usize {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plain)?;
+        let mut compressed = u32_to_bytes(plain.len() as u32).to_vec();
+        compressed.extend(encoder.finish()?);
+        // incompressible data is sealed as is
+        if compressed.len() < bytes.len() {
+            flags |= FLAG_COMPRESSED;
+            bytes = compressed;
+        }
+    }
+
+    if opts.pad {
+        flags |= FLAG_PADDED;
+        // a single 0x80 marks where the padding starts
+        let padded_len = std::cmp::max(bytes.len() + 1, MIN_PAD_BUCKET).next_power_of_two();
+        bytes.push(0x80);
+        bytes.resize(padded_len, 0);
+    }
+
+    Ok((flags, bytes))
+}
+
+/// Undo `prepare` given the flags it returned
+fn unprepare(flags: u8, prepared: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = prepared;
+
+    if flags & FLAG_PADDED != 0 {
+        let marker = bytes.iter().rposition(|b| *b != 0);
+        match marker {
+            Some(i) if bytes[i] == 0x80 => bytes = &bytes[..i],
+            _ => return Err(Error::Crypto("Malformed plaintext padding".into()))
+        }
+    }
+
+    if flags & FLAG_COMPRESSED != 0 {
+        if bytes.len() < 4 {
+            return Err(Error::Crypto("Malformed compressed plaintext".into()));
+        }
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&bytes[..4]);
+        let declared_len = bytes_to_u32(&len_bytes) as usize;
+
+        // never inflate past the declared length
+        let mut inflated = Vec::new();
+        DeflateDecoder::new(&bytes[4..])
+            .take(declared_len as u64 + 1)
+            .read_to_end(&mut inflated)?;
+        if inflated.len() != declared_len {
+            return Err(Error::Crypto("Compressed plaintext does not match its length".into()));
+        }
+        return Ok(inflated);
+    }
+
+    Ok(bytes.to_vec())
+}
+
+/// Version of the `WrappedKey` layout written by this code
+pub const WRAPPED_KEY_VERSION: u8 = 1;
+
+/// An X25519 public key, data keys are wrapped for its holder
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; 256 / 8]);
+
+/// The X25519 secret of a recipient, it unwraps keys shared with them.
+///
+/// ring only hands out single use X25519 keys, so the long lived
+/// recipient side of the agreement is done with x25519-dalek.
+#[derive(Clone)]
+pub struct SecretKey([u8; 256 / 8]);
+
+impl SecretKey {
+    pub fn generate() -> Result<Self> {
+        Ok(SecretKey(rand_256()?))
+    }
+
+    pub fn from_bytes(bytes: [u8; 256 / 8]) -> Self {
+        SecretKey(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 256 / 8] {
+        self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::x25519(self.0, x25519_dalek::X25519_BASEPOINT_BYTES))
+    }
+
+    /// Recover a key that was wrapped for our public key
+    pub fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<MasterKey> {
+        if wrapped.version != WRAPPED_KEY_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported wrapped key version: {}", wrapped.version)
+            ));
+        }
+        if wrapped.recipient != self.public_key() {
+            return Err(Error::Crypto("Key was wrapped for a different recipient".into()));
+        }
+
+        let shared = x25519_dalek::x25519(self.0, wrapped.ephemeral.0);
+        if constant_time::verify_slices_are_equal(&shared, &[0u8; 256 / 8]).is_ok() {
+            return Err(Error::Crypto("Wrapped key has a degenerate ephemeral key".into()));
+        }
+        let kek = wrapping_key(&shared, &wrapped.ephemeral, &wrapped.recipient);
+
+        let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &kek)
+            .map_err(|_| Error::Crypto("Failed to create key when unwrapping".into()))?;
+        let mut in_out = wrapped.ciphertext.clone();
+        let plain = aead::open_in_place(
+            &opening_key,
+            &wrapped.nonce,
+            &wrapped.associated_data(),
+            0,
+            &mut in_out
+        ).map_err(|_| Error::Crypto("Failed to unwrap key".into()))?;
+
+        if plain.len() != 256 / 8 {
+            return Err(Error::Crypto("Unwrapped key has the wrong length".into()));
+        }
+        let mut master_key = MasterKey([0u8; 256 / 8]);
+        master_key.0.copy_from_slice(plain);
+        if master_key.id() != wrapped.key_id {
+            return Err(Error::Crypto("Unwrapped key does not match its key id".into()));
+        }
+        Ok(master_key)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SecretKey({:?})", self.public_key())
+    }
+}
+
+/// A `MasterKey` sealed for a single recipient under an ephemeral-static
+/// X25519 agreement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub version: u8,
+    pub recipient: PublicKey,
+    pub ephemeral: PublicKey,
+    /// Id of the wrapped key, see `MasterKey::id`
+    pub key_id: KeyId,
+    pub nonce: [u8; 96 / 8],
+    pub ciphertext: Vec<u8>
+}
+
+impl WrappedKey {
+    /// Wrap `key` so that only the holder of `recipient`'s secret can unwrap it
+    pub fn wrap(key: &MasterKey, recipient: &PublicKey) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let ephemeral_secret = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+            .map_err(|_| Error::Crypto("Failed to generate an ephemeral key".into()))?;
+        let mut ephemeral = PublicKey([0u8; 256 / 8]);
+        ephemeral_secret.compute_public_key(&mut ephemeral.0)
+            .map_err(|_| Error::Crypto("Failed to compute an ephemeral public key".into()))?;
+
+        let kek = agreement::agree_ephemeral(
+            ephemeral_secret,
+            &agreement::X25519,
+            untrusted::Input::from(&recipient.0),
+            Error::Crypto("Key agreement with the recipient failed".into()),
+            |shared| Ok(wrapping_key(shared, &ephemeral, recipient))
+        )?;
+
+        let aead_algo = &aead::CHACHA20_POLY1305;
+        let seal_key = aead::SealingKey::new(aead_algo, &kek)
+            .map_err(|_| Error::Crypto("Failed to generate a wrapping key".into()))?;
+
+        let mut wrapped = WrappedKey {
+            version: WRAPPED_KEY_VERSION,
+            recipient: recipient.clone(),
+            ephemeral,
+            key_id: key.id(),
+            nonce: rand_96()?,
+            ciphertext: Vec::with_capacity(key.0.len() + aead_algo.tag_len())
+        };
+        wrapped.ciphertext.extend(&key.0);
+        wrapped.ciphertext.extend(vec![0u8; aead_algo.tag_len()]);
+
+        let ad = wrapped.associated_data();
+        aead::seal_in_place(
+            &seal_key,
+            &wrapped.nonce,
+            &ad,
+            &mut wrapped.ciphertext,
+            aead_algo.tag_len()
+        ).map_err(|_| Error::Crypto("Failed to wrap key".into()))?;
+
+        Ok(wrapped)
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        let mut ad = Vec::with_capacity(1 + 2 * 256 / 8 + self.key_id.len());
+        ad.push(self.version);
+        ad.extend_from_slice(&self.recipient.0);
+        ad.extend_from_slice(&self.ephemeral.0);
+        ad.extend_from_slice(&self.key_id);
+        ad
+    }
+}
+
+/// Binds the key encryption key to both public keys of the agreement
+fn wrapping_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, shared);
+    let mut ctx = hmac::SigningContext::with_key(&key);
+    ctx.update(b"hermitdb key wrap");
+    ctx.update(&ephemeral.0);
+    ctx.update(&recipient.0);
+    ctx.sign().as_ref().to_vec()
+}
+
+/// Version of the `Keyfile` layout written by this code
+pub const KEYFILE_VERSION: u8 = 1;
+
+const KEYFILE_MAGIC: &[u8] = b"hermitdb keyfile";
+
+/// Everything needed to re-derive a device's `Session` from a password.
+///
+/// On disk the keyfile is `KEYFILE_MAGIC ++ bincode(Keyfile) ++ sha256`, the
+/// digest lets a damaged file be told apart from a wrong password.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyfile {
+    pub version: u8,
+    pub actor: u128,
+    pub kdf: KdfHeader,
+    pub entropy: [u8; 256 / 8],
+    /// `MasterKey::check_value` of the derived key
+    pub check: Vec<u8>
+}
+
+impl Keyfile {
+    pub fn new(actor: u128, kdf: &KDF, pass: &[u8]) -> Result<Self> {
+        let master_key = kdf.master_key(pass)?;
+        Ok(Keyfile {
+            version: KEYFILE_VERSION,
+            actor,
+            kdf: kdf.header(),
+            entropy: kdf.entropy,
+            check: master_key.check_value()
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let body = bincode::serialize(&self)?;
+        let mut bytes = Vec::with_capacity(KEYFILE_MAGIC.len() + body.len() + 256 / 8);
+        bytes.extend_from_slice(KEYFILE_MAGIC);
+        bytes.extend_from_slice(&body);
+        bytes.extend_from_slice(digest::digest(&digest::SHA256, &body).as_ref());
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let digest_len = digest::SHA256.output_len;
+        if bytes.len() < KEYFILE_MAGIC.len() + digest_len
+            || &bytes[..KEYFILE_MAGIC.len()] != KEYFILE_MAGIC {
+            return Err(Error::Corrupt("Not a hermitdb keyfile".into()));
+        }
+
+        let (body, file_digest) = bytes[KEYFILE_MAGIC.len()..]
+            .split_at(bytes.len() - KEYFILE_MAGIC.len() - digest_len);
+        if digest::digest(&digest::SHA256, body).as_ref() != file_digest {
+            return Err(Error::Corrupt("Keyfile checksum mismatch".into()));
+        }
+
+        let keyfile: Keyfile = bincode::deserialize(body)
+            .map_err(|e| Error::Corrupt(format!("Failed to parse keyfile: {}", e)))?;
+        if keyfile.version != KEYFILE_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported keyfile version: {}", keyfile.version)
+            ));
+        }
+        if keyfile.kdf.version != KDF_HEADER_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported kdf header version: {}", keyfile.kdf.version)
+            ));
+        }
+        Ok(keyfile)
+    }
+
+    /// Derives the master key, Err(WrongPassword) if it fails the key check
+    pub fn master_key(&self, pass: &[u8]) -> Result<MasterKey> {
+        let kdf = KDF::from_header(&self.kdf, self.entropy);
+        let master_key = kdf.master_key(pass)?;
+        constant_time::verify_slices_are_equal(&master_key.check_value(), &self.check)
+            .map_err(|_| Error::WrongPassword)?;
+        Ok(master_key)
+    }
+
+    /// A session that can be locked and unlocked again with `pass`
+    pub fn unlock(&self, pass: &[u8]) -> Result<Session> {
+        let master_key = self.master_key(pass)?;
+        Ok(Session::new(self.actor, master_key).with_keyfile(self.clone()))
     }
 }
 
+/// Version of the `KeyRecord` layout written by this code
+pub const KEY_RECORD_VERSION: u8 = 1;
+
+/// A random data key sealed under a key encryption key derived from the
+/// passphrase.
+///
+/// Data is only ever encrypted with the data key, so changing the passphrase
+/// means sealing the data key again and replacing this small record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub version: u8,
+    /// Parameters of the key encryption key, the entropy is kept locally
+    pub kdf: KdfHeader,
+    /// `MasterKey::check_value` of the key encryption key, tells a wrong
+    /// passphrase apart from a damaged record
+    pub check: Vec<u8>,
+    pub sealed: Encrypted
+}
+
+impl KeyRecord {
+    fn context() -> Context {
+        Context::named(KEY_RECORD_VERSION, b"hermitdb key record")
+    }
+
+    pub fn seal(data_key: &MasterKey, kdf: &KDF, pass: &[u8]) -> Result<Self> {
+        let kek = kdf.master_key(pass)?;
+        let check = kek.check_value();
+        let mut kek_sess = Session::new(0, kek);
+        kek_sess.seal_opts = SealOptions { compress: false, pad: false };
+        let mut plain = Plaintext(data_key.0.to_vec());
+        let sealed = plain.encrypt(&kek_sess, &KeyRecord::context());
+        wipe(&mut plain.0);
+        Ok(KeyRecord {
+            version: KEY_RECORD_VERSION,
+            kdf: kdf.header(),
+            check,
+            sealed: sealed?
+        })
+    }
+
+    /// Recover the data key, Err(WrongPassword) if `pass` fails the key
+    /// check and Err(Corrupt) if it passes but the data key doesn't open.
+    pub fn unseal(&self, entropy: [u8; 256 / 8], pass: &[u8]) -> Result<MasterKey> {
+        if self.version != KEY_RECORD_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported key record version: {}", self.version)
+            ));
+        }
+
+        let kek = KDF::from_header(&self.kdf, entropy).master_key(pass)?;
+        constant_time::verify_slices_are_equal(&kek.check_value(), &self.check)
+            .map_err(|_| Error::WrongPassword)?;
+        let mut plain = match self.sealed.decrypt(&Session::new(0, kek), &KeyRecord::context()) {
+            Ok(plain) => plain,
+            Err(Error::Crypto(_)) => return Err(Error::Corrupt("Key record fails to open".into())),
+            Err(e) => return Err(e)
+        };
+        if plain.0.len() != 256 / 8 {
+            wipe(&mut plain.0);
+            return Err(Error::Corrupt("Sealed data key has the wrong length".into()));
+        }
+
+        let mut data_key = MasterKey([0u8; 256 / 8]);
+        data_key.0.copy_from_slice(&plain.0);
+        wipe(&mut plain.0);
+        Ok(data_key)
+    }
+
+    /// Seal the same data key under a new passphrase, with a fresh salt
+    pub fn change_passphrase(&self, entropy: [u8; 256 / 8], old_pass: &[u8], new_pass: &[u8]) -> Result<Self> {
+        let data_key = self.unseal(entropy, old_pass)?;
+        let kdf = KDF {
+            algo: self.kdf.algo.clone(),
+            salt: rand_256()?,
+            entropy
+        };
+        KeyRecord::seal(&data_key, &kdf, new_pass)
+    }
+}
+
+/// Version of the recovery code and key share layouts written by this code
+pub const RECOVERY_VERSION: u8 = 1;
+
+/// Prints a key as a recovery code, `XXXX-XXXX-...` in base32.
+///
+/// Anyone holding the code can read the data, it's meant to be written down
+/// and stored offline.
+pub fn recovery_code(key: &MasterKey) -> String {
+    let mut payload = vec![RECOVERY_VERSION];
+    payload.extend_from_slice(&key.0);
+    let code = encode_code(&payload);
+    wipe(&mut payload);
+    code
+}
+
+/// Rebuild a session from a code printed by `recovery_code`. The session
+/// has nothing to unlock with once locked, `DB::recover_with_code` also
+/// seals the key under a new passphrase.
+pub fn recover_from_code(actor: u128, code: &str) -> Result<Session> {
+    let mut payload = decode_code(code)?;
+    if payload.first() != Some(&RECOVERY_VERSION) {
+        wipe(&mut payload);
+        return Err(Error::Version("Unsupported recovery code version".into()));
+    }
+    if payload.len() != 1 + 256 / 8 {
+        wipe(&mut payload);
+        return Err(Error::Corrupt("Recovery code has the wrong length".into()));
+    }
+
+    let mut key = MasterKey([0u8; 256 / 8]);
+    key.0.copy_from_slice(&payload[1..]);
+    wipe(&mut payload);
+    Ok(Session::new(actor, key))
+}
+
+/// One of the shares of a key split with `split_key`
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyShare {
+    pub version: u8,
+    /// Number of shares needed to rebuild the key
+    pub threshold: u8,
+    pub index: u8,
+    /// Id of the key this is a share of, see `MasterKey::id`
+    pub key_id: KeyId,
+    pub share: Vec<u8>
+}
+
+impl Drop for KeyShare {
+    fn drop(&mut self) {
+        wipe(&mut self.share);
+    }
+}
+
+impl std::fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "KeyShare({} of {}, key id: {:?})", self.index, self.threshold, self.key_id)
+    }
+}
+
+impl KeyShare {
+    /// Printable form of the share, see `recovery_code`
+    pub fn to_code(&self) -> Result<String> {
+        let mut payload = bincode::serialize(&self)?;
+        let code = encode_code(&payload);
+        wipe(&mut payload);
+        Ok(code)
+    }
+
+    pub fn from_code(code: &str) -> Result<Self> {
+        let mut payload = decode_code(code)?;
+        let share = bincode::deserialize(&payload);
+        wipe(&mut payload);
+        let share: KeyShare = share
+            .map_err(|e| Error::Corrupt(format!("Failed to parse key share: {}", e)))?;
+        if share.version != RECOVERY_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported key share version: {}", share.version)
+            ));
+        }
+        Ok(share)
+    }
+}
+
+/// Split `key` into `num_shares` shares so that any `threshold` of them
+/// rebuild it, while fewer reveal nothing about the key.
+pub fn split_key(key: &MasterKey, threshold: u8, num_shares: u8) -> Result<Vec<KeyShare>> {
+    let rng = SystemRandom::new();
+    let mut shares = shamir::split(&key.0, threshold, num_shares, |coefficients| {
+        rng.fill(coefficients)
+            .map_err(|_| Error::Crypto("Failed to generate share coefficients".into()))
+    })?;
+
+    let key_shares = shares.iter()
+        .map(|(index, share)| KeyShare {
+            version: RECOVERY_VERSION,
+            threshold,
+            index: *index,
+            key_id: key.id(),
+            share: share.clone()
+        })
+        .collect();
+    for (_, share) in shares.iter_mut() {
+        wipe(share);
+    }
+    Ok(key_shares)
+}
+
+/// Rebuild a key from shares made by `split_key`
+pub fn combine_shares(shares: &[KeyShare]) -> Result<MasterKey> {
+    let (threshold, key_id) = match shares.first() {
+        Some(share) => (share.threshold, share.key_id),
+        None => return Err(Error::State("No key shares to combine".into()))
+    };
+    if shares.iter().any(|share| share.key_id != key_id || share.threshold != threshold) {
+        return Err(Error::State("Key shares belong to different keys".into()));
+    }
+    if shares.len() < threshold as usize {
+        return Err(Error::State(
+            format!("Need {} key shares, only have {}", threshold, shares.len())));
+    }
+
+    let points: Vec<(u8, Vec<u8>)> = shares.iter()
+        .map(|share| (share.index, share.share.clone()))
+        .collect();
+    let mut secret = shamir::combine(&points)?;
+    let mut key = MasterKey([0u8; 256 / 8]);
+    let valid = secret.len() == key.0.len();
+    if valid {
+        key.0.copy_from_slice(&secret);
+    }
+    wipe(&mut secret);
+    for (_, mut share) in points.into_iter() {
+        wipe(&mut share);
+    }
+
+    if !valid || key.id() != key_id {
+        return Err(Error::Corrupt("Key shares don't rebuild the key they were split from".into()));
+    }
+    Ok(key)
+}
+
+/// Rebuild a session from key shares, see `split_key`. Like
+/// `recover_from_code` the session can't be unlocked again, see
+/// `DB::recover_with_shares`.
+pub fn recover_from_shares(actor: u128, shares: &[KeyShare]) -> Result<Session> {
+    Ok(Session::new(actor, combine_shares(shares)?))
+}
+
+/// base32 of the payload and a 4 byte checksum, in dash separated groups
+fn encode_code(payload: &[u8]) -> String {
+    let mut bytes = payload.to_vec();
+    bytes.extend_from_slice(&digest::digest(&digest::SHA256, payload).as_ref()[..4]);
+    let encoded = data_encoding::BASE32_NOPAD.encode(&bytes);
+    wipe(&mut bytes);
+
+    let groups: Vec<String> = encoded.as_bytes()
+        .chunks(4)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect();
+    groups.join("-")
+}
+
+/// Inverse of `encode_code`, ignores case, dashes and whitespace
+fn decode_code(code: &str) -> Result<Vec<u8>> {
+    let normalized: String = code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let mut bytes = data_encoding::BASE32_NOPAD.decode(normalized.as_bytes())
+        .map_err(|_| Error::Corrupt("Code is not valid base32".into()))?;
+    if bytes.len() < 4 {
+        return Err(Error::Corrupt("Code is too short".into()));
+    }
+
+    let payload_len = bytes.len() - 4;
+    let checksum_ok = digest::digest(&digest::SHA256, &bytes[..payload_len]).as_ref()[..4]
+        == bytes[payload_len..];
+    if !checksum_ok {
+        wipe(&mut bytes);
+        return Err(Error::Corrupt("Code checksum mismatch, check for typos".into()));
+    }
+    bytes.truncate(payload_len);
+    Ok(bytes)
+}
+
+/// Will return Err if a file exists at `path`
+pub fn create_keyfile(path: &std::path::Path, actor: u128, algo: KdfAlgo, pass: &[u8]) -> Result<Session> {
+    if path.exists() {
+        return Err(Error::State("Attempting to create a keyfile when one exists".into()));
+    }
+
+    let kdf = KDF {
+        algo,
+        salt: rand_256()?,
+        entropy: rand_256()?
+    };
+    let keyfile = Keyfile::new(actor, &kdf, pass)?;
+
+    let mut f = std::fs::File::create(path)?;
+    f.write_all(&keyfile.to_bytes()?)?;
+    f.sync_all()?;
+    keyfile.unlock(pass)
+}
+
+/// Err(WrongPassword) if `pass` is wrong, Err(Corrupt(_)) if the file is damaged
+pub fn open_keyfile(path: &std::path::Path, pass: &[u8]) -> Result<Session> {
+    let mut bytes = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+    Keyfile::from_bytes(&bytes)?.unlock(pass)
+}
+
 /// Will return Err if entropy_file does not exist
 pub fn read_entropy_file(root: &std::path::Path) -> Result<[u8; 256/8]> {
     let entropy_filepath = root.join("entropy_file");
@@ -121,13 +1215,14 @@ pub fn create_entropy_file(root: &std::path::Path) -> Result<[u8; 256/8]> {
     let mut f = std::fs::File::create(entropy_filepath)?;
     let entropy = rand_256()?;
     f.write_all(&entropy)?;
+    f.sync_all()?;
     Ok(entropy)
 }
 
 pub fn u32_to_bytes(i: u32) -> [u8; 4] {
     // output is in little endian
     // u32_to_bytes(0x12345678) -> [0x12, 0x34, 0x56, 0x78]
-    return [
+    [
         ((i >> 24) & 0xff) as u8,
         ((i >> 16) & 0xff) as u8,
         ((i >> 8) & 0xff) as u8,
@@ -143,6 +1238,14 @@ pub fn bytes_to_u32(xs: &[u8; 4]) -> u32 {
         | (xs[3] as u32)
 }
 
+/// Zero key material in a way the optimizer won't elide
+fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
 pub fn rand_96() -> Result<[u8; 96/8]> {
     let mut buf = [0u8; 96/8];
     // TAI: Should this rng live in a session so we don't have to recreate it each time?
@@ -189,53 +1292,479 @@ mod test {
         assert_eq!(entropy1, entropy2);
     }
 
+    #[test]
+    fn compress_and_pad() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let mut sess = Session::new(0, kdf.master_key("pass".as_bytes()).unwrap());
+
+        let small = Plaintext(b"a".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+        let medium = Plaintext(b"some op of a few bytes".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(small.flags & FLAG_PADDED, FLAG_PADDED);
+        assert_eq!(small.ciphertext.len(), medium.ciphertext.len());
+        assert_eq!(small.decrypt(&sess, &Context::default()).unwrap().0, b"a".to_vec());
+        assert_eq!(medium.decrypt(&sess, &Context::default()).unwrap().0, b"some op of a few bytes".to_vec());
+
+        // compression is opt in
+        let repetitive = vec![7u8; 10_000];
+        let uncompressed = Plaintext(repetitive.clone()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(uncompressed.flags, FLAG_PADDED);
+
+        sess.seal_opts.compress = true;
+        let compressed = Plaintext(repetitive.clone()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(compressed.flags, FLAG_COMPRESSED | FLAG_PADDED);
+        assert!(compressed.ciphertext.len() < repetitive.len());
+        assert_eq!(compressed.decrypt(&sess, &Context::default()).unwrap().0, repetitive);
+
+        // trailing zeros in the plaintext survive padding
+        let zeros = vec![1u8, 0, 0, 0];
+        sess.seal_opts.compress = false;
+        let padded = Plaintext(zeros.clone()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(padded.flags, FLAG_PADDED);
+        assert_eq!(padded.decrypt(&sess, &Context::default()).unwrap().0, zeros);
+
+        sess.seal_opts.pad = false;
+        let exact = Plaintext(zeros.clone()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(exact.flags, 0);
+        assert_eq!(exact.ciphertext.len(), zeros.len() + aead::CHACHA20_POLY1305.tag_len());
+        assert_eq!(exact.decrypt(&sess, &Context::default()).unwrap().0, zeros);
+
+        let mut flipped_flags = exact;
+        flipped_flags.flags = FLAG_PADDED;
+        assert_matches!(flipped_flags.decrypt(&sess, &Context::default()), Err(Error::Crypto(_)));
+
+        flipped_flags.flags = 1 << 7;
+        assert_matches!(flipped_flags.decrypt(&sess, &Context::default()), Err(Error::Version(_)));
+    }
+
+    #[test]
+    fn inflating_stops_at_the_declared_length() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&vec![0u8; 1_000_000]).unwrap();
+        let mut bomb = u32_to_bytes(16).to_vec();
+        bomb.extend(encoder.finish().unwrap());
+        assert_matches!(unprepare(FLAG_COMPRESSED, &bomb), Err(Error::Crypto(_)));
+        assert_matches!(unprepare(FLAG_COMPRESSED, &bomb[..2]), Err(Error::Crypto(_)));
+
+        let (flags, prepared) = prepare(b"hello hello hello hello", &SealOptions {
+            compress: true,
+            pad: false
+        }).unwrap();
+        assert_eq!(unprepare(flags, &prepared).unwrap(), b"hello hello hello hello".to_vec());
+    }
+
+    #[test]
+    fn wrap_key_for_recipient() {
+        let alice = SecretKey::generate().unwrap();
+        let bob = SecretKey::generate().unwrap();
+        let key = MasterKey(rand_256().unwrap());
+
+        let wrapped = WrappedKey::wrap(&key, &alice.public_key()).unwrap();
+        assert_eq!(wrapped.recipient, alice.public_key());
+        assert_eq!(wrapped.key_id, key.id());
+        assert_eq!(alice.unwrap_key(&wrapped).unwrap(), key);
+
+        // each wrap uses a fresh ephemeral key
+        let wrapped2 = WrappedKey::wrap(&key, &alice.public_key()).unwrap();
+        assert_ne!(wrapped.ephemeral, wrapped2.ephemeral);
+
+        assert_matches!(bob.unwrap_key(&wrapped), Err(Error::Crypto(_)));
+
+        let mut redirected = wrapped.clone();
+        redirected.recipient = bob.public_key();
+        assert_matches!(bob.unwrap_key(&redirected), Err(Error::Crypto(_)));
+
+        let mut tampered = wrapped.clone();
+        tampered.key_id[0] ^= 1;
+        assert_matches!(alice.unwrap_key(&tampered), Err(Error::Crypto(_)));
+
+        let restored = SecretKey::from_bytes(alice.to_bytes());
+        assert_eq!(restored.unwrap_key(&wrapped).unwrap(), key);
+    }
+
+    #[test]
+    fn session_lock_unlock() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let keyfile = Keyfile::new(3, &kdf, b"pass").unwrap();
+        let mut sess = keyfile.unlock(b"pass").unwrap();
+        let key = sess.master_key().unwrap();
+        let encrypted = Plaintext(b"locked away".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+
+        // key bytes never show up in debug output
+        let debug = format!("{:?} {:?}", sess, key);
+        assert!(!debug.contains(&format!("{:?}", key.0)));
+
+        sess.lock();
+        assert!(sess.is_locked());
+        assert_matches!(sess.master_key(), Err(Error::Locked));
+        assert_matches!(encrypted.decrypt(&sess, &Context::default()), Err(Error::Locked));
+        assert_matches!(Plaintext(b"x".to_vec()).encrypt(&sess, &Context::default()), Err(Error::Locked));
+
+        assert_matches!(sess.unlock(b"wrong"), Err(Error::WrongPassword));
+        assert!(sess.is_locked());
+        sess.unlock(b"pass").unwrap();
+        assert_eq!(sess.master_key().unwrap(), key);
+        assert_eq!(encrypted.decrypt(&sess, &Context::default()).unwrap().0, b"locked away".to_vec());
+
+        // a session without a keyfile can't be unlocked again
+        let mut bare = Session::new(3, key);
+        bare.lock();
+        assert_matches!(bare.unlock(b"pass"), Err(Error::State(_)));
+
+        sess.set_idle_timeout(Some(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(sess.is_locked());
+        assert_matches!(sess.master_key(), Err(Error::Locked));
+        assert!(sess.lock_if_idle());
+        assert!(sess.master_key.borrow().is_none());
+    }
+
+    #[test]
+    fn idle_sessions_wipe_their_keys_on_read() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let mut sess = Session::new(0, kdf.master_key(b"old").unwrap());
+        sess.rotate_to(kdf.master_key(b"new").unwrap()).unwrap();
+        let encrypted = Plaintext(b"idle".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+
+        sess.set_idle_timeout(Some(Duration::from_millis(10)));
+        std::thread::sleep(Duration::from_millis(20));
+
+        // a read through a shared reference is enough to wipe the keys
+        let shared = &sess;
+        assert_matches!(encrypted.decrypt(shared, &Context::default()), Err(Error::Locked));
+        assert!(sess.master_key.borrow().is_none());
+        assert!(sess.old_keys.borrow().is_empty());
+    }
+
+    #[test]
+    fn key_record() {
+        let entropy = rand_256().unwrap();
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy
+        };
+        let data_key = MasterKey::generate().unwrap();
+        let record = KeyRecord::seal(&data_key, &kdf, b"old").unwrap();
+        assert_eq!(record.unseal(entropy, b"old").unwrap(), data_key);
+        assert_matches!(record.unseal(entropy, b"new"), Err(Error::WrongPassword));
+        assert_matches!(record.unseal(rand_256().unwrap(), b"old"), Err(Error::WrongPassword));
+
+        let changed = record.change_passphrase(entropy, b"old", b"new").unwrap();
+        assert_ne!(changed.kdf.salt, record.kdf.salt);
+        assert_eq!(changed.unseal(entropy, b"new").unwrap(), data_key);
+        assert_matches!(changed.unseal(entropy, b"old"), Err(Error::WrongPassword));
+        assert_matches!(
+            record.change_passphrase(entropy, b"wrong", b"new"),
+            Err(Error::WrongPassword)
+        );
+
+        // a damaged record is not mistaken for a wrong passphrase
+        let mut damaged = record.clone();
+        damaged.sealed.ciphertext[0] ^= 1;
+        assert_matches!(damaged.unseal(entropy, b"old"), Err(Error::Corrupt(_)));
+        assert_matches!(damaged.unseal(entropy, b"new"), Err(Error::WrongPassword));
+
+        // a session built on the record unlocks with the passphrase
+        let mut sess = Session::new(0, data_key.clone()).with_key_record(changed, entropy);
+        sess.lock();
+        assert_matches!(sess.unlock(b"old"), Err(Error::WrongPassword));
+        sess.unlock(b"new").unwrap();
+        assert_eq!(sess.master_key().unwrap(), data_key);
+    }
+
+    #[test]
+    fn recovery_code_round_trip() {
+        let key = MasterKey::generate().unwrap();
+        let code = recovery_code(&key);
+        assert!(code.split('-').all(|group| group.len() <= 4));
+
+        let sess = recover_from_code(5, &code).unwrap();
+        assert_eq!(sess.actor, 5);
+        assert_eq!(sess.master_key().unwrap(), key);
+
+        // forgiving about how it was typed back in
+        let retyped = code.to_lowercase().replace("-", " ");
+        assert_eq!(recover_from_code(5, &retyped).unwrap().master_key().unwrap(), key);
+
+        let typo = code.replacen(&code[..1], if &code[..1] == "A" { "B" } else { "A" }, 1);
+        assert_matches!(recover_from_code(5, &typo), Err(Error::Corrupt(_)));
+    }
+
+    #[test]
+    fn key_shares() {
+        let key = MasterKey::generate().unwrap();
+        let shares = split_key(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        let subset = vec![shares[4].clone(), shares[0].clone(), shares[2].clone()];
+        assert_eq!(combine_shares(&subset).unwrap(), key);
+        assert_eq!(recover_from_shares(1, &shares).unwrap().master_key().unwrap(), key);
+
+        assert_matches!(combine_shares(&shares[..2]), Err(Error::State(_)));
+
+        // shares travel as printable codes
+        let codes: Vec<String> = shares.iter().map(|share| share.to_code().unwrap()).collect();
+        let decoded: Vec<KeyShare> = codes[1..4].iter()
+            .map(|code| KeyShare::from_code(code).unwrap())
+            .collect();
+        assert_eq!(combine_shares(&decoded).unwrap(), key);
+
+        let mut tampered = decoded.clone();
+        tampered[0].share[0] ^= 1;
+        assert_matches!(combine_shares(&tampered), Err(Error::Corrupt(_)));
+
+        let other_shares = split_key(&MasterKey::generate().unwrap(), 3, 5).unwrap();
+        let mixed = vec![shares[0].clone(), shares[1].clone(), other_shares[2].clone()];
+        assert_matches!(combine_shares(&mixed), Err(Error::State(_)));
+
+        assert!(!format!("{:?}", shares[0]).contains(&format!("{:?}", shares[0].share)));
+    }
+
+    #[test]
+    fn keyfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keyfile");
+        let algo = KdfAlgo::Argon2id { mem_cost: 64, time_cost: 1, lanes: 1 };
+
+        assert_matches!(open_keyfile(&path, b"pass"), Err(Error::IO(_)));
+
+        let sess = create_keyfile(&path, 7, algo.clone(), b"pass").unwrap();
+        assert_matches!(create_keyfile(&path, 7, algo, b"pass"), Err(Error::State(_)));
+
+        let reopened = open_keyfile(&path, b"pass").unwrap();
+        assert_eq!(reopened.actor, 7);
+        assert_eq!(reopened.master_key().unwrap(), sess.master_key().unwrap());
+
+        assert_matches!(open_keyfile(&path, b"wrong pass"), Err(Error::WrongPassword));
+
+        let mut bytes = Vec::new();
+        std::fs::File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        let mid = bytes.len() / 2;
+        bytes[mid] ^= 1;
+        std::fs::File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert_matches!(open_keyfile(&path, b"pass"), Err(Error::Corrupt(_)));
+
+        std::fs::File::create(&path).unwrap().write_all(b"garbage").unwrap();
+        assert_matches!(open_keyfile(&path, b"pass"), Err(Error::Corrupt(_)));
+    }
+
     #[test]
     fn kdf() {
         let dir = tempfile::tempdir().unwrap();
         let dir_path = dir.path().to_owned();
         let kdf = KDF {
-            pbkdf2_iters: 1000,
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
             salt: rand_256().unwrap(),
             entropy: create_entropy_file(&dir_path).unwrap()
         };
 
-        let master_key1 = kdf.master_key("sssshh.. it's a secret".as_bytes());
-        let master_key2 = kdf.master_key("sssshh.. it's a secret".as_bytes());
-        let master_key3 = kdf.master_key("imposter!!".as_bytes());
+        let master_key1 = kdf.master_key("sssshh.. it's a secret".as_bytes()).unwrap();
+        let master_key2 = kdf.master_key("sssshh.. it's a secret".as_bytes()).unwrap();
+        let master_key3 = kdf.master_key("imposter!!".as_bytes()).unwrap();
         
         assert_eq!(master_key1, master_key2);
         assert_ne!(master_key1, master_key3);
     }
 
+    #[test]
+    fn argon2id_kdf() {
+        let kdf = KDF {
+            algo: KdfAlgo::Argon2id { mem_cost: 64, time_cost: 1, lanes: 1 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+
+        let master_key1 = kdf.master_key("hunter2".as_bytes()).unwrap();
+        let master_key2 = kdf.master_key("hunter2".as_bytes()).unwrap();
+        let master_key3 = kdf.master_key("hunter3".as_bytes()).unwrap();
+        assert_eq!(master_key1, master_key2);
+        assert_ne!(master_key1, master_key3);
+
+        let pbkdf2 = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: kdf.salt,
+            entropy: kdf.entropy
+        };
+        assert_ne!(pbkdf2.master_key("hunter2".as_bytes()).unwrap(), master_key1);
+    }
+
+    #[test]
+    fn kdf_header() {
+        let entropy = rand_256().unwrap();
+        let kdf = KDF {
+            algo: KdfAlgo::Argon2id { mem_cost: 64, time_cost: 1, lanes: 1 },
+            salt: rand_256().unwrap(),
+            entropy
+        };
+
+        let header_bytes = kdf.header().to_bytes().unwrap();
+        let header = KdfHeader::from_bytes(&header_bytes).unwrap();
+        assert_eq!(header, kdf.header());
+
+        let rebuilt = KDF::from_header(&header, entropy);
+        assert_eq!(
+            rebuilt.master_key("pass".as_bytes()).unwrap(),
+            kdf.master_key("pass".as_bytes()).unwrap()
+        );
+
+        let mut future_header = header.clone();
+        future_header.version += 1;
+        assert_matches!(
+            KdfHeader::from_bytes(&future_header.to_bytes().unwrap()),
+            Err(Error::Version(_))
+        );
+    }
+
+    #[test]
+    fn kdf_upgrade() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let upgraded = kdf.upgrade(KdfAlgo::Argon2id { mem_cost: 64, time_cost: 1, lanes: 1 }).unwrap();
+
+        assert_eq!(upgraded.entropy, kdf.entropy);
+        assert_ne!(upgraded.salt, kdf.salt);
+        assert_ne!(upgraded.header(), kdf.header());
+
+        let old_key = kdf.master_key("pass".as_bytes()).unwrap();
+        let new_key = upgraded.master_key("pass".as_bytes()).unwrap();
+        assert_ne!(old_key, new_key);
+
+        // data sealed under the old key is carried over by rotating
+        let mut sess = Session::new(0, old_key);
+        let encrypted = Plaintext(b"upgrade".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+        sess.rotate_to(new_key).unwrap();
+        let rotated = encrypted.reencrypt(&sess, &Context::default()).unwrap();
+        sess.retire_old_keys();
+        assert_eq!(rotated.decrypt(&sess, &Context::default()).unwrap().0, b"upgrade".to_vec());
+    }
+
     #[test]
     fn plaintext_encrypt_decrypt() {
         let dir = tempfile::tempdir().unwrap();
         let dir_path = dir.path().to_owned();
         
         let kdf = KDF {
-            pbkdf2_iters: 1000,
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
             salt: rand_256().unwrap(),
             entropy: create_entropy_file(&dir_path).unwrap()
         };
 
-        let sess = Session {
-            actor: 0,
-            master_key: kdf.master_key("do you KNOW who I am??".as_bytes())
-        };
+        let sess = Session::new(0, kdf.master_key("do you KNOW who I am??".as_bytes()).unwrap());
 
         let mut plain = Plaintext("I kinda like you".as_bytes().to_vec());
-        let encrypted = plain.encrypt(&sess).unwrap();
+        let encrypted = plain.encrypt(&sess, &Context::default()).unwrap();
         assert_ne!(encrypted.ciphertext, plain.0);
         
-        let encrypted2 = plain.encrypt(&sess).unwrap();
+        let encrypted2 = plain.encrypt(&sess, &Context::default()).unwrap();
         assert_ne!(encrypted.nonce, encrypted2.nonce);
         assert_ne!(encrypted.ciphertext, encrypted2.ciphertext);
 
-        let plain2 = encrypted.decrypt(&sess).unwrap();
+        let plain2 = encrypted.decrypt(&sess, &Context::default()).unwrap();
         let decrypted_string = String::from_utf8(plain2.0).unwrap();
         assert_eq!(decrypted_string, "I kinda like you");
     }
 
+    #[test]
+    fn keyed_hash() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+
+        let sess = Session::new(0, kdf.master_key("pass".as_bytes()).unwrap());
+        let other_sess = Session::new(0, kdf.master_key("other".as_bytes()).unwrap());
+
+        assert_eq!(sess.keyed_hash(b"key").unwrap(), sess.keyed_hash(b"key").unwrap());
+        assert_eq!(sess.keyed_hash(b"key").unwrap().len(), 256 / 8);
+        assert_ne!(sess.keyed_hash(b"key").unwrap(), sess.keyed_hash(b"kex").unwrap());
+        assert_ne!(sess.keyed_hash(b"key").unwrap(), other_sess.keyed_hash(b"key").unwrap());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let old_key = kdf.master_key("old pass".as_bytes()).unwrap();
+        let new_key = kdf.master_key("new pass".as_bytes()).unwrap();
+        assert_ne!(old_key.id(), new_key.id());
+
+        let mut sess = Session::new(0, old_key.clone());
+        let encrypted = Plaintext(b"rotate me".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+        assert_eq!(encrypted.version, ENVELOPE_VERSION);
+        assert_eq!(encrypted.key_id, old_key.id());
+
+        sess.rotate_to(new_key.clone()).unwrap();
+        assert!(!encrypted.is_current(&sess));
+
+        // old ciphertexts remain readable mid rotation
+        assert_eq!(encrypted.decrypt(&sess, &Context::default()).unwrap().0, b"rotate me".to_vec());
+
+        let rotated = encrypted.reencrypt(&sess, &Context::default()).unwrap();
+        assert!(rotated.is_current(&sess));
+
+        sess.retire_old_keys();
+        assert_matches!(encrypted.decrypt(&sess, &Context::default()), Err(Error::Crypto(_)));
+        assert_eq!(rotated.decrypt(&sess, &Context::default()).unwrap().0, b"rotate me".to_vec());
+    }
+
+    #[test]
+    fn tampered_envelope_header() {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+            salt: rand_256().unwrap(),
+            entropy: rand_256().unwrap()
+        };
+        let sess = Session::new(0, kdf.master_key("pass".as_bytes()).unwrap());
+
+        let mut encrypted = Plaintext(b"header".to_vec()).encrypt(&sess, &Context::default()).unwrap();
+        encrypted.version += 1;
+        assert_matches!(encrypted.decrypt(&sess, &Context::default()), Err(Error::Version(_)));
+        encrypted.version -= 1;
+
+        encrypted.nonce[0] ^= 1;
+        assert_matches!(encrypted.decrypt(&sess, &Context::default()), Err(Error::Crypto(_)));
+    }
+
+    #[test]
+    fn context_is_authenticated() {
+        let sess = Session::new(0, MasterKey::generate().unwrap());
+        let ctx = Context::named(1, b"op").with_actor(b"7").at(b"parent");
+        let encrypted = Plaintext(b"bound".to_vec()).encrypt(&sess, &ctx).unwrap();
+        assert_eq!(encrypted.decrypt(&sess, &ctx).unwrap().0, b"bound".to_vec());
+
+        let others = [
+            Context::named(2, b"op").with_actor(b"7").at(b"parent"),
+            Context::named(1, b"op").with_actor(b"8").at(b"parent"),
+            Context::named(1, b"op").with_actor(b"7").at(b"other parent"),
+            Context::named(1, b"other").with_actor(b"7").at(b"parent"),
+            // field boundaries are part of the encoding
+            Context::named(1, b"op").with_actor(b"7p").at(b"arent"),
+            Context::default()
+        ];
+        for other in others.iter() {
+            assert_matches!(encrypted.decrypt(&sess, other), Err(Error::Crypto(_)));
+        }
+    }
+
     #[test]
     fn u32_bytes_conversions() {
         assert_eq!(u32_to_bytes(65), [0, 0, 0, 0x41]);
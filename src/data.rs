@@ -1,11 +1,15 @@
 use std::cmp;
-use crdts::{self, CvRDT, CmRDT, Causal};
+use std::hash::{Hash, Hasher};
+use crdts::{self, CvRDT, Causal, FunkyCvRDT, FunkyCmRDT};
 use error::{Error, Result};
+use log::CmRDT;
 
 pub type Actor = u128;
+pub type Map = crdts::Map<(Vec<u8>, Kind), Box<Data>, Actor>;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum Prim {
+    #[default]
     Nil,
     Float(f64),
     Int(i64),
@@ -15,21 +19,34 @@ pub enum Prim {
 
 impl Eq for Prim {}
 
+// Ord falls back to the derived partial order across variants
+#[allow(clippy::derive_ord_xor_partial_ord)]
 impl Ord for Prim {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         match (self, other) {
-            (Prim::Int(a), Prim::Int(b)) => a.cmp(&b),
-            (Prim::Str(a), Prim::Str(b)) => a.cmp(&b),
-            (Prim::Blob(a), Prim::Blob(b)) => a.cmp(&b),
+            (Prim::Int(a), Prim::Int(b)) => a.cmp(b),
+            (Prim::Str(a), Prim::Str(b)) => a.cmp(b),
+            (Prim::Blob(a), Prim::Blob(b)) => a.cmp(b),
             // TAI: we panic when we compare with NaN. expected behaviour?
-            (a, b) => a.partial_cmp(&b).unwrap()
+            (a, b) => a.partial_cmp(b).unwrap()
         }
     }
 }
 
-impl Default for Prim {
-    fn default() -> Self {
-        Prim::Nil
+impl Hash for Prim {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Prim::Nil => 0u8.hash(state),
+            Prim::Float(p) => {
+                1u8.hash(state);
+                // 0.0 == -0.0, they have to hash the same
+                let p = if *p == 0.0 { 0.0f64 } else { *p };
+                p.to_bits().hash(state)
+            },
+            Prim::Int(p) => { 2u8.hash(state); p.hash(state) },
+            Prim::Str(p) => { 3u8.hash(state); p.hash(state) },
+            Prim::Blob(p) => { 4u8.hash(state); p.hash(state) }
+        }
     }
 }
 
@@ -45,12 +62,13 @@ pub enum Kind {
     Blob
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Data {
+    #[default]
     Nil,
     Reg(crdts::LWWReg<Prim, (u64, Actor)>),
     Set(crdts::Orswot<Prim, Actor>),
-    Map(crdts::Map<(Vec<u8>, Kind), Box<Data>, Actor>)
+    Map(Map)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -60,56 +78,33 @@ pub enum Op {
     Map(crdts::map::Op<(Vec<u8>, Kind), Box<Data>, Actor>)
 }
 
-impl Default for Data {
-    fn default() -> Self {
-        Data::Nil
-    }
-}
-
 impl CvRDT for Data {
-    type Error = Error;
-
-    fn merge(&mut self, other: &Self) -> Result<()> {
+    fn merge(&mut self, other: Self) {
         if &mut Data::Nil == self {
-            *self = other.clone();
-            return Ok(());
+            *self = other;
+            return;
         }
 
-        // compute kinds here in case the match falls to error case.
-        // (the match will consume self and other)
-        let kind = self.kind();
-        let other_kind = other.kind();
         match (self, other) {
-            (_, Data::Nil) => Ok(()),
-            (Data::Reg(a), Data::Reg(b)) => {
-                a.merge(b)?;
-                Ok(())
-            },
-            (Data::Set(a), Data::Set(b)) => {
-                a.merge(&b)?;
-                Ok(())
-            },
-            (Data::Map(a), Data::Map(b)) => {
-                a.merge(&b)?;
-                Ok(())
-            },
-            _ => {
-                return Err(Error::UnexpectedKind(kind, other_kind));
-            }
+            // A marker can't be reused by its actor, so a conflicting
+            // marker means the other replica is broken; we keep our value.
+            (Data::Reg(a), Data::Reg(b)) => { let _ = a.merge(b); },
+            (Data::Set(a), Data::Set(b)) => a.merge(b),
+            (Data::Map(a), Data::Map(b)) => a.merge(b),
+            // The kind is part of every key, data of different
+            // kinds never meets under the same key.
+            _ => ()
         }
     }
 }
 
 impl CvRDT for Box<Data> {
-    type Error = Error;
-        
-    fn merge(&mut self, other: &Self) -> Result<()> {
-        Data::merge(self, other)
+    fn merge(&mut self, other: Self) {
+        Data::merge(self, *other)
     }
 }
 
 impl CmRDT for Data {
-    type Error = Error;
     type Op = Op;
 
     fn apply(&mut self, op: &Self::Op) -> Result<()> {
@@ -119,37 +114,38 @@ impl CmRDT for Data {
         let kind = self.kind();
         let op_kind = op.kind();
         match (self, op) {
-            (Data::Reg(cmrdt), Op::Reg(op)) => cmrdt.apply(&op).map_err(|e| e.into()),
-            (Data::Set(cmrdt), Op::Set(op)) => cmrdt.apply(&op).map_err(|e| e.into()),
-            (Data::Map(cmrdt), Op::Map(op)) => cmrdt.apply(&op).map_err(|e| e.into()),
+            (Data::Reg(cmrdt), Op::Reg(op)) => cmrdt.apply(op.clone()).map_err(|e| e.into()),
+            (Data::Set(cmrdt), Op::Set(op)) => cmrdt.apply(op),
+            (Data::Map(cmrdt), Op::Map(op)) => cmrdt.apply(op),
             _ => Err(Error::UnexpectedKind(kind, op_kind))
-        }   
+        }
     }
 }
 
-impl CmRDT for Box<Data> {
-    type Error = Error;
+impl crdts::CmRDT for Box<Data> {
     type Op = Box<Op>;
 
-    fn apply(&mut self, op: &Self::Op) -> Result<()> {
-        Data::apply(self, op)
+    fn apply(&mut self, op: Self::Op) {
+        // A nested map has no way to report a failed op, ops that don't
+        // apply to the data under their key are dropped.
+        let _ = CmRDT::apply(&mut **self, &op);
     }
 }
 
 impl Causal<Actor> for Data {
-    fn truncate(&mut self, clock: &crdts::VClock<Actor>) {
+    fn forget(&mut self, clock: &crdts::VClock<Actor>) {
         match self {
-            Data::Nil => (),
-            Data::Reg(causal) => causal.truncate(&clock),
-            Data::Set(causal) => causal.truncate(&clock),
-            Data::Map(causal) => causal.truncate(&clock)
+            // registers don't track their causal history, nothing to forget
+            Data::Nil | Data::Reg(_) => (),
+            Data::Set(causal) => causal.forget(clock),
+            Data::Map(causal) => causal.forget(clock)
         }
     }
 }
 
 impl Causal<Actor> for Box<Data> {
-    fn truncate(&mut self, clock: &crdts::VClock<Actor>) {
-        Data::truncate(self, clock)
+    fn forget(&mut self, clock: &crdts::VClock<Actor>) {
+        Data::forget(self, clock)
     }
 }
 
@@ -185,9 +181,9 @@ impl Data {
         }
     }
 
-    pub fn map(self) -> Result<crdts::Map<(Vec<u8>, Kind), Box<Data>, Actor>> {
+    pub fn map(self) -> Result<Map> {
         match self {
-            Data::Nil => Ok(crdts::Map::default()),
+            Data::Nil => Ok(Map::default()),
             Data::Map(m) => Ok(m),
             other => Err(Error::UnexpectedKind(Kind::Map, other.kind()))
         }
@@ -257,7 +253,7 @@ impl Kind {
             Kind::Nil => Data::Nil,
             Kind::Reg => Data::Reg(crdts::LWWReg::default()),
             Kind::Set => Data::Set(crdts::Orswot::default()),
-            Kind::Map => Data::Map(crdts::Map::default()),
+            Kind::Map => Data::Map(Map::default()),
 
             // TAI: does it make sense to implement these prim kinds as Reg(<prim>::default())?
             Kind::Float => panic!("attempted to call default_data on Kind::Float"),
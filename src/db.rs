@@ -1,53 +1,683 @@
-use crdts::CmRDT;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::fs;
 
-use error::Result;
-use map;
-use data::{Data, Op, Actor, Kind};
-use log::{TaggedOp, LogReplicable};
+use bincode;
+use sled;
+use git2;
 
-pub type Map = map::Map<(Vec<u8>, Kind), Data, Actor>;
+use error::{Error, Result};
+use map::{self, Meta};
+use data::{Data, Op, Prim, Actor, Kind};
+use log::{TaggedOp, LogReplicable, Cursor, Progress};
+use store::{KvStore, MemoryStore, EncryptedStore, CLEAR_PREFIX};
+use git_log::{self, OpSummary};
+use crypto::{self, Session, MasterKey, PublicKey, SecretKey, WrappedKey, KeyRecord, KeyShare, KDF, KdfAlgo};
+use encoding;
 
-pub struct DB<L: LogReplicable<Actor, Map>> {
+pub type Map<S = sled::Tree> = map::Map<(Vec<u8>, Kind), Data, Actor, S>;
+
+/// Meta key prefix of the per actor cursors of the ops applied to the map
+const CURSOR_PREFIX: &[u8] = b"cursor/";
+
+/// Name prefix of the log's clear records holding the data key wrapped for
+/// each recipient the DB is shared with, see `LogReplicable::put_clear`
+const RECIPIENT_PREFIX: &str = "recipient_";
+
+/// Map keys starting with this are kept for hermitdb's own entries, the
+/// DB's `get`, `update` and `rm` refuse them.
+pub const RESERVED_PREFIX: &[u8] = b"\xffhermitdb/";
+
+/// Key of the replicated register holding the passphrase sealed data key
+const KEY_RECORD_KEY: &[u8] = b"\xffhermitdb/key_record";
+
+/// Name of the log's clear record holding the key record, a new device
+/// unseals the data key from it before it can read the ops, see
+/// `DB::open_with_passphrase`
+const KEY_RECORD_CLEAR: &str = "key_record";
+
+/// Store key of the local copy of the key record, it's needed to unlock the
+/// map so it's kept outside of it.
+const KEY_RECORD_STORE_KEY: &[u8] = b"key_record";
+
+/// A raw store key and value of the map in a snapshot, see
+/// `DB::export_snapshot`
+type SnapshotEntry = (Vec<u8>, Vec<u8>);
+
+/// Files and directories `DB::open` keeps under its directory
+const GIT_DIR: &str = "git";
+const SLED_DIR: &str = "sled";
+const ACTOR_FILE: &str = "actor";
+
+/// Describes a map op for git log commit messages, see
+/// `git_log::Log::with_summary`
+pub fn summarize_op(op: &map::Op<(Vec<u8>, Kind), Data, Actor>) -> OpSummary {
+    OpSummary {
+        kind: op.kind().to_string(),
+        key: op.key().map(|(key, kind)| format!("{}:{:?}", String::from_utf8_lossy(key), kind))
+    }
+}
+
+/// How `DB::open` sets up the log of a database directory
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// The remote name other logs know this one by, `actor_<id>` if None
+    pub name: Option<String>,
+    /// Where other logs fetch this one from, e.g. the url of the repo it's
+    /// pushed to. The DB's own git directory if None, which only reaches
+    /// logs on the same machine.
+    pub url: Option<String>,
+    /// The name and url of each log to sync with, see `DB::add_remote`
+    pub remotes: Vec<(String, String)>,
+    pub log: git_log::Options,
+    /// How `DB::open_with_passphrase` derives the key sealing a new DB's
+    /// data key, `KdfAlgo::recommended` if None
+    pub kdf_algo: Option<KdfAlgo>
+}
+
+pub struct DB<L, S = sled::Tree>
+    where S: KvStore,
+          L: LogReplicable<Actor, Map<S>>
+{
     log: L,
     remote_logs: Vec<L>,
-    map: Map
+    map: Map<S>
 }
 
-impl<L: LogReplicable<Actor, Map>> DB<L> {
-    pub fn new(log: L, map: Map) -> Self {
-        DB { log, remote_logs: Vec::new(), map }
+impl<L, S> DB<L, S>
+    where S: KvStore,
+          L: LogReplicable<Actor, Map<S>>
+{
+    /// Opens a DB over a log and the map materialized from it, maps
+    /// written by an older version of hermitdb are migrated first.
+    ///
+    /// The map records how far into each actor's log it has applied ops,
+    /// if we crashed between applying ops and acking them in the log, the
+    /// log's acks are moved to match the map.
+    pub fn new(log: L, map: Map<S>) -> Result<Self> {
+        let mut db = DB { log, remote_logs: Vec::new(), map };
+        db.map.migrate()?;
+        db.reconcile_cursors()?;
+        Ok(db)
+    }
+
+    /// The log the DB replicates through
+    pub fn log(&self) -> &L {
+        &self.log
     }
 
     pub fn get(&self, key: &(Vec<u8>, Kind)) -> Result<Option<Data>> {
+        check_user_key(key)?;
         self.map.get(key)
     }
 
+    pub fn get_with_meta(&self, key: &(Vec<u8>, Kind)) -> Result<Option<(Data, Meta<Actor>)>> {
+        check_user_key(key)?;
+        self.map.get_with_meta(key)
+    }
+
     pub fn update<F>(&mut self, key: (Vec<u8>, Kind), actor: Actor, updater: F) -> Result<()>
         where F: FnOnce(Data) -> Option<Op>
     {
-        let map_op = self.map.update(key, actor, updater)?;
-        let tagged_op = self.log.commit(map_op)?;
-        self.map.apply(tagged_op.op())?;
-        self.log.ack(&tagged_op)
+        check_user_key(&key)?;
+        self.update_entry(key, actor, updater)
     }
 
     pub fn rm(&mut self, key: (Vec<u8>, Kind), actor: Actor) -> Result<()> {
+        check_user_key(&key)?;
         let op = self.map.rm(key, actor)?;
         let tagged_op = self.log.commit(op)?;
-        self.map.apply(tagged_op.op())?;
-        self.log.ack(&tagged_op)
+        self.apply_and_ack(vec![tagged_op])
+    }
+
+    /// `update` without the check for reserved keys
+    fn update_entry<F>(&mut self, key: (Vec<u8>, Kind), actor: Actor, updater: F) -> Result<()>
+        where F: FnOnce(Data) -> Option<Op>
+    {
+        let map_op = self.map.update(key, actor, updater)?;
+        let tagged_op = self.log.commit(map_op)?;
+        self.apply_and_ack(vec![tagged_op])
+    }
+
+    /// Sync with `remote_log` along with the log we were opened with
+    pub fn add_remote(&mut self, remote_log: L) {
+        self.remote_logs.push(remote_log);
     }
 
     pub fn sync(&mut self) -> Result<()> {
-        for mut remote_log in self.remote_logs.iter_mut() {
-            self.log.pull(&remote_log)?;
-            self.log.push(&mut remote_log)?;
+        self.sync_with_progress(|_| ())
+    }
+
+    /// `sync`, reporting transfer progress and the number of ops about to
+    /// be applied to `progress` as it goes.
+    pub fn sync_with_progress<F>(&mut self, mut progress: F) -> Result<()>
+        where F: FnMut(Progress)
+    {
+        debug!("sync started; remotes={}", self.remote_logs.len());
+        for remote_log in self.remote_logs.iter_mut() {
+            self.log.pull_with_progress(remote_log, &mut progress)?;
+            self.log.push_with_progress(remote_log, &mut progress)?;
         }
 
-        while let Some(tagged_op) = self.log.next()? {
-            self.map.apply(tagged_op.op())?;
-            self.log.ack(&tagged_op)?;
+        let pending = self.log.pending()?;
+        debug!("sync applying ops; op_count={}", pending.len());
+        progress(Progress::Apply { ops_pending: pending.len() });
+        self.apply_and_ack(pending)
+    }
+
+    /// Applies the ops to the map along with the cursors of the last op from
+    /// each actor, only then are the ops acked in the log.
+    fn apply_and_ack(&mut self, tagged_ops: Vec<L::Op>) -> Result<()> {
+        if tagged_ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut ops = Vec::with_capacity(tagged_ops.len());
+        let mut cursors = BTreeMap::new();
+        let mut touches_key_record = false;
+        for tagged_op in tagged_ops.iter() {
+            touches_key_record |= tagged_op.op().key()
+                .map(|(key, _)| key.as_slice() == KEY_RECORD_KEY)
+                .unwrap_or(false);
+            ops.push(tagged_op.op().clone());
+            let (actor, cursor) = self.log.cursor(tagged_op)?;
+            cursors.insert(actor, cursor);
+        }
+
+        let mut meta = Vec::with_capacity(cursors.len());
+        for (actor, cursor) in cursors.into_iter() {
+            meta.push((cursor_key(&actor)?, cursor));
+        }
+        self.map.apply_batch_with_meta(&ops, meta)?;
+        if touches_key_record {
+            self.store_key_record()?;
+        }
+
+        for tagged_op in tagged_ops.iter() {
+            self.log.ack(tagged_op)?;
+        }
+        Ok(())
+    }
+
+    /// The key record as last synced, see `DB::create_with_passphrase`
+    pub fn key_record(&self) -> Result<Option<KeyRecord>> {
+        match self.map.get(&(KEY_RECORD_KEY.to_vec(), Kind::Reg))? {
+            Some(data) => match data.reg()?.val {
+                Prim::Blob(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+                _ => Err(Error::State("Key record entry is not a key record".into()))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Keeps the local copy of the key record in step with the replicated
+    /// one, so that a passphrase change on another device applies here too.
+    /// The record is also kept in the log's clear records for devices that
+    /// don't hold the data key yet.
+    fn store_key_record(&mut self) -> Result<()> {
+        let record_bytes = match self.key_record()? {
+            Some(record) => bincode::serialize(&record)?,
+            None => return Ok(())
+        };
+
+        let store_key = key_record_store_key();
+        if self.map.store().get(&store_key)?.as_ref() != Some(&record_bytes) {
+            self.log.put_clear(KEY_RECORD_CLEAR, Some(record_bytes.clone()))?;
+            self.map.store_mut().set(store_key, record_bytes)?;
+            self.map.store_mut().flush()?;
+        }
+        Ok(())
+    }
+
+    /// The cursor of the last op applied to the map from each actor
+    fn applied_cursors(&self) -> Result<BTreeMap<Actor, Cursor>> {
+        let mut applied = BTreeMap::new();
+        for res in self.map.scan_meta(CURSOR_PREFIX.to_vec()) {
+            let (key, cursor) = res?;
+            let actor: Actor = bincode::deserialize(&key[CURSOR_PREFIX.len()..])?;
+            applied.insert(actor, cursor);
+        }
+        Ok(applied)
+    }
+
+    /// The map is the source of truth for which ops have been applied, any
+    /// log ack it does not know about is rewound so the op is redelivered.
+    fn reconcile_cursors(&mut self) -> Result<()> {
+        let applied = self.applied_cursors()?;
+        let acked = self.log.cursors()?;
+        for (actor, cursor) in applied.iter() {
+            if acked.get(actor) != Some(cursor) {
+                self.log.reset_cursor(actor, Some(cursor))?;
+            }
+        }
+
+        for actor in acked.keys() {
+            if !applied.contains_key(actor) {
+                self.log.reset_cursor(actor, None)?;
+            }
         }
         Ok(())
     }
 }
+
+impl DB<git_log::Log<Actor, Map>> {
+    /// Opens the DB kept under `dir`, creating it if `dir` is empty or
+    /// missing. The DB is a bare git repo holding the log, a sled tree
+    /// holding the map and the actor id we commit ops as.
+    pub fn open(dir: &Path, opts: Options) -> Result<Self> {
+        let (log, remote_logs, tree) = open_dir(dir, opts)?;
+        let mut db = DB::new(log, map::Map::new(tree))?;
+        db.remote_logs = remote_logs;
+        Ok(db)
+    }
+}
+
+impl<S: KvStore + Debug> DB<git_log::Log<Actor, Map<S>>, S> {
+    /// Writes a snapshot of the map for a new device to join from, see
+    /// `join_snapshot`. Only the ops after the ones applied to the map are
+    /// bundled in full, see `git_log::Log::export_snapshot`.
+    pub fn export_snapshot(&self, path: &Path) -> Result<()> {
+        // entries and clock only, cursors travel in the bundle
+        let mut snapshot: Map<MemoryStore> = map::Map::new(MemoryStore::new());
+        snapshot.merge(&self.map)?;
+        let entries = snapshot.store().scan(&[]).collect::<Result<Vec<_>>>()?;
+        let state = bincode::serialize(&(map::FORMAT_VERSION, entries))?;
+        self.log.export_snapshot(path, &state, &self.applied_cursors()?)
+    }
+
+    /// Joins the DB from a snapshot written by another device's
+    /// `export_snapshot`. The snapshot's map is merged into ours and the
+    /// ops it holds are taken as applied, so syncing after this only reads
+    /// the ops that came after the snapshot and fetches only commits made
+    /// after it. `remote` names where the snapshot came from, as with
+    /// `git_log::Log::import_bundle`.
+    ///
+    /// Meant for a DB that has not synced yet, Err(State) if the snapshot
+    /// holds ops of an actor we already have ops from. Joining again from
+    /// the same snapshot is fine if a join fails halfway.
+    pub fn join_snapshot(&mut self, path: &Path, remote: &str) -> Result<()> {
+        let (state, cursors) = self.log.import_snapshot(path, remote)?;
+        let (format, entries): (u8, Vec<SnapshotEntry>) = bincode::deserialize(&state)?;
+        if format != map::FORMAT_VERSION {
+            return Err(Error::Version(format!("Unsupported snapshot map format: {}", format)));
+        }
+
+        let mut store = MemoryStore::new();
+        for (key, val) in entries.into_iter() {
+            store.set(key, val)?;
+        }
+        let snapshot: Map<MemoryStore> = map::Map::new(store);
+        self.map.merge(&snapshot)?;
+
+        let mut meta = Vec::with_capacity(cursors.len());
+        for (actor, cursor) in cursors.into_iter() {
+            meta.push((cursor_key(&actor)?, cursor));
+        }
+        self.map.apply_batch_with_meta(&[], meta)?;
+        self.store_key_record()?;
+
+        // moves the log's acks up to the snapshot
+        self.reconcile_cursors()?;
+        self.log.verify_remotes()
+    }
+}
+
+impl DB<git_log::Log<Actor, Map<EncryptedStore<sled::Tree>>>, EncryptedStore<sled::Tree>> {
+    /// `open` for a DB encrypted at rest, its data key is sealed under
+    /// `pass` (see `create_with_passphrase`) and seals the log's ops too.
+    /// Err(WrongPassword) if `pass` doesn't unseal the data key.
+    ///
+    /// A DB without a key record fetches one from `opts.remotes` and joins
+    /// the data key it seals, the entropy file of a device that has the DB
+    /// must be copied into `dir` first, the entropy is never replicated.
+    /// Only when every remote is reached and none has a key record is a new
+    /// data key picked, along with new entropy if `dir` has none.
+    pub fn open_with_passphrase(dir: &Path, opts: Options, pass: &[u8]) -> Result<Self> {
+        let kdf_algo = opts.kdf_algo.clone().unwrap_or_else(KdfAlgo::recommended);
+        let (log, remote_logs, tree) = open_dir(dir, opts)?;
+        let actor = *log.actor();
+
+        let (record_bytes, joined) = match EncryptedStore::get_clear(&tree, &key_record_store_key())? {
+            Some(record_bytes) => (Some(record_bytes), false),
+            None => {
+                for remote_log in remote_logs.iter() {
+                    log.fetch_unverified(remote_log)?;
+                }
+                let fetched = log.clear_records()?.remove(KEY_RECORD_CLEAR);
+                let joined = fetched.is_some();
+                (fetched, joined)
+            }
+        };
+
+        let mut db = match record_bytes {
+            Some(record_bytes) => {
+                let record: KeyRecord = bincode::deserialize(&record_bytes)?;
+                let entropy = crypto::read_entropy_file(dir)?;
+                let data_key = record.unseal(entropy, pass)?;
+                let sess = Session::new(actor, data_key.clone()).with_key_record(record, entropy);
+                DB::unlock(sealed_log(log, data_key), tree, sess)?
+            },
+            None => {
+                // the entropy outlives a creation that failed halfway
+                let entropy = match crypto::read_entropy_file(dir) {
+                    Ok(entropy) => entropy,
+                    Err(Error::IO(ref err)) if err.kind() == io::ErrorKind::NotFound =>
+                        crypto::create_entropy_file(dir)?,
+                    Err(err) => return Err(err)
+                };
+                let kdf = KDF {
+                    algo: kdf_algo,
+                    salt: crypto::rand_256()?,
+                    entropy
+                };
+                let data_key = MasterKey::generate()?;
+                let record = KeyRecord::seal(&data_key, &kdf, pass)?;
+                let sess = Session::new(actor, data_key.clone()).with_key_record(record.clone(), entropy);
+                let mut db = DB::unlock(sealed_log(log, data_key), tree, sess)?;
+                db.put_key_record(&record)?;
+                db
+            }
+        };
+        db.remote_logs = remote_logs;
+        if joined {
+            // brings in the replicated key record along with the data
+            db.sync()?;
+        }
+        Ok(db)
+    }
+}
+
+impl<L, S> DB<L, EncryptedStore<S>>
+    where S: KvStore,
+          L: LogReplicable<Actor, Map<EncryptedStore<S>>>
+{
+    /// Opens a DB whose map is encrypted at rest with the session's key,
+    /// see `store::EncryptedStore`.
+    pub fn unlock(log: L, store: S, sess: Session) -> Result<Self> {
+        let store = EncryptedStore::unlock(store, sess)?;
+        DB::new(log, map::Map::new(store))
+    }
+
+    /// Unwraps the data key shared with us (see `share_with`) and opens the
+    /// DB. The wrapped key comes from the log's clear records, pull from a
+    /// remote that has it before unlocking. Err(NotFound) if the DB was
+    /// not shared with `secret`.
+    pub fn unlock_with_secret_key(log: L, store: S, actor: Actor, secret: &SecretKey) -> Result<Self> {
+        let wrapped_bytes = log.clear_records()?
+            .remove(&recipient_record(&secret.public_key()))
+            .ok_or(Error::NotFound)?;
+        let wrapped: WrappedKey = bincode::deserialize(&wrapped_bytes)?;
+        let data_key = secret.unwrap_key(&wrapped)?;
+        DB::unlock(log, store, Session::new(actor, data_key))
+    }
+
+    /// Opens a new DB whose data key is random and sealed under `pass`, see
+    /// `crypto::KeyRecord`. The sealed key is replicated with the DB.
+    pub fn create_with_passphrase(log: L, store: S, actor: Actor, kdf: &KDF, pass: &[u8]) -> Result<Self> {
+        if EncryptedStore::get_clear(&store, &key_record_store_key())?.is_some() {
+            return Err(Error::State("Attempting to create a DB over an existing key record".into()));
+        }
+
+        let data_key = MasterKey::generate()?;
+        let record = KeyRecord::seal(&data_key, kdf, pass)?;
+        let sess = Session::new(actor, data_key).with_key_record(record.clone(), kdf.entropy);
+        let mut db = DB::unlock(log, store, sess)?;
+        db.put_key_record(&record)?;
+        Ok(db)
+    }
+
+    /// Unseals the data key with `pass` and opens the DB,
+    /// Err(WrongPassword) if `pass` is not the current passphrase.
+    pub fn unlock_with_passphrase(log: L, store: S, actor: Actor, entropy: [u8; 256 / 8], pass: &[u8]) -> Result<Self> {
+        let record_bytes = EncryptedStore::get_clear(&store, &key_record_store_key())?
+            .ok_or(Error::NotFound)?;
+        let record: KeyRecord = bincode::deserialize(&record_bytes)?;
+        let data_key = record.unseal(entropy, pass)?;
+        let sess = Session::new(actor, data_key).with_key_record(record, entropy);
+        DB::unlock(log, store, sess)
+    }
+
+    /// Opens the DB with a code printed by `recovery_code` and seals the
+    /// data key under `new_pass` with `kdf`, for when the passphrase or the
+    /// entropy kept with it is lost. The new key record replaces the old one
+    /// on every device.
+    pub fn recover_with_code(log: L, store: S, actor: Actor, code: &str, kdf: &KDF, new_pass: &[u8]) -> Result<Self> {
+        let sess = crypto::recover_from_code(actor, code)?;
+        DB::recover(log, store, sess, kdf, new_pass)
+    }
+
+    /// `recover_with_code` from enough shares of a key split with
+    /// `crypto::split_key`
+    pub fn recover_with_shares(log: L, store: S, actor: Actor, shares: &[KeyShare], kdf: &KDF, new_pass: &[u8]) -> Result<Self> {
+        let sess = crypto::recover_from_shares(actor, shares)?;
+        DB::recover(log, store, sess, kdf, new_pass)
+    }
+
+    fn recover(log: L, store: S, sess: Session, kdf: &KDF, new_pass: &[u8]) -> Result<Self> {
+        let record = KeyRecord::seal(&sess.master_key()?, kdf, new_pass)?;
+        let sess = sess.with_key_record(record.clone(), kdf.entropy);
+        let mut db = DB::unlock(log, store, sess)?;
+        db.put_key_record(&record)?;
+        Ok(db)
+    }
+
+    /// Prints the data key as a recovery code, see `crypto::recovery_code`.
+    /// The code holds the data key itself, it stops working once the data
+    /// key is rotated.
+    pub fn recovery_code(&self) -> Result<String> {
+        Ok(crypto::recovery_code(&self.map.store().session().master_key()?))
+    }
+
+    /// Seals the data key under `new_pass`, only the key record is rewritten
+    pub fn change_passphrase(&mut self, entropy: [u8; 256 / 8], old_pass: &[u8], new_pass: &[u8]) -> Result<()> {
+        let record = self.key_record()?
+            .ok_or(Error::State("DB has no key record, it was not created with a passphrase".into()))?;
+        let new_record = record.change_passphrase(entropy, old_pass, new_pass)?;
+        self.put_key_record(&new_record)?;
+        self.map.store_mut().session_mut().set_key_record(new_record, entropy);
+        Ok(())
+    }
+
+    /// Moves the data to a new random data key sealed under `pass`, see
+    /// `rotate_key`. Use this over `rotate_key` for DBs with a key record.
+    ///
+    /// Recovery codes and key shares hold the data key, the ones printed
+    /// before this no longer open the DB. Print new ones with
+    /// `recovery_code` or `crypto::split_key` afterwards.
+    pub fn rotate_data_key(&mut self, entropy: [u8; 256 / 8], pass: &[u8]) -> Result<()> {
+        let record = self.key_record()?
+            .ok_or(Error::State("DB has no key record, it was not created with a passphrase".into()))?;
+        record.unseal(entropy, pass)?;
+
+        let data_key = MasterKey::generate()?;
+        let kdf = KDF {
+            algo: record.kdf.algo.clone(),
+            salt: crypto::rand_256()?,
+            entropy
+        };
+        let new_record = KeyRecord::seal(&data_key, &kdf, pass)?;
+        self.rotate_key(data_key)?;
+        self.put_key_record(&new_record)?;
+        self.map.store_mut().session_mut().set_key_record(new_record, entropy);
+        Ok(())
+    }
+
+    fn put_key_record(&mut self, record: &KeyRecord) -> Result<()> {
+        let actor = self.map.store().session().actor;
+        let record_bytes = bincode::serialize(&record)?;
+        self.update_entry((KEY_RECORD_KEY.to_vec(), Kind::Reg), actor, |data| {
+            let mut reg = data.reg().ok()?;
+            let marker = (reg.marker.0 + 1, actor);
+            reg.update(Prim::Blob(record_bytes), marker).ok()?;
+            Some(Op::Reg(reg))
+        })
+    }
+
+    /// Wipes the session's keys, reads and writes fail with `Error::Locked`
+    /// until the session is unlocked again.
+    pub fn lock(&mut self) {
+        self.map.store_mut().session_mut().lock()
+    }
+
+    /// Unlocks the session with its password, see `Session::unlock`
+    pub fn unlock_session(&mut self, pass: &[u8]) -> Result<()> {
+        self.map.store_mut().session_mut().unlock(pass)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.map.store().session().is_locked()
+    }
+
+    /// Re-encrypts the map under `new_key`, see `EncryptedStore::rotate_key`.
+    /// The new key is wrapped again for everyone the DB is shared with.
+    ///
+    /// A log that seals its ops seals new ops with `new_key`. Ops already in
+    /// the log are not rewritten, others may have fetched them, they stay
+    /// readable with the retired key, see `LogReplicable::rotate_key`.
+    pub fn rotate_key(&mut self, new_key: MasterKey) -> Result<()> {
+        let log_key = new_key.clone();
+        self.map.store_mut().rotate_key(new_key)?;
+        self.log.rotate_key(&log_key)?;
+
+        let recipients: Vec<PublicKey> = self.recipients()?
+            .into_iter()
+            .map(|wrapped| wrapped.recipient)
+            .collect();
+        for recipient in recipients.iter() {
+            self.share_with(recipient)?;
+        }
+        Ok(())
+    }
+
+    /// Wraps the data key for `recipient`. The wrapped key is kept in the
+    /// log's clear records so they can read it before they hold the data
+    /// key, see `unlock_with_secret_key`.
+    pub fn share_with(&mut self, recipient: &PublicKey) -> Result<()> {
+        let wrapped = WrappedKey::wrap(&self.map.store().session().master_key()?, recipient)?;
+        let wrapped_bytes = bincode::serialize(&wrapped)?;
+        self.log.put_clear(&recipient_record(recipient), Some(wrapped_bytes))
+    }
+
+    /// Stops wrapping the data key for `recipient`. They may still hold the
+    /// current key, `rotate_key` afterwards to keep them out of new data.
+    /// Only our own wrap is removed, other actors who shared the DB with
+    /// `recipient` have to unshare too.
+    pub fn unshare(&mut self, recipient: &PublicKey) -> Result<()> {
+        self.log.put_clear(&recipient_record(recipient), None)
+    }
+
+    /// The data key wrapped for each recipient the DB is shared with
+    pub fn recipients(&self) -> Result<Vec<WrappedKey>> {
+        let mut recipients = Vec::new();
+        for (name, bytes) in self.log.clear_records()? {
+            if name.starts_with(RECIPIENT_PREFIX) {
+                recipients.push(bincode::deserialize(&bytes)?);
+            }
+        }
+        Ok(recipients)
+    }
+}
+
+fn check_user_key(key: &(Vec<u8>, Kind)) -> Result<()> {
+    if key.0.starts_with(RESERVED_PREFIX) {
+        return Err(Error::State("Keys under RESERVED_PREFIX are kept for hermitdb".into()));
+    }
+    Ok(())
+}
+
+type DirLog<S> = git_log::Log<Actor, Map<S>>;
+
+/// The log, the remote logs and the sled tree kept under `dir`, created if
+/// missing along with the actor id, see `DB::open`
+fn open_dir<S: KvStore + Debug>(dir: &Path, opts: Options) -> Result<(DirLog<S>, Vec<DirLog<S>>, sled::Tree)> {
+    fs::create_dir_all(dir)?;
+    let actor = match read_actor_file(dir) {
+        Ok(actor) => actor,
+        Err(Error::IO(ref err)) if err.kind() == io::ErrorKind::NotFound =>
+            create_actor_file(dir)?,
+        Err(err) => return Err(err)
+    };
+
+    let git_dir = dir.join(GIT_DIR);
+    let repo = if git_dir.is_dir() {
+        git2::Repository::open_bare(&git_dir)?
+    } else {
+        info!("creating git repo; path={}", git_dir.display());
+        git2::Repository::init_bare(&git_dir)?
+    };
+    let url = match opts.url {
+        Some(url) => url,
+        None => git_dir.to_str()
+            .ok_or(Error::State("DB path is not utf8".into()))?
+            .to_string()
+    };
+    let name = opts.name.unwrap_or_else(|| format!("actor_{}", actor));
+    let log = git_log::Log::no_auth(actor, repo, name, url)
+        .with_options(opts.log)
+        .with_summary(summarize_op);
+
+    // a remote log is only read for its name and url
+    let mut remote_logs = Vec::with_capacity(opts.remotes.len());
+    for (name, url) in opts.remotes.into_iter() {
+        let repo = git2::Repository::open_bare(&git_dir)?;
+        remote_logs.push(git_log::Log::no_auth(actor, repo, name, url));
+    }
+
+    let config = sled::ConfigBuilder::new()
+        .path(dir.join(SLED_DIR))
+        .build();
+    let tree = sled::Tree::start(config)?;
+    Ok((log, remote_logs, tree))
+}
+
+/// `log` with its ops sealed under `data_key`, see `git_log::Options::sealed`
+fn sealed_log<S: KvStore + Debug>(log: DirLog<S>, data_key: MasterKey) -> DirLog<S> {
+    let opts = log.options().clone().sealed(data_key);
+    log.with_options(opts)
+}
+
+/// Will return Err(IO) with NotFound if the actor file does not exist
+fn read_actor_file(dir: &Path) -> Result<Actor> {
+    let mut bytes = Vec::new();
+    fs::File::open(dir.join(ACTOR_FILE))?.read_to_end(&mut bytes)?;
+    if bytes.len() != 128 / 8 {
+        return Err(Error::State("actor file must contain exactly 128 bits".into()));
+    }
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// Picks a random actor id and saves it, Err if the actor file exists
+fn create_actor_file(dir: &Path) -> Result<Actor> {
+    let actor_filepath = dir.join(ACTOR_FILE);
+    if actor_filepath.is_file() {
+        return Err(Error::State("Attempting to create an actor file when one exists".into()));
+    }
+
+    let mut actor: Actor = 0;
+    for byte in crypto::rand_256()?[..128 / 8].iter() {
+        actor = (actor << 8) | *byte as Actor;
+    }
+    let mut f = fs::File::create(actor_filepath)?;
+    f.write_all(&bincode::serialize(&actor)?)?;
+    f.sync_all()?;
+    info!("created actor; actor={}", actor);
+    Ok(actor)
+}
+
+fn key_record_store_key() -> Vec<u8> {
+    let mut key = CLEAR_PREFIX.to_vec();
+    key.extend_from_slice(KEY_RECORD_STORE_KEY);
+    key
+}
+
+fn recipient_record(recipient: &PublicKey) -> String {
+    format!("{}{}", RECIPIENT_PREFIX, encoding::encode(&recipient.0))
+}
+
+fn cursor_key(actor: &Actor) -> Result<Vec<u8>> {
+    let mut key = CURSOR_PREFIX.to_vec();
+    key.extend(bincode::serialize(actor)?);
+    Ok(key)
+}
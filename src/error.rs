@@ -6,6 +6,7 @@ extern crate sled;
 
 use std::{self, fmt};
 use data::Kind;
+use git_log::PushResult;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -19,9 +20,16 @@ pub enum Error {
     BranchNameEncodingError,
     BranchIsNotADirectReference,
     LogCommitDoesNotContainOp,
+    NonLinearHistory(String),
+    RewrittenHistory(String),
+    /// The branches a remote refused, see `git_log::Log::push_refs`
+    PushRejected(Vec<PushResult>),
     Parse(String),
     Crypto(String),
     Version(String),
+    WrongPassword,
+    Locked,
+    Corrupt(String),
     State(String),
     Bincode(bincode::Error),
     CRDT(crdts::Error),
@@ -32,7 +40,7 @@ pub enum Error {
 }
 
 impl fmt::Display for Error {
-    fn fmt(&self, mut f: &mut fmt::Formatter) -> fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::NotFound =>
                 write!(f, "Key not found"),
@@ -46,6 +54,17 @@ impl fmt::Display for Error {
                 write!(f, "A branch reference isn't a direct ref to an oid"),
             Error::LogCommitDoesNotContainOp =>
                 write!(f, "Trees attached to commits in git are expected to have an 'op' entry"),
+            Error::NonLinearHistory(s) =>
+                write!(f, "A log branch is not a linear history: {}", s),
+            Error::RewrittenHistory(s) =>
+                write!(f, "A log branch was rewritten: {}", s),
+            Error::PushRejected(results) => {
+                write!(f, "The remote rejected the push of")?;
+                for res in results.iter() {
+                    write!(f, " {} ({:?})", res.branch, res.status)?;
+                }
+                Ok(())
+            },
             Error::DaoField(s) =>
                 write!(f, "Dao Field error: {}", s),
             Error::Parse(s) =>
@@ -54,19 +73,26 @@ impl fmt::Display for Error {
                 write!(f, "Crypto failure: {}", s),
             Error::Version(s) =>
                 write!(f, "Version failure: {}", s),
+            Error::WrongPassword =>
+                write!(f, "The password does not unlock this key"),
+            Error::Locked =>
+                write!(f, "The session is locked"),
+            Error::Corrupt(s) =>
+                write!(f, "Data is corrupt: {}", s),
             Error::State(s) =>
                 write!(f, "Gitdb entered a bad state: {}", s),
-            Error::Bincode(e) => e.fmt(&mut f),
-            Error::CRDT(e) => e.fmt(&mut f),
-            Error::Git(e) => e.fmt(&mut f),
-            Error::IO(e) => e.fmt(&mut f),
-            Error::DataEncodingDecode(e) => e.fmt(&mut f),
-            Error::SledGeneric(e) => e.fmt(&mut f)
+            Error::Bincode(e) => e.fmt(f),
+            Error::CRDT(e) => e.fmt(f),
+            Error::Git(e) => e.fmt(f),
+            Error::IO(e) => e.fmt(f),
+            Error::DataEncodingDecode(e) => e.fmt(f),
+            Error::SledGeneric(e) => e.fmt(f)
         }
     }
 }
 
 impl std::error::Error for Error {
+    #[allow(deprecated)]
     fn description(&self) -> &str {
         match self {
             Error::NotFound => "Key was not found",
@@ -78,11 +104,17 @@ impl std::error::Error for Error {
                 "A branch reference isn't a direct ref to an oid",
             Error::LogCommitDoesNotContainOp =>
                 "Trees attached to commits in git are expected to have an 'op' entry",
+            Error::NonLinearHistory(_) => "A log branch is not a linear history",
+            Error::RewrittenHistory(_) => "A log branch was rewritten",
+            Error::PushRejected(_) => "The remote rejected a push",
             Error::DaoField(_) =>
                 "Problem with field while processing Dao request",
             Error::Parse(_) => "Parsing failed",
             Error::Crypto(_) => "Crypto failure",
             Error::Version(_) => "Version failure",
+            Error::WrongPassword => "The password does not unlock this key",
+            Error::Locked => "The session is locked",
+            Error::Corrupt(_) => "Data is corrupt",
             Error::State(_) => "Gitdb entered a bad state",
             Error::Bincode(e) => e.description(),
             Error::CRDT(e) => e.description(),
@@ -92,7 +124,7 @@ impl std::error::Error for Error {
             Error::SledGeneric(e) => e.description()
         }
     }
-    fn cause(&self) -> Option<&std::error::Error> {
+    fn cause(&self) -> Option<&dyn std::error::Error> {
         match self {
             Error::NotFound => None,
             Error::NoRemote => None,
@@ -100,10 +132,16 @@ impl std::error::Error for Error {
             Error::BranchNameEncodingError => None,
             Error::BranchIsNotADirectReference => None,
             Error::LogCommitDoesNotContainOp => None,
+            Error::NonLinearHistory(_) => None,
+            Error::RewrittenHistory(_) => None,
+            Error::PushRejected(_) => None,
             Error::DaoField(_) => None,
             Error::Parse(_) => None,
             Error::Crypto(_) => None,
             Error::Version(_) => None,
+            Error::WrongPassword => None,
+            Error::Locked => None,
+            Error::Corrupt(_) => None,
             Error::State(_) => None,
             Error::Bincode(e) => Some(e),
             Error::CRDT(e) => Some(e),
@@ -8,31 +8,28 @@ use error::{Result, Error};
 use remote::Remote;
 
 pub fn fetch<'a>(repo: &'a Repository, remote: &Remote) -> Result<git2::Remote<'a>> {
-    eprintln!("fetching remote: {}", &remote.name);
+    debug!("fetch started; remote={}", remote.name);
 
-    eprintln!("searching for existing remote in repo");
     let mut git_remote = match repo.find_remote(&remote.name) {
         Ok(git_remote) => git_remote,
         Err(_) => {
-            eprintln!("Failed to find remote '{}', adding remote to git", remote.name);
+            info!("adding git remote; remote={}", remote.name);
             // this remote is not added to git yet, we add it
             repo.remote(&remote.name, &remote.url)?
         }
     };
 
-    eprintln!("found a remote, starting fetch...");
-    
     let mut fetch_opt = git2::FetchOptions::new();
     fetch_opt.remote_callbacks(remote.git_callbacks());
     git_remote.fetch(&["master"], Some(&mut fetch_opt), None)?;
 
-    eprintln!("finished fetch");
+    debug!("fetch finished; remote={}", remote.name);
 
     Ok(git_remote)
 }
 
 pub fn commit(repo: &Repository, msg: &str, extra_parents: &[&Commit]) -> Result<()> {
-    eprintln!("committing");
+    trace!("commit; message={}", msg);
 
     let mut index = repo.index()?;
     let tree = index.write_tree()
@@ -41,7 +38,7 @@ pub fn commit(repo: &Repository, msg: &str, extra_parents: &[&Commit]) -> Result
     let parent: Option<Commit> = match repo.head() {
         Ok(head_ref) => {
             let head_oid = head_ref.target()
-                .ok_or(Error::State(format!("Failed to find oid referenced by HEAD")))?;
+                .ok_or(Error::State("Failed to find oid referenced by HEAD".to_string()))?;
             let head_commit = repo.find_commit(head_oid)?;
             Some(head_commit)
         },
@@ -53,13 +50,13 @@ pub fn commit(repo: &Repository, msg: &str, extra_parents: &[&Commit]) -> Result
             let prev_tree = commit.tree()?;
             let stats = repo.diff_tree_to_tree(Some(&tree), Some(&prev_tree), None)?.stats()?;
             if stats.files_changed() == 0 {
-                eprintln!("aborting commit, no files changed");
+                debug!("commit skipped, no files changed");
                 return Ok(())
             }
         },
         None => {
             if index.is_empty() {
-                eprintln!("aborting commit, Index is empty, nothing to commit");
+                debug!("commit skipped, the index is empty");
                 return Ok(());
             }
         }
@@ -73,13 +70,13 @@ pub fn commit(repo: &Repository, msg: &str, extra_parents: &[&Commit]) -> Result
     }
     parent_commits.extend(extra_parents);
     
-    repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &parent_commits)?;
+    repo.commit(Some("HEAD"), &sig, &sig, msg, &tree, &parent_commits)?;
     Ok(())
 }
 
 pub fn stage_file(repo: &Repository, file: &Path) -> Result<()> {
     let mut index = repo.index()?;
-    index.add_path(&file)?;
+    index.add_path(file)?;
     index.write()?;
     Ok(())
 }
@@ -92,17 +89,17 @@ pub fn stage_globs(repo: &Repository, globs: &[&str]) -> Result<()> {
 }
 
 pub fn fast_forward(repo: &Repository, branch: &git2::Branch) -> Result<()> {
-    eprintln!("fast forwarding repository to match branch {:?}", branch.name()?);
+    debug!("fast forward; branch={:?}", branch.name()?);
     let remote_commit_oid = branch.get().resolve()?.target()
         .ok_or(Error::State("remote ref didn't resolve to commit".into()))?;
 
     let remote_commit = repo.find_commit(remote_commit_oid)?;
 
     if let Ok(branch) = repo.find_branch("master", git2::BranchType::Local) {
-        let mut branch_ref = &mut branch.into_reference();
+        let branch_ref = &mut branch.into_reference();
         branch_ref.set_target(remote_commit_oid, "fast forward")?;
     } else {
-        eprintln!("creating local master branch");
+        debug!("creating local master branch");
         repo.branch("master", &remote_commit, false)?;
     }
     repo.set_head("refs/heads/master")?;
@@ -110,17 +107,15 @@ pub fn fast_forward(repo: &Repository, branch: &git2::Branch) -> Result<()> {
     Ok(())
 }
 
-pub fn sync<'a>(repo: &Repository, remote: &Remote, mut merger: &mut (FnMut(git2::DiffDelta, f32) -> bool + 'a)) -> Result<()> {
+pub fn sync<'a>(repo: &Repository, remote: &Remote, mut merger: &mut (dyn FnMut(git2::DiffDelta, f32) -> bool + 'a)) -> Result<()> {
     // we assume all files to be synced have already been added to the index
-    commit(&repo, "sync commit from site", &[])?;
+    commit(repo, "sync commit from site", &[])?;
 
     // fetch and merge
-    let mut git_remote = fetch(&repo, &remote)?;
+    let mut git_remote = fetch(repo, remote)?;
 
-    eprintln!("searching for remote master branch");
-    let remote_master_ref = format!("{}/master", &remote.name);
+    let remote_master_ref = format!("{}/master", remote.name);
     if let Ok(branch) = repo.find_branch(&remote_master_ref, git2::BranchType::Remote) {
-        eprintln!("found remote master branch");
         let remote_commit_oid = branch.get().resolve()?.target()
             .ok_or(Error::State("remote ref didn't resolve to commit".into()))?;
 
@@ -138,36 +133,24 @@ pub fn sync<'a>(repo: &Repository, remote: &Remote, mut merger: &mut (FnMut(git2
 
             // TODO: see if there are any diff options we can use to speed up the diff
             let diff = repo.diff_tree_to_index(Some(&remote_tree), None, None)?;
-            eprintln!("iterating foreach");
+            trace!("merging remote tree; remote={}", remote.name);
             diff.foreach(&mut merger, None, None, None)?;
-            commit(&repo, "merge commit", &[&remote_commit])?;
+            commit(repo, "merge commit", &[&remote_commit])?;
         } else if analysis.contains(MergeAnalysis::ANALYSIS_FASTFORWARD) {
-            fast_forward(&repo, &branch)?;
+            fast_forward(repo, &branch)?;
         } else if analysis == git2::MergeAnalysis::ANALYSIS_UP_TO_DATE {
-            eprintln!("nothing to merge, ahead of remote");
+            debug!("nothing to merge, ahead of remote; remote={}", remote.name);
         } else {
             return Err(Error::State(format!("Bad merge analysis result: {:?}", analysis)));
         }
     } else {
-        eprintln!("no remote branch: {}", remote.name);
-    }
-
-    eprintln!("pushing git_remote");
-
-    eprintln!("just for fun, here are the current references:");
-
-    for reference in repo.references()? {
-        let reference = reference.unwrap();
-        let name = reference.name().unwrap();
-        let target = reference.target().unwrap();
-        eprintln!("ref: {} => {}", name, target);
+        debug!("remote has no master branch; remote={}", remote.name);
     }
 
-    eprintln!("finished printing references");
-    
+    debug!("push started; remote={}", remote.name);
     let mut push_opt = git2::PushOptions::new();
     push_opt.remote_callbacks(remote.git_callbacks());
-    git_remote.push(&[&"refs/heads/master"], Some(&mut push_opt))?;
-    eprintln!("Finish push");
+    git_remote.push(&["refs/heads/master"], Some(&mut push_opt))?;
+    debug!("push finished; remote={}", remote.name);
     Ok(())
 }
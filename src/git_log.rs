@@ -1,19 +1,40 @@
 extern crate bincode;
 extern crate serde;
+extern crate ring;
+extern crate flate2;
 
 use std::str::FromStr;
 use std::string::ToString;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::fs;
 
 use self::serde::de::DeserializeOwned;
 use self::serde::Serialize;
+use self::ring::{digest, hmac};
+use self::flate2::Compression;
+use self::flate2::read::DeflateDecoder;
+use self::flate2::write::DeflateEncoder;
 
 use git2;
 
 use error::{Error, Result};
-use crdts::{CmRDT, Actor};
-use log::{TaggedOp, LogReplicable};
+use encoding;
+use crypto::{Session, MasterKey, Plaintext, Encrypted, Context};
+use log::{Actor, CmRDT, TaggedOp, LogReplicable, Cursor, Progress};
+
+/// Format of the op in each log commit, kept in the commit's `format`
+/// blob. bincode can't tell one shape from another so it's bumped whenever
+/// the op changes shape, ops in older formats go through `CmRDT::upgrade_op`.
+///
+/// 0: commits made before op formats were versioned, they have no `format`.
+/// 1: map update ops name their actor.
+const OP_FORMAT: u8 = 1;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Auth {
@@ -21,6 +42,216 @@ pub struct Auth {
     pass: String
 }
 
+/// How the branches holding each actor's ops are named
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RefNames {
+    /// `actor_<actor>` and `acked_actor_<actor>`, readable by the remote host
+    Plain,
+    /// `log_<hash>` and `acked_<hash>` where the hash is keyed with the given
+    /// key, the remote host can't tell which actor a branch belongs to.
+    /// Commits carry their actor sealed, under the op key when ops are sealed
+    /// and under a key derived from the ref key otherwise, so that other
+    /// replicas holding the key can recover it.
+    Hashed(Vec<u8>)
+}
+
+/// Where in the repo the log keeps its refs
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Namespace {
+    /// Branches under `refs/heads/`, fetched into `refs/remotes/<remote>/`.
+    /// Every branch in the repo is treated as part of the log.
+    Heads,
+    /// Refs under `refs/hermitdb/<db>/local/`, fetched into
+    /// `refs/hermitdb/<db>/remotes/<remote>/`. Nothing else in the repo is
+    /// read or pushed, so several databases and ordinary branches can share
+    /// one repo.
+    Db(String)
+}
+
+impl Namespace {
+    /// Prefix of the refs the log commits to and acks with
+    pub fn local_prefix(&self) -> String {
+        match self {
+            Namespace::Heads => "refs/heads/".into(),
+            Namespace::Db(db) => format!("refs/hermitdb/{}/local/", db)
+        }
+    }
+
+    /// Prefix of the refs fetched from remotes, followed by `<remote>/`
+    pub fn remotes_prefix(&self) -> String {
+        match self {
+            Namespace::Heads => "refs/remotes/".into(),
+            Namespace::Db(db) => format!("refs/hermitdb/{}/remotes/", db)
+        }
+    }
+
+    /// Prefix of quarantined remote branches, see `Options::quarantine`
+    pub fn quarantine_prefix(&self) -> String {
+        match self {
+            Namespace::Heads => "refs/hermitdb/quarantine/".into(),
+            Namespace::Db(db) => format!("refs/hermitdb/{}/quarantine/", db)
+        }
+    }
+
+    pub fn fetch_refspec(&self, remote: &str) -> String {
+        format!("+{}*:{}{}/*", self.local_prefix(), self.remotes_prefix(), remote)
+    }
+
+    pub fn push_refspec(&self, branch: &str) -> String {
+        let local_ref = format!("{}{}", self.local_prefix(), branch);
+        format!("{}:{}", local_ref, local_ref)
+    }
+}
+
+/// How a pushed branch fared, see `Log::push_refs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushStatus {
+    /// The remote branch now points at our tip
+    Updated,
+    /// The remote branch already held everything we have
+    UpToDate,
+    /// The remote refused the update and our history diverged from the
+    /// remote's, so it was not retried
+    Rejected(String)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushResult {
+    pub branch: String,
+    pub status: PushStatus
+}
+
+impl PushResult {
+    pub fn is_rejected(&self) -> bool {
+        matches!(self.status, PushStatus::Rejected(_))
+    }
+}
+
+/// Starts every bundle file, followed by the bundle version and the
+/// deflated bundle, see `Log::export_bundle`
+const BUNDLE_MAGIC: &[u8] = b"hermitdb bundle\n";
+
+/// 1: commits, trees and blobs of the bundled branches
+/// 2: snapshots, bundles may carry the sender's state and cursors
+const BUNDLE_VERSION: u8 = 2;
+
+/// Commits of a log's branches along with the trees and blobs they point
+/// to, for moving ops between repos that can't reach each other.
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    refs: Vec<(String, Vec<u8>)>,
+    /// Commits the bundle builds on without carrying them
+    prerequisites: Vec<Vec<u8>>,
+    objects: Vec<(u8, Vec<u8>)>,
+    /// Set in snapshots only, see `Log::export_snapshot`
+    snapshot: Option<Snapshot>
+}
+
+/// State built from a log up to `cursors`, the commits before the cursors
+/// are bundled without their trees and blobs.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Actors and the commit of their last op the state holds
+    cursors: Vec<(String, Vec<u8>)>,
+    /// The state, sealed when the log seals its ops
+    state: Vec<u8>
+}
+
+/// Who commits to the log, see `Options::identity`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Identity {
+    /// A name and email made up from the actor, or from its ref hash when
+    /// ref names are hashed. Commit times are given in UTC.
+    Pseudonymous,
+    /// The repo's `user.name` and `user.email`
+    RepoConfig,
+    Named {
+        name: String,
+        email: String
+    }
+}
+
+/// Describes an op in a commit message, see `Log::with_summary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpSummary {
+    pub kind: String,
+    /// Only shown in commit messages with `Options::disclose_keys`
+    pub key: Option<String>
+}
+
+/// Layout version of sealed ops, authenticated with each of them
+const OP_SCHEMA: u8 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Options {
+    pub ref_names: RefNames,
+    /// Push every local branch, including the ones recording what we acked.
+    /// When false only our own ops are pushed, ack progress stays local.
+    pub push_acks: bool,
+    pub namespace: Namespace,
+    /// When a fetched branch has merge commits, was rewritten or holds
+    /// commits without ops, move it aside and carry on with the other
+    /// branches instead of failing. See `Log::quarantined`.
+    pub quarantine: bool,
+    pub identity: Identity,
+    /// Commit message, `{kind}` and `{key}` are replaced with the op's
+    /// summary. `{key}` is left empty unless `disclose_keys` is set.
+    pub message: String,
+    /// Let `{key}` name the key each op touches in the clear. Off by
+    /// default, and ignored while ops are sealed.
+    pub disclose_keys: bool,
+    /// Seal op blobs with this key. Each op is bound to its actor and the
+    /// commit it follows, so ops replayed or moved between logs by the
+    /// remote host fail to open.
+    pub op_key: Option<MasterKey>,
+    /// Keys ops were sealed with before `Log::rotate_key`, ops are tagged
+    /// with the id of their key so these still open them.
+    pub retired_op_keys: Vec<MasterKey>,
+    /// Largest a bundle may be once inflated, larger bundles are refused
+    /// by `Log::export_bundle` and `Log::import_bundle`
+    pub max_bundle_size: u64
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            ref_names: RefNames::Plain,
+            push_acks: true,
+            namespace: Namespace::Heads,
+            quarantine: false,
+            identity: Identity::Pseudonymous,
+            message: "db {kind}".into(),
+            disclose_keys: false,
+            op_key: None,
+            retired_op_keys: Vec::new(),
+            max_bundle_size: 1 << 30
+        }
+    }
+}
+
+impl Options {
+    /// Hashed ref names with acks kept local
+    pub fn private(ref_key: Vec<u8>) -> Self {
+        Options {
+            ref_names: RefNames::Hashed(ref_key),
+            push_acks: false,
+            ..Options::default()
+        }
+    }
+
+    /// Keep the log's refs under `refs/hermitdb/<db_name>/`
+    pub fn namespaced(mut self, db_name: &str) -> Self {
+        self.namespace = Namespace::Db(db_name.to_string());
+        self
+    }
+
+    /// Seal op blobs with `key`, see `op_key`
+    pub fn sealed(mut self, key: MasterKey) -> Self {
+        self.op_key = Some(key);
+        self
+    }
+}
+
 pub struct Log<A: Actor, C: Debug + CmRDT>
     where C::Op : DeserializeOwned + Serialize + Eq
 {
@@ -29,26 +260,59 @@ pub struct Log<A: Actor, C: Debug + CmRDT>
     url: String,
     auth: Option<Auth>,
     repo: git2::Repository,
+    opts: Options,
+    summarize: Option<fn(&C::Op) -> OpSummary>,
     phantom_crdt: PhantomData<C>
 }
 
-#[serde(bound(deserialize = ""))]
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Op<A: Actor, C: Debug + CmRDT + Eq>
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Op<A: Actor, C: Debug + CmRDT>
     where C::Op : DeserializeOwned + Serialize + Eq
 {
     actor: A,
-    oid: Vec<u8>, // the object id of the commit with this op
+    /// The object id of the commit with this op
+    #[serde(with = "oid_bytes")]
+    oid: git2::Oid,
     op: C::Op
 }
 
-impl<A: Actor, C: Debug + CmRDT + Eq> TaggedOp<C> for Op<A, C>
+// Spelled out so ops compare without asking `C` itself to be `Eq`
+impl<A: Actor, C: Debug + CmRDT> PartialEq for Op<A, C>
+    where C::Op : DeserializeOwned + Serialize + Eq
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.actor == other.actor && self.oid == other.oid && self.op == other.op
+    }
+}
+
+impl<A: Actor, C: Debug + CmRDT> Eq for Op<A, C>
+    where C::Op : DeserializeOwned + Serialize + Eq
+{}
+
+/// Commit ids are kept as their raw bytes, checked to be an id when read
+mod oid_bytes {
+    use super::serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use super::serde::de::Error;
+    use git2;
+
+    pub fn serialize<S: Serializer>(oid: &git2::Oid, serializer: S) -> Result<S::Ok, S::Error> {
+        oid.as_bytes().to_vec().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<git2::Oid, D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        git2::Oid::from_bytes(&bytes).map_err(|err| D::Error::custom(err.message()))
+    }
+}
+
+impl<A: Actor, C: Debug + CmRDT> TaggedOp<C> for Op<A, C>
     where C::Op : DeserializeOwned + Serialize + Eq
 {
     type ID = git2::Oid;
 
     fn id(&self) -> Self::ID {
-        git2::Oid::from_bytes(&self.oid).unwrap()
+        self.oid
     }
 
     fn op(&self) -> &C::Op {
@@ -56,132 +320,210 @@ impl<A: Actor, C: Debug + CmRDT + Eq> TaggedOp<C> for Op<A, C>
     }
 }
 
-impl<A: Actor, C: Debug + CmRDT + Eq> Op<A, C>
+impl<A: Actor + ToString, C: Debug + CmRDT> Op<A, C>
     where C::Op : DeserializeOwned + Serialize + Eq
 {
-    pub fn from_commit(actor: A, repo: &git2::Repository, commit: &git2::Commit) -> Result<Self> {
+    /// Reads the op committed in `commit`, opening it with `op_keys` if the
+    /// log seals its ops.
+    pub fn from_commit(
+        actor: A,
+        repo: &git2::Repository,
+        commit: &git2::Commit,
+        op_keys: Option<&Session>
+    ) -> Result<Self> {
         let tree = commit.tree()?;
+        let format = match tree.get_name("format") {
+            Some(entry) => {
+                let blob = repo.find_blob(entry.id())?;
+                String::from_utf8_lossy(blob.content()).parse::<u8>()
+                    .map_err(|_| Error::Version(
+                        format!("Unreadable op format in log commit {}", commit.id())
+                    ))?
+            },
+            None => 0
+        };
         let tree_entry = tree.get_name("op")
             .ok_or(Error::LogCommitDoesNotContainOp)?;
         let id = tree_entry.id();
         let blob = repo.find_blob(id)?;
-        let bytes = blob.content();
-        let op = bincode::deserialize(bytes)?;
+        let opened;
+        let bytes = match op_keys {
+            Some(sess) => {
+                let sealed: Encrypted = bincode::deserialize(blob.content())?;
+                let ctx = op_context(&actor.to_string(), commit.parent_ids().next());
+                opened = sealed.decrypt(sess, &ctx)?;
+                &opened.0[..]
+            },
+            None => blob.content()
+        };
+        let op = if format == OP_FORMAT {
+            bincode::deserialize(bytes)?
+        } else if format < OP_FORMAT {
+            C::upgrade_op(format, bytes)?
+        } else {
+            return Err(Error::Version(
+                format!("Unsupported op format in log commit {}: {}", commit.id(), format)
+            ));
+        };
         Ok(Op {
-            actor: actor,
-            oid: commit.id().as_bytes().to_vec(),
-            op: op
+            actor,
+            oid: commit.id(),
+            op
         })
     }
 
-    pub fn next_from_branches(
+    /// The first op committed after `acked` on the way to `unacked`
+    pub fn next_from_oids(
         actor: A,
         repo: &git2::Repository,
-        unacked: Option<git2::Branch>,
-        acked: Option<git2::Branch>
+        unacked: Option<git2::Oid>,
+        acked: Option<git2::Oid>,
+        op_keys: Option<&Session>
     ) -> Result<Option<Op<A, C>>> {
-        match (unacked, acked) {
-            (Some(unacked), Some(acked)) => {
-                let local_unacked_oid = unacked.get().target()
-                    .ok_or(Error::BranchIsNotADirectReference)?;
-                let local_acked_oid = acked.get().target()
-                    .ok_or(Error::BranchIsNotADirectReference)?;
+        let tip = match (unacked, acked) {
+            (Some(unacked), _) => unacked,
+            (None, Some(acked)) => return Err(acked_without_log(acked)),
+            (None, None) => return Ok(None)
+        };
+        if let Some(acked) = acked {
+            if is_behind(repo, tip, acked)? {
+                return Ok(None);
+            }
+        }
 
-                if local_unacked_oid != local_acked_oid {
-                    let mut curr_oid = local_unacked_oid;
-                    let mut commit;
-                    loop {
-                        commit = repo.find_commit(curr_oid)?;
-                        let parents: Vec<git2::Oid> = commit.parent_ids().collect();
-                        assert_eq!(parents.len(), 1);
-                        if parents[0] == local_acked_oid {
-                            break;
-                        }
-                        curr_oid = parents[0];
-                    }
+        let mut commit = repo.find_commit(tip)?;
+        loop {
+            let parent = linear_parent(&commit)?;
+            if parent == acked {
+                break;
+            }
+            match parent {
+                Some(parent) => commit = repo.find_commit(parent)?,
+                None => return Err(ack_not_in_history(acked, tip))
+            }
+        }
 
-                    let op = Op::from_commit(actor, &repo, &commit)?;
-                    Ok(Some(op))
-                } else {
-                    Ok(None)
-                }
-            },
-            (Some(unacked), None) => {
-                let mut curr_oid = unacked.get().target()
-                    .ok_or(Error::BranchIsNotADirectReference)?;
-                let mut commit;
-                loop {
-                    commit = repo.find_commit(curr_oid)?;
-                    let parents: Vec<git2::Oid> = commit.parent_ids().collect();
-                    if parents.len() == 0 {
-                        break;
-                    }
+        let op = Op::from_commit(actor, repo, &commit, op_keys)?;
+        Ok(Some(op))
+    }
 
-                    assert_eq!(parents.len(), 1);
-                    curr_oid = parents[0];
-                }
+    /// Collects the ops committed after `acked` up to and including
+    /// `unacked`, oldest first.
+    pub fn pending_from_oids(
+        actor: A,
+        repo: &git2::Repository,
+        unacked: Option<git2::Oid>,
+        acked: Option<git2::Oid>,
+        op_keys: Option<&Session>
+    ) -> Result<Vec<Op<A, C>>> {
+        let tip = match (unacked, acked) {
+            (Some(unacked), _) => unacked,
+            (None, Some(acked)) => return Err(acked_without_log(acked)),
+            (None, None) => return Ok(Vec::new())
+        };
+        if let Some(acked) = acked {
+            if is_behind(repo, tip, acked)? {
+                return Ok(Vec::new());
+            }
+        }
 
-                let op = Op::from_commit(actor, &repo, &commit)?;
-                Ok(Some(op))
-            },
-            (None, Some(_)) => panic!("we have acked ops that were never unacked!"),
-            _ => Ok(None)
+        let mut commits = Vec::new();
+        let mut curr_oid = Some(tip);
+        while curr_oid != acked {
+            let commit = match curr_oid {
+                Some(oid) => repo.find_commit(oid)?,
+                None => return Err(ack_not_in_history(acked, tip))
+            };
+            curr_oid = linear_parent(&commit)?;
+            commits.push(commit);
         }
+
+        commits.iter()
+            .rev()
+            .map(|commit| Op::from_commit(actor.clone(), repo, commit, op_keys))
+            .collect()
     }
 }
 
+/// The commit before `commit` in a log, None for the first op
+fn linear_parent(commit: &git2::Commit) -> Result<Option<git2::Oid>> {
+    match commit.parent_ids().len() {
+        0 => Ok(None),
+        1 => Ok(Some(commit.parent_id(0)?)),
+        n => Err(Error::NonLinearHistory(
+            format!("Commit {} has {} parents, log commits have at most one", commit.id(), n)))
+    }
+}
+
+fn acked_without_log(acked: git2::Oid) -> Error {
+    Error::RewrittenHistory(format!("Op {} was acked but its log is gone", acked))
+}
+
+fn ack_not_in_history(acked: Option<git2::Oid>, tip: git2::Oid) -> Error {
+    let acked = acked.map(|oid| oid.to_string()).unwrap_or_default();
+    Error::RewrittenHistory(format!("Acked op {} is not in the history of {}", acked, tip))
+}
+
+/// Push errors that fetching first may resolve, the remote moved ahead of
+/// us or holds commits we haven't seen.
+fn is_push_race(err: &git2::Error) -> bool {
+    matches!(err.code(), git2::ErrorCode::NotFastForward | git2::ErrorCode::NotFound)
+}
+
+/// Errors caused by the shape of a branch rather than by us or the repo
+fn is_bad_history(err: &Error) -> bool {
+    matches!(err, Error::NonLinearHistory(_) | Error::RewrittenHistory(_) | Error::LogCommitDoesNotContainOp)
+}
+
+/// Errors telling whose branch it is, the branch is named for an actor
+/// it doesn't hold or its commits don't record one we can read
+fn is_bad_actor(err: &Error) -> bool {
+    match err {
+        Error::Parse(_) | Error::State(_) | Error::Crypto(_) | Error::Version(_) | Error::Bincode(_) => true,
+        _ => is_bad_history(err)
+    }
+}
 
 impl<A, C> LogReplicable<A, C> for Log<A, C> where
     A: Actor + FromStr + ToString + Debug,
-    C: Debug + CmRDT + Eq + Serialize + DeserializeOwned, // TODO: why are serde bounds on `C` needed?
+    C: Debug + CmRDT,
     C::Op : DeserializeOwned + Serialize + Eq
 {
     type Op = Op<A, C>;
     fn next(&self) -> Result<Option<Self::Op>> {
-        let local_name = format!("actor_{}", self.actor.to_string());
-        let local_acked = format!("acked_actor_{}", self.actor.to_string());
+        let op_keys = self.op_keys();
+        let local_name = self.log_branch(&self.actor);
+        let local_acked = self.acked_branch(&self.actor);
 
-        let unacked = self.repo.find_branch(&local_name, git2::BranchType::Local);
-        let acked = self.repo.find_branch(&local_acked, git2::BranchType::Local);
-        if let Some(op) = Op::next_from_branches(
+        let unacked = self.branch_oid(&local_name)?;
+        let acked = self.branch_oid(&local_acked)?;
+        if let Some(op) = Op::next_from_oids(
             self.actor.clone(),
             &self.repo,
-            unacked.ok(),
-            acked.ok()
+            unacked,
+            acked,
+            op_keys.as_ref()
         )? {
             return Ok(Some(op));
         }
 
         // we have no local unacked ops, check for remote ops
-        for branch in self.repo.branches(Some(git2::BranchType::Remote))? {
-            let (remote_branch, _) = branch?;
-
-            println!("branch name: {}", remote_branch.name()
-                     ?.ok_or(Error::BranchNameEncodingError)?);
-
-            let actor = {
-                let branch_name = remote_branch.name()
-                    ?.ok_or(Error::BranchNameEncodingError)?;
-                let split: Vec<&str> = branch_name.split("/actor_").collect();
-                println!("branch_name split: {:?}", split);
-                let actor: A = match split.as_slice() {
-                    [_, s] => s.parse()
-                        .map_err(|_| Error::Parse(
-                            format!("Failed to parse actor from branch: {}", s)))?,
-                    _ => continue
-                };
-                println!("actor {:?}", actor.to_string());
-                actor
+        for (remote_branch, remote_oid) in self.remote_branches()? {
+            trace!("checking remote branch; branch={}", remote_branch);
+
+            let actor = match self.remote_branch_actor(&remote_branch, remote_oid)? {
+                Some(actor) => actor,
+                None => continue
             };
-            
-            let tracking_branch = self.repo
-                .find_branch(&format!("actor_{}", actor.to_string()), git2::BranchType::Local);
 
-            let next_op = Op::next_from_branches(
+            let tracking_oid = self.branch_oid(&self.log_branch(&actor))?;
+
+            let next_op = Op::next_from_oids(
                 actor,
                 &self.repo,
-                Some(remote_branch),
-                tracking_branch.ok()
+                Some(remote_oid),
+                tracking_oid,
+                op_keys.as_ref()
             )?;
 
             if let Some(op) = next_op {
@@ -191,6 +533,52 @@ impl<A, C> LogReplicable<A, C> for Log<A, C> where
         Ok(None)
     }
 
+    fn pending(&self) -> Result<Vec<Self::Op>> {
+        let op_keys = self.op_keys();
+        let local_name = self.log_branch(&self.actor);
+        let local_acked = self.acked_branch(&self.actor);
+
+        let unacked = self.branch_oid(&local_name)?;
+        let acked = self.branch_oid(&local_acked)?;
+        let mut ops = Op::pending_from_oids(
+            self.actor.clone(),
+            &self.repo,
+            unacked,
+            acked,
+            op_keys.as_ref()
+        )?;
+
+        // acking a remote op moves its local tracking branch, we keep track of
+        // where those branches will be once the ops before them are acked.
+        let mut tracking: BTreeMap<String, git2::Oid> = BTreeMap::new();
+        for (remote_branch, remote_oid) in self.remote_branches()? {
+            let actor = match self.remote_branch_actor(&remote_branch, remote_oid)? {
+                Some(actor) => actor,
+                None => continue
+            };
+
+            let tracking_name = self.log_branch(&actor);
+            let tracking_oid = match tracking.get(&tracking_name) {
+                Some(oid) => Some(*oid),
+                None => self.branch_oid(&tracking_name)?
+            };
+
+            let remote_ops = Op::pending_from_oids(
+                actor,
+                &self.repo,
+                Some(remote_oid),
+                tracking_oid,
+                op_keys.as_ref()
+            )?;
+
+            if !remote_ops.is_empty() {
+                tracking.insert(tracking_name, remote_oid);
+            }
+            ops.extend(remote_ops);
+        }
+        Ok(ops)
+    }
+
     fn ack(&mut self, op: &Self::Op) -> Result<()> {
         match self.next()? {
             Some(expected) => {
@@ -205,113 +593,199 @@ impl<A, C> LogReplicable<A, C> for Log<A, C> where
             }
         }
 
-        let branch_name: String = if op.actor == self.actor {
-            format!("acked_actor_{}", op.actor.to_string())
-        } else {
-            format!("actor_{}", op.actor.to_string())
-        };
+        let branch_name = self.acked_branch(&op.actor);
+
+        trace!("ack; actor={:?} branch={} commit={}", op.actor, branch_name, op.id());
+        self.set_branch(&branch_name, op.id())
+    }
+
+    fn cursor(&self, op: &Self::Op) -> Result<(A, Cursor)> {
+        Ok((op.actor.clone(), op.oid.as_bytes().to_vec()))
+    }
+
+    fn cursors(&self) -> Result<BTreeMap<A, Cursor>> {
+        let mut cursors = BTreeMap::new();
+        for (branch_name, oid) in self.local_branches()? {
+            let actor = match self.branch_actor(&branch_name, oid)? {
+                Some(actor) => actor,
+                None => continue
+            };
+
+            if branch_name != self.acked_branch(&actor) {
+                // our own unacked branch
+                continue;
+            }
 
-        let commit = self.repo.find_commit(op.id())?;
-        println!("updating commit on {}, to {:?}", branch_name, commit.id());
-        self.repo.branch(&branch_name, &commit, true)?;
+            cursors.insert(actor, oid.as_bytes().to_vec());
+        }
+        Ok(cursors)
+    }
+
+    fn reset_cursor(&mut self, actor: &A, cursor: Option<&Cursor>) -> Result<()> {
+        let branch_name = self.acked_branch(actor);
+        match cursor {
+            Some(cursor) => {
+                let commit = self.repo.find_commit(git2::Oid::from_bytes(cursor)?)?;
+                self.set_branch(&branch_name, commit.id())?;
+            },
+            None => {
+                if let Ok(mut reference) = self.repo.find_reference(&self.local_ref(&branch_name)) {
+                    reference.delete()?;
+                }
+            }
+        }
         Ok(())
     }
 
     fn commit(&mut self, op: C::Op) -> Result<Self::Op> {
-        let name = format!("actor_{}", self.actor.to_string());
-        let parent = match self.repo.find_branch(&name, git2::BranchType::Local) {
-            Ok(branch) => {
-                let target = branch
-                    .get()
-                    .target()
-                    .ok_or(Error::BranchIsNotADirectReference)?;
-                let commit = self.repo.find_commit(target)?;
-                Some(commit)
-            },
-            _ => None
+        let name = self.log_branch(&self.actor);
+        let parent = match self.branch_oid(&name)? {
+            Some(target) => Some(self.repo.find_commit(target)?),
+            None => None
         };
 
-        let op_bytes = bincode::serialize(&op)?;
+        let op_keys = self.op_keys();
+        let mut op_bytes = bincode::serialize(&op)?;
+        if let Some(ref sess) = op_keys {
+            let ctx = op_context(&self.actor.to_string(), parent.as_ref().map(|c| c.id()));
+            let sealed = Plaintext(op_bytes).encrypt(sess, &ctx)?;
+            op_bytes = bincode::serialize(&sealed)?;
+        }
         let op_oid = self.repo.blob(&op_bytes)?;
         let mut builder = self.repo.treebuilder(None)?;
         builder.insert("op", op_oid, 0o100644)?;
+        let format_oid = self.repo.blob(OP_FORMAT.to_string().as_bytes())?;
+        builder.insert("format", format_oid, 0o100644)?;
+        if let Some(sess) = self.actor_keys() {
+            // the branch name no longer tells others who wrote this op
+            let ctx = actor_context(parent.as_ref().map(|c| c.id()));
+            let sealed = Plaintext(self.actor.to_string().into_bytes()).encrypt(&sess, &ctx)?;
+            let actor_oid = self.repo.blob(&bincode::serialize(&sealed)?)?;
+            builder.insert("actor", actor_oid, 0o100644)?;
+        }
         let tree_oid = builder.write()?;
         let tree = self.repo.find_tree(tree_oid)?;
 
-        let sig = self.repo.signature()?;
+        let sig = self.signature()?;
+        let message = self.message(&op);
 
         let mut parent_commits = Vec::new();
         if let Some(ref commit) = parent {
             parent_commits.push(commit)
         }
 
-        let branch_ref = format!("refs/heads/{}", name);
-        println!("committing to branch ref: {}", branch_ref);
+        let branch_ref = self.local_ref(&name);
+        trace!("commit; actor={:?} ref={}", self.actor, branch_ref);
 
         let commit_oid = self.repo
-            .commit(Some(&branch_ref), &sig, &sig, "db op", &tree, &parent_commits)?;
+            .commit(Some(&branch_ref), &sig, &sig, &message, &tree, &parent_commits)?;
         
         Op::from_commit(
             self.actor.clone(),
             &self.repo,
-            &self.repo.find_commit(commit_oid)?
+            &self.repo.find_commit(commit_oid)?,
+            op_keys.as_ref()
         )
     }
 
-    fn pull(&mut self, other: &Self) -> Result<()> {
-        println!("fetching remote: {}", &other.name);
+    fn rotate_key(&mut self, new_key: &MasterKey) -> Result<()> {
+        let old_key = match self.opts.op_key.take() {
+            Some(old_key) => old_key,
+            // ops are not sealed, and won't start being sealed halfway through the log
+            None => return Ok(())
+        };
+        if &old_key != new_key && !self.opts.retired_op_keys.contains(&old_key) {
+            self.opts.retired_op_keys.push(old_key);
+        }
+        self.opts.op_key = Some(new_key.clone());
+        Ok(())
+    }
 
-        println!("searching for existing remote in repo");
-        let mut git_remote = match self.repo.find_remote(&other.name) {
-            Ok(git_remote) => git_remote,
-            Err(_) => {
-                eprintln!("Failed to find remote '{}', adding remote to git", other.name);
-                // this remote is not added to git yet, we add it
-                self.repo.remote(&other.name, &other.url)?
-            }
+    fn put_clear(&mut self, name: &str, val: Option<Vec<u8>>) -> Result<()> {
+        let branch = self.clear_branch();
+        let parent = match self.branch_oid(&branch)? {
+            Some(oid) => Some(self.repo.find_commit(oid)?),
+            None => None
+        };
+        let base_tree = match parent {
+            Some(ref commit) => Some(commit.tree()?),
+            None => None
         };
 
-        println!("found a remote, starting fetch...");
-        
-        let mut fetch_opt = git2::FetchOptions::new();
-        fetch_opt.remote_callbacks(other.git_callbacks());
-        let refspec_iter = git_remote.fetch_refspecs()?;
-        let refspecs: Vec<&str> = refspec_iter.iter()
-            .map(|r| r.unwrap())
-            .collect();
-        git_remote.fetch(&refspecs, Some(&mut fetch_opt), None)?;
-        println!("finished fetch");
+        let mut builder = self.repo.treebuilder(base_tree.as_ref())?;
+        match val {
+            Some(val) => {
+                let blob_oid = self.repo.blob(&val)?;
+                builder.insert(name, blob_oid, 0o100644)?;
+            },
+            None => {
+                if builder.get(name)?.is_none() {
+                    return Ok(());
+                }
+                builder.remove(name)?;
+            }
+        }
+        let tree = self.repo.find_tree(builder.write()?)?;
+
+        let sig = self.repo.signature()?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let branch_ref = self.local_ref(&branch);
+        self.repo.commit(Some(&branch_ref), &sig, &sig, "db clear records", &tree, &parents)?;
         Ok(())
     }
 
-    fn push(&self, other: &mut Self) -> Result<()> {
-        println!("searching for existing remote in repo");
-        let mut git_remote = match self.repo.find_remote(&other.name) {
-            Ok(git_remote) => git_remote,
-            Err(_) => {
-                eprintln!("Failed to find remote '{}', adding remote to git", other.name);
-                // this remote is not added to git yet, we add it
-                self.repo.remote(&other.name, &other.url)?
+    fn clear_records(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        let own_branch = self.clear_branch();
+        let mut tips = Vec::new();
+        for (branch_name, oid) in self.remote_branches()? {
+            let short_name = match branch_name.find('/') {
+                Some(i) => &branch_name[i + 1..],
+                None => continue
+            };
+            if short_name.starts_with("clear_") && short_name != own_branch {
+                tips.push(oid);
             }
-        };
+        }
+        // our own records go last so they win
+        if let Some(oid) = self.branch_oid(&own_branch)? {
+            tips.push(oid);
+        }
+
+        let mut records = BTreeMap::new();
+        for oid in tips.into_iter() {
+            let tree = self.repo.find_commit(oid)?.tree()?;
+            for entry in tree.iter() {
+                let name = entry.name()
+                    .ok_or(Error::Parse("Clear record name is not utf8".into()))?
+                    .to_string();
+                let blob = self.repo.find_blob(entry.id())?;
+                records.insert(name, blob.content().to_vec());
+            }
+        }
+        Ok(records)
+    }
 
-        let mut push_opt = git2::PushOptions::new();
-        push_opt.remote_callbacks(other.git_callbacks());
+    fn pull(&mut self, other: &Self) -> Result<()> {
+        self.pull_with_progress(other, &mut |_| ())
+    }
 
-        let branches: Vec<String> = self.repo.branches(Some(git2::BranchType::Local))
-            ?.map(|b| b.unwrap())
-            .map(|(branch, _)| branch)
-            .map(|b| {
-                let b = b.name().unwrap().unwrap();
-                format!("refs/heads/{}", b)
-            })
-            .collect();
+    fn push(&self, other: &mut Self) -> Result<()> {
+        self.push_with_progress(other, &mut |_| ())
+    }
 
-        let borrowed: Vec<&str> = branches.iter().map(|s| s.as_ref()).collect();
-        
-        println!("branches to push: {:?}", borrowed);
-        git_remote.push(&borrowed, Some(&mut push_opt))?;
-        eprintln!("Finish push");
+    fn pull_with_progress(&mut self, other: &Self, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        self.fetch(other, progress)?;
+        self.verify_remotes()
+    }
+
+    fn push_with_progress(&self, other: &mut Self, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        let rejected: Vec<PushResult> = self.push_refs(other, progress)?
+            .into_iter()
+            .filter(|res| res.is_rejected())
+            .collect();
+        if !rejected.is_empty() {
+            return Err(Error::PushRejected(rejected));
+        }
         Ok(())
     }
 }
@@ -319,39 +793,964 @@ impl<A, C> LogReplicable<A, C> for Log<A, C> where
 impl<A: Actor, C: Debug + CmRDT> Log<A, C>
     where C::Op : DeserializeOwned + Serialize + Eq
 {
+    /// The branch `actor` commits their ops to
+    fn log_branch(&self, actor: &A) -> String
+        where A: ToString
+    {
+        match self.opts.ref_names {
+            RefNames::Plain => format!("actor_{}", actor.to_string()),
+            RefNames::Hashed(ref key) => format!("log_{}", ref_hash(key, &actor.to_string()))
+        }
+    }
+
+    /// The branch holding the last acked op of `actor`. Our own ops are
+    /// committed to our log branch so acks for them live on a separate
+    /// branch, for everyone else the local copy of their log branch tracks
+    /// what we acked.
+    fn acked_branch(&self, actor: &A) -> String
+        where A: ToString
+    {
+        if actor != &self.actor {
+            return self.log_branch(actor);
+        }
+
+        match self.opts.ref_names {
+            RefNames::Plain => format!("acked_actor_{}", actor.to_string()),
+            RefNames::Hashed(ref key) => format!("acked_{}", ref_hash(key, &actor.to_string()))
+        }
+    }
+
+    /// The actor owning a local branch, None if it's not a log or ack branch
+    fn branch_actor(&self, branch_name: &str, tip: git2::Oid) -> Result<Option<A>>
+        where A: FromStr + ToString
+    {
+        let actor: A = match self.opts.ref_names {
+            RefNames::Plain => {
+                let actor_str = match branch_name.strip_prefix("acked_actor_")
+                    .or_else(|| branch_name.strip_prefix("actor_")) {
+                    Some(actor_str) => actor_str,
+                    None => return Ok(None)
+                };
+
+                actor_str.parse()
+                    .map_err(|_| Error::Parse(
+                        format!("Failed to parse actor from branch: {}", branch_name)))?
+            },
+            RefNames::Hashed(_) => {
+                if !branch_name.starts_with("log_") && !branch_name.starts_with("acked_") {
+                    return Ok(None);
+                }
+                let actor = self.commit_actor(tip)?;
+                if branch_name != self.log_branch(&actor) && branch_name != self.acked_branch(&actor) {
+                    return Err(Error::State(
+                        format!("Branch {} holds ops from another actor", branch_name)));
+                }
+                actor
+            }
+        };
+        Ok(Some(actor))
+    }
+
+    /// The actor owning a remote tracking branch `<remote>/<branch>`
+    fn remote_branch_actor(&self, branch_name: &str, tip: git2::Oid) -> Result<Option<A>>
+        where A: FromStr + ToString
+    {
+        let short_name = match branch_name.find('/') {
+            Some(i) => &branch_name[i + 1..],
+            None => return Ok(None)
+        };
+        if short_name.starts_with("acked_") {
+            return Ok(None);
+        }
+        self.branch_actor(short_name, tip)
+    }
+
+    /// The actor recorded in a commit made with hashed ref names
+    fn commit_actor(&self, oid: git2::Oid) -> Result<A>
+        where A: FromStr
+    {
+        let sess = self.actor_keys()
+            .ok_or(Error::State("Only commits made with hashed ref names record their actor".into()))?;
+        let commit = self.repo.find_commit(oid)?;
+        let tree = commit.tree()?;
+        let entry = tree.get_name("actor")
+            .ok_or(Error::State("Log commit does not record its actor".into()))?;
+        let blob = self.repo.find_blob(entry.id())?;
+        let sealed: Encrypted = bincode::deserialize(blob.content())?;
+        let plain = sealed.decrypt(&sess, &actor_context(commit.parent_ids().next()))?;
+        let actor_str = String::from_utf8(plain.0.clone())
+            .map_err(|_| Error::Parse("Commit actor is not utf8".into()))?;
+        actor_str.parse()
+            .map_err(|_| Error::Parse(format!("Failed to parse commit actor: {}", actor_str)))
+    }
+
+    /// The options the log was opened with, after a `rotate_key` they hold
+    /// the keys to open the log with next time.
+    pub fn options(&self) -> &Options {
+        &self.opts
+    }
+
+    /// The current op key along with the retired ones, None if ops are not
+    /// sealed.
+    fn op_keys(&self) -> Option<Session> {
+        self.opts.op_key.as_ref().map(|key| {
+            Session::new(0, key.clone()).with_old_keys(&self.opts.retired_op_keys)
+        })
+    }
+
+    /// The keys sealing the actor of each commit, None unless ref names are
+    /// hashed.
+    fn actor_keys(&self) -> Option<Session> {
+        match self.opts.ref_names {
+            RefNames::Plain => None,
+            RefNames::Hashed(ref key) => Some(self.op_keys().unwrap_or_else(|| {
+                Session::new(0, MasterKey::from_secret(key, b"hermitdb actor key"))
+            }))
+        }
+    }
+
+    /// Describe ops in commit messages with `summarize`, without it every
+    /// op is of kind "op" and has no key.
+    pub fn with_summary(mut self, summarize: fn(&C::Op) -> OpSummary) -> Self {
+        self.summarize = Some(summarize);
+        self
+    }
+
+    fn signature(&self) -> Result<git2::Signature<'static>>
+        where A: ToString
+    {
+        match self.opts.identity {
+            Identity::Pseudonymous => {
+                let pseudonym = match self.opts.ref_names {
+                    RefNames::Plain => format!("actor-{}", self.actor.to_string()),
+                    RefNames::Hashed(ref key) => ref_hash(key, &self.actor.to_string())
+                };
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map_err(|_| Error::State("System clock is before the unix epoch".into()))?
+                    .as_secs();
+                let time = git2::Time::new(secs as i64, 0);
+                Ok(git2::Signature::new(
+                    &format!("hermitdb {}", pseudonym),
+                    &format!("{}@hermitdb.invalid", pseudonym),
+                    &time
+                )?)
+            },
+            Identity::RepoConfig => Ok(self.repo.signature()?),
+            Identity::Named { ref name, ref email } => Ok(git2::Signature::now(name, email)?)
+        }
+    }
+
+    fn message(&self, op: &C::Op) -> String {
+        let summary = match self.summarize {
+            Some(summarize) => summarize(op),
+            None => OpSummary { kind: "op".into(), key: None }
+        };
+        let key = match summary.key {
+            Some(key) if self.opts.disclose_keys && self.opts.op_key.is_none() => key,
+            _ => String::new()
+        };
+        self.opts.message
+            .replace("{kind}", &summary.kind)
+            .replace("{key}", &key)
+            .trim()
+            .to_string()
+    }
+
+    /// The actor we commit ops as
+    pub fn actor(&self) -> &A {
+        &self.actor
+    }
+
+    /// Where other logs fetch this one from
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Use `opts` for naming and pushing branches, set this before the log
+    /// is first used since existing branches are not renamed or moved to a
+    /// new namespace.
+    pub fn with_options(mut self, opts: Options) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// The branch holding our clear records, see `LogReplicable::put_clear`
+    fn clear_branch(&self) -> String
+        where A: ToString
+    {
+        match self.opts.ref_names {
+            RefNames::Plain => format!("clear_actor_{}", self.actor.to_string()),
+            RefNames::Hashed(ref key) => format!("clear_{}", ref_hash(key, &self.actor.to_string()))
+        }
+    }
+
     pub fn auth(actor: A, repo: git2::Repository, name: String, url: String, user: String, pass: String) -> Self {
         Log {
-            actor: actor,
-            name: name,
-            url: url,
+            actor,
+            name,
+            url,
             auth: Some(Auth { user, pass }),
-            repo: repo,
+            repo,
+            opts: Options::default(),
+            summarize: None,
             phantom_crdt: PhantomData
         }
     }
 
     pub fn no_auth(actor: A, repo: git2::Repository, name: String, url: String) -> Self {
         Log {
-            actor: actor,
-            name: name,
-            url: url,
+            actor,
+            name,
+            url,
             auth: None,
-            repo: repo,
+            repo,
+            opts: Options::default(),
+            summarize: None,
             phantom_crdt: PhantomData
         }
     }
 
-    pub fn git_callbacks(&self) -> git2::RemoteCallbacks {
+    /// Push our branches to `other` and report how each one fared.
+    ///
+    /// Branches the remote refuses are fetched. If the remote turns out to
+    /// be ahead of us there's nothing to push, if we are ahead of the
+    /// remote the push is retried. Branches whose histories diverged, say
+    /// because one actor id was restored on two devices, are left alone
+    /// and reported as rejected.
+    pub fn push_refs(&self, other: &Self, progress: &mut dyn FnMut(Progress)) -> Result<Vec<PushResult>>
+        where A: FromStr + ToString
+    {
+        let branches: Vec<String> = self.shared_branches()?
+            .into_iter()
+            .map(|(branch, _)| branch)
+            .collect();
+        if branches.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let rejected = self.push_branches(other, &branches, progress)?;
+        let mut results: Vec<PushResult> = branches.iter()
+            .filter(|branch| !rejected.contains_key(*branch))
+            .map(|branch| PushResult { branch: branch.clone(), status: PushStatus::Updated })
+            .collect();
+        if rejected.is_empty() {
+            self.track_pushed(other, &results)?;
+            return Ok(results);
+        }
+
+        self.fetch(other, progress)?;
+        let mut retry = Vec::new();
+        for (branch, reason) in rejected.into_iter() {
+            let local_oid = match self.branch_oid(&branch)? {
+                Some(oid) => oid,
+                None => continue
+            };
+            let remote_ref = format!("{}{}/{}", self.opts.namespace.remotes_prefix(), other.name, branch);
+            let remote_oid = match self.repo.find_reference(&remote_ref) {
+                Ok(reference) => reference.target(),
+                Err(_) => None
+            };
+            let remote_oid = match remote_oid {
+                Some(oid) => oid,
+                None => {
+                    retry.push(branch);
+                    continue;
+                }
+            };
+
+            let status = if remote_oid == local_oid
+                || self.repo.graph_descendant_of(remote_oid, local_oid)? {
+                PushStatus::UpToDate
+            } else if self.repo.graph_descendant_of(local_oid, remote_oid)? {
+                retry.push(branch);
+                continue;
+            } else {
+                PushStatus::Rejected(format!("{}, histories diverged", reason))
+            };
+            results.push(PushResult { branch, status });
+        }
+
+        if !retry.is_empty() {
+            let rejected = self.push_branches(other, &retry, progress)?;
+            for branch in retry.into_iter() {
+                let status = match rejected.get(&branch) {
+                    Some(reason) => PushStatus::Rejected(reason.clone()),
+                    None => PushStatus::Updated
+                };
+                results.push(PushResult { branch, status });
+            }
+        }
+        self.track_pushed(other, &results)?;
+        Ok(results)
+    }
+
+    /// git only moves tracking refs covered by the remote's fetch refspecs,
+    /// refs in a db namespace are moved here so the next read doesn't see
+    /// the branches we pushed as lagging behind
+    fn track_pushed(&self, other: &Self, results: &[PushResult]) -> Result<()> {
+        for res in results.iter().filter(|res| res.status == PushStatus::Updated) {
+            if let Some(oid) = self.branch_oid(&res.branch)? {
+                self.set_remote_branch(&other.name, &res.branch, oid)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The branches we share with other logs and their tips. Only log, ack
+    /// and clear record branches are shared, other branches in the repo are
+    /// none of our business.
+    fn shared_branches(&self) -> Result<Vec<(String, git2::Oid)>>
+        where A: FromStr + ToString
+    {
+        let mut branches = Vec::new();
+        if self.opts.push_acks {
+            for (branch_name, oid) in self.local_branches()? {
+                if self.branch_actor(&branch_name, oid)?.is_some() {
+                    branches.push((branch_name, oid));
+                }
+            }
+        } else {
+            let own_branch = self.log_branch(&self.actor);
+            if let Some(oid) = self.branch_oid(&own_branch)? {
+                branches.push((own_branch, oid));
+            }
+        }
+
+        // clear records are only shared by the actor who wrote them
+        let clear_branch = self.clear_branch();
+        if let Some(oid) = self.branch_oid(&clear_branch)? {
+            branches.push((clear_branch, oid));
+        }
+        Ok(branches)
+    }
+
+    /// Writes the branches we would push to a bundle file at `path`, for
+    /// carrying to a repo we can't reach. Commits reachable from `since`
+    /// are left out, pass the receiver's `cursors()` to only bundle what
+    /// it has not acked yet.
+    pub fn export_bundle(&self, path: &Path, since: &BTreeMap<A, Cursor>) -> Result<()>
+        where A: FromStr + ToString
+    {
+        let mut walk = self.repo.revwalk()?;
+        let mut refs = Vec::new();
+        for (branch, oid) in self.shared_branches()? {
+            walk.push(oid)?;
+            refs.push((branch, oid.as_bytes().to_vec()));
+        }
+        for cursor in since.values() {
+            let oid = git2::Oid::from_bytes(cursor)?;
+            // the receiver may be further along some logs than we are
+            if self.repo.find_commit(oid).is_ok() {
+                walk.hide(oid)?;
+            }
+        }
+
+        let mut commits = Vec::new();
+        for oid in walk {
+            commits.push(oid?);
+        }
+        let bundled: BTreeSet<git2::Oid> = commits.iter().cloned().collect();
+
+        let mut prerequisites = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        let mut oids = Vec::new();
+        for oid in commits.iter() {
+            let commit = self.repo.find_commit(*oid)?;
+            for parent in commit.parent_ids() {
+                if !bundled.contains(&parent) {
+                    prerequisites.insert(parent);
+                }
+            }
+            self.tree_objects(commit.tree_id(), &mut seen, &mut oids)?;
+            oids.push(*oid);
+        }
+
+        let bundle = Bundle {
+            refs,
+            prerequisites: prerequisites.iter().map(|oid| oid.as_bytes().to_vec()).collect(),
+            objects: self.bundle_objects(&oids)?,
+            snapshot: None
+        };
+        self.write_bundle(path, &bundle)?;
+        info!(
+            "bundle exported; path={} refs={} commits={}",
+            path.display(), bundle.refs.len(), commits.len()
+        );
+        Ok(())
+    }
+
+    /// Writes a bundle for a new device to join from, so it doesn't have
+    /// to fetch the whole history. `state` is what applying the ops up to
+    /// `cursors` built, usually our state and the cursors it was built
+    /// up to. Every commit of
+    /// the shared branches is bundled, but the trees and blobs holding the
+    /// ops are only bundled for the commits after `cursors`, the cursor
+    /// commits included. The state is sealed with the op key when ops are.
+    ///
+    /// Later fetches offer the commits we skipped the ops of as commits we
+    /// have, so the remote only sends what comes after them. A remote
+    /// sending thin packs may expect us to hold the trees of those commits,
+    /// the libgit2 we bind to never sends thin packs.
+    pub fn export_snapshot(&self, path: &Path, state: &[u8], cursors: &BTreeMap<A, Cursor>) -> Result<()>
+        where A: FromStr + ToString
+    {
+        let mut skeleton = self.repo.revwalk()?;
+        let mut walk = self.repo.revwalk()?;
+        let mut refs = Vec::new();
+        for (branch, oid) in self.shared_branches()? {
+            skeleton.push(oid)?;
+            walk.push(oid)?;
+            refs.push((branch, oid.as_bytes().to_vec()));
+        }
+
+        let mut full = BTreeSet::new();
+        let mut snapshot_cursors = Vec::with_capacity(cursors.len());
+        for (actor, cursor) in cursors.iter() {
+            let oid = self.repo.find_commit(git2::Oid::from_bytes(cursor)?)?.id();
+            // logs we acked but don't push are still walked by the joiner
+            skeleton.push(oid)?;
+            walk.hide(oid)?;
+            full.insert(oid);
+            snapshot_cursors.push((actor.to_string(), cursor.clone()));
+        }
+        for oid in walk {
+            full.insert(oid?);
+        }
+
+        let mut seen = BTreeSet::new();
+        let mut oids = Vec::new();
+        let mut commit_count = 0;
+        for oid in skeleton {
+            let oid = oid?;
+            if full.contains(&oid) {
+                let commit = self.repo.find_commit(oid)?;
+                self.tree_objects(commit.tree_id(), &mut seen, &mut oids)?;
+            }
+            oids.push(oid);
+            commit_count += 1;
+        }
+
+        let state = match self.op_keys() {
+            Some(sess) => {
+                let ctx = snapshot_context(&snapshot_cursors)?;
+                bincode::serialize(&Plaintext(state.to_vec()).encrypt(&sess, &ctx)?)?
+            },
+            None => state.to_vec()
+        };
+
+        let bundle = Bundle {
+            refs,
+            prerequisites: Vec::new(),
+            objects: self.bundle_objects(&oids)?,
+            snapshot: Some(Snapshot {
+                cursors: snapshot_cursors,
+                state
+            })
+        };
+        self.write_bundle(path, &bundle)?;
+        info!(
+            "snapshot exported; path={} refs={} commits={} with_ops={}",
+            path.display(), bundle.refs.len(), commit_count, full.len()
+        );
+        Ok(())
+    }
+
+    /// The trees and blobs under `tree_id` not in `seen` yet, children first
+    fn tree_objects(
+        &self,
+        tree_id: git2::Oid,
+        seen: &mut BTreeSet<git2::Oid>,
+        oids: &mut Vec<git2::Oid>
+    ) -> Result<()> {
+        if !seen.insert(tree_id) {
+            return Ok(());
+        }
+        let tree = self.repo.find_tree(tree_id)?;
+        for entry in tree.iter() {
+            match entry.kind() {
+                Some(git2::ObjectType::Tree) => self.tree_objects(entry.id(), seen, oids)?,
+                _ => {
+                    if seen.insert(entry.id()) {
+                        oids.push(entry.id());
+                    }
+                }
+            }
+        }
+        oids.push(tree_id);
+        Ok(())
+    }
+
+    /// Reads `oids` out of the repo, tagged with their kind
+    fn bundle_objects(&self, oids: &[git2::Oid]) -> Result<Vec<(u8, Vec<u8>)>> {
+        let odb = self.repo.odb()?;
+        let mut objects = Vec::with_capacity(oids.len());
+        for oid in oids.iter() {
+            let object = odb.read(*oid)?;
+            let kind = match object.kind() {
+                git2::ObjectType::Commit => 1,
+                git2::ObjectType::Tree => 2,
+                git2::ObjectType::Blob => 3,
+                kind => return Err(Error::State(format!("Can't bundle {} objects", kind)))
+            };
+            objects.push((kind, object.data().to_vec()));
+        }
+        Ok(objects)
+    }
+
+    fn write_bundle(&self, path: &Path, bundle: &Bundle) -> Result<()> {
+        let bytes = bincode::serialize(&bundle)?;
+        if bytes.len() as u64 > self.opts.max_bundle_size {
+            return Err(Error::State(
+                format!("Bundle is larger than {} bytes", self.opts.max_bundle_size)));
+        }
+
+        let mut f = fs::File::create(path)?;
+        f.write_all(BUNDLE_MAGIC)?;
+        f.write_all(&[BUNDLE_VERSION])?;
+        let mut encoder = DeflateEncoder::new(f, Compression::default());
+        encoder.write_all(&bytes)?;
+        encoder.finish()?.sync_all()?;
+        Ok(())
+    }
+
+    /// Reads a bundle written by `export_bundle`. Its branches become the
+    /// branches of `remote`, as if we had fetched from it, so the ops in
+    /// them go through the same checks before they're applied. Name the
+    /// remote after where the bundle came from, the bundle replaces any
+    /// branch of `remote` it carries.
+    pub fn import_bundle(&mut self, path: &Path, remote: &str) -> Result<()>
+        where A: FromStr + ToString
+    {
+        let bundle = self.read_bundle(path, remote)?;
+        if bundle.snapshot.is_some() {
+            return Err(Error::State("Bundle is a snapshot, join from it with import_snapshot".into()));
+        }
+        debug!("bundle import started; path={} remote={}", path.display(), remote);
+
+        self.write_bundle_objects(&bundle)?;
+        let remote_refs = self.bundle_remote_refs(&bundle, remote)?;
+        for (remote_ref, oid) in remote_refs.into_iter() {
+            self.repo.reference(&remote_ref, oid, true, "hermitdb: import bundle")?;
+        }
+        info!("bundle imported; path={} refs={}", path.display(), bundle.refs.len());
+        self.verify_remotes()
+    }
+
+    /// Reads a snapshot written by `export_snapshot` and returns its state
+    /// and cursors. Its branches become the branches of `remote` as with
+    /// `import_bundle`, but nothing is acked: the caller takes the state
+    /// in, then moves our cursors to the snapshot's with `reset_cursor`.
+    /// Until then the ops before the cursors can't be read.
+    ///
+    /// Only actors we hold nothing of are taken from a snapshot, Err(State)
+    /// if it has a cursor for us or for an actor whose log we track.
+    pub fn import_snapshot(&mut self, path: &Path, remote: &str) -> Result<(Vec<u8>, BTreeMap<A, Cursor>)>
+        where A: FromStr + ToString
+    {
+        let bundle = self.read_bundle(path, remote)?;
+        let snapshot = match bundle.snapshot {
+            Some(ref snapshot) => snapshot,
+            None => return Err(Error::State("Bundle is not a snapshot".into()))
+        };
+        debug!("snapshot import started; path={} remote={}", path.display(), remote);
+
+        let mut cursors = BTreeMap::new();
+        for (actor_str, cursor) in snapshot.cursors.iter() {
+            let actor: A = actor_str.parse()
+                .map_err(|_| Error::Parse(format!("Failed to parse snapshot actor: {}", actor_str)))?;
+            if actor == self.actor {
+                return Err(Error::State("Snapshot already holds our own ops".into()));
+            }
+            if self.branch_oid(&self.log_branch(&actor))?.is_some() {
+                return Err(Error::State(
+                    format!("Snapshot holds ops of actor {} we already track", actor_str)));
+            }
+            git2::Oid::from_bytes(cursor)?;
+            cursors.insert(actor, cursor.clone());
+        }
+
+        let state = match self.op_keys() {
+            Some(sess) => {
+                let sealed: Encrypted = bincode::deserialize(&snapshot.state)?;
+                sealed.decrypt(&sess, &snapshot_context(&snapshot.cursors)?)?.0
+            },
+            None => snapshot.state.clone()
+        };
+
+        self.write_bundle_objects(&bundle)?;
+        for cursor in cursors.values() {
+            let commit = self.repo.find_commit(git2::Oid::from_bytes(cursor)?)
+                .map_err(|_| Error::Corrupt("Snapshot is missing a cursor commit".into()))?;
+            if self.repo.find_tree(commit.tree_id()).is_err() {
+                return Err(Error::Corrupt(format!("Snapshot is missing the op of {}", commit.id())));
+            }
+        }
+        let remote_refs = self.bundle_remote_refs(&bundle, remote)?;
+        for (remote_ref, oid) in remote_refs.into_iter() {
+            self.repo.reference(&remote_ref, oid, true, "hermitdb: import snapshot")?;
+        }
+        info!(
+            "snapshot imported; path={} refs={} cursors={}",
+            path.display(), bundle.refs.len(), cursors.len()
+        );
+        Ok((state, cursors))
+    }
+
+    fn read_bundle(&self, path: &Path, remote: &str) -> Result<Bundle> {
+        let mut f = fs::File::open(path)?;
+        let mut header = vec![0u8; BUNDLE_MAGIC.len() + 1];
+        f.read_exact(&mut header)
+            .map_err(|_| Error::Parse("File is too short to be a bundle".into()))?;
+        if &header[..BUNDLE_MAGIC.len()] != BUNDLE_MAGIC {
+            return Err(Error::Parse("File is not a hermitdb bundle".into()));
+        }
+        let version = header[BUNDLE_MAGIC.len()];
+        if version != BUNDLE_VERSION {
+            return Err(Error::Version(format!("Unsupported bundle version: {}", version)));
+        }
+
+        if remote.is_empty() || remote.contains('/') {
+            return Err(Error::State(format!("Invalid bundle remote name: {}", remote)));
+        }
+
+        let mut bytes = Vec::new();
+        DeflateDecoder::new(f)
+            .take(self.opts.max_bundle_size + 1)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() as u64 > self.opts.max_bundle_size {
+            return Err(Error::Corrupt(
+                format!("Bundle inflates past {} bytes", self.opts.max_bundle_size)));
+        }
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    /// Adds the bundle's objects to the repo, Err(State) if the commits it
+    /// builds on are not in the repo
+    fn write_bundle_objects(&self, bundle: &Bundle) -> Result<()> {
+        for prerequisite in bundle.prerequisites.iter() {
+            let oid = git2::Oid::from_bytes(prerequisite)?;
+            if self.repo.find_commit(oid).is_err() {
+                return Err(Error::State(
+                    format!("Bundle builds on commit {} which is not in the repo", oid)));
+            }
+        }
+
+        let odb = self.repo.odb()?;
+        for &(kind, ref data) in bundle.objects.iter() {
+            let kind = match kind {
+                1 => git2::ObjectType::Commit,
+                2 => git2::ObjectType::Tree,
+                3 => git2::ObjectType::Blob,
+                _ => return Err(Error::Corrupt(format!("Unknown bundle object kind: {}", kind)))
+            };
+            odb.write(kind, data)?;
+        }
+        Ok(())
+    }
+
+    /// The refs the bundle's branches go to under `remote` and their tips,
+    /// once the bundle's objects are in
+    fn bundle_remote_refs(&self, bundle: &Bundle, remote: &str) -> Result<Vec<(String, git2::Oid)>> {
+        let mut remote_refs = Vec::with_capacity(bundle.refs.len());
+        for (branch, tip) in bundle.refs.iter() {
+            let remote_ref = format!(
+                "{}{}/{}", self.opts.namespace.remotes_prefix(), remote, branch
+            );
+            if branch.contains('/') || !git2::Reference::is_valid_name(&remote_ref) {
+                return Err(Error::Corrupt(format!("Bundle has an invalid branch: {}", branch)));
+            }
+            // objects are stored by hash, a tampered commit won't be found
+            let oid = git2::Oid::from_bytes(tip)?;
+            if self.repo.find_commit(oid).is_err() {
+                return Err(Error::Corrupt(format!("Bundle is missing the tip of {}", branch)));
+            }
+            remote_refs.push((remote_ref, oid));
+        }
+        Ok(remote_refs)
+    }
+
+    /// Push `branches` to `other`, returns the branches the remote refused
+    /// and why.
+    fn push_branches(
+        &self,
+        other: &Self,
+        branches: &[String],
+        progress: &mut dyn FnMut(Progress)
+    ) -> Result<BTreeMap<String, String>> {
+        let mut git_remote = self.git_remote(other)?;
+        let refspecs: Vec<String> = branches.iter()
+            .map(|branch| self.opts.namespace.push_refspec(branch))
+            .collect();
+        let borrowed: Vec<&str> = refspecs.iter().map(|s| s.as_ref()).collect();
+        debug!("push started; remote={} refs={}", other.name, borrowed.len());
+
+        let local_prefix = self.opts.namespace.local_prefix();
+        let rejected: RefCell<BTreeMap<String, String>> = RefCell::new(BTreeMap::new());
+        let res = {
+            let mut cbs = other.git_callbacks();
+            let total_refs = refspecs.len();
+            let mut pushed_refs = 0;
+            cbs.push_update_reference(|refname, status| {
+                if let Some(reason) = status {
+                    let branch = refname.strip_prefix(local_prefix.as_str()).unwrap_or(refname);
+                    rejected.borrow_mut().insert(branch.to_string(), reason.to_string());
+                }
+                pushed_refs += 1;
+                progress(Progress::Push { pushed_refs, total_refs });
+                Ok(())
+            });
+            let mut push_opt = git2::PushOptions::new();
+            push_opt.remote_callbacks(cbs);
+            git_remote.push(&borrowed, Some(&mut push_opt))
+        };
+
+        match res {
+            Ok(()) => (),
+            Err(ref err) if is_push_race(err) => {
+                // libgit2 refuses the whole push when one ref can't be
+                // fast-forwarded, without saying which
+                return Ok(branches.iter()
+                    .map(|branch| (branch.clone(), err.message().to_string()))
+                    .collect());
+            },
+            Err(err) => return Err(err.into())
+        }
+        let rejected = rejected.into_inner();
+        debug!("push finished; remote={} rejected={}", other.name, rejected.len());
+        Ok(rejected)
+    }
+
+    /// Fetch `other`'s branches into our remote tracking refs.
+    ///
+    /// Fetches are incremental, our local and remote tracking branches are
+    /// offered to `other` as commits we have, so only commits after the
+    /// ones we fetched or acked before are transferred. Depth limited
+    /// (shallow) fetches are not possible with the libgit2 we bind to, a
+    /// new device joins from a snapshot instead, see `export_snapshot`.
+    fn fetch(&self, other: &Self, progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        debug!("fetch started; remote={}", other.name);
+        let mut git_remote = self.git_remote(other)?;
+
+        let mut cbs = other.git_callbacks();
+        cbs.transfer_progress(|stats| {
+            progress(Progress::Fetch {
+                received_objects: stats.received_objects(),
+                indexed_objects: stats.indexed_objects(),
+                total_objects: stats.total_objects(),
+                received_bytes: stats.received_bytes()
+            });
+            true
+        });
+        let mut fetch_opt = git2::FetchOptions::new();
+        fetch_opt.remote_callbacks(cbs);
+        let refspec = self.opts.namespace.fetch_refspec(&other.name);
+        git_remote.fetch(&[refspec.as_str()], Some(&mut fetch_opt), None)?;
+        debug!("fetch finished; remote={}", other.name);
+        Ok(())
+    }
+
+    /// `pull` without verifying the fetched branches, for reading the clear
+    /// records of a log we don't hold the op key of yet. `pull` once the
+    /// key is known, until then `next` and `pending` may fail on them.
+    pub fn fetch_unverified(&self, other: &Self) -> Result<()> {
+        self.fetch(other, &mut |_| ())
+    }
+
+    fn git_remote(&self, other: &Self) -> Result<git2::Remote<'_>> {
+        match self.repo.find_remote(&other.name) {
+            Ok(ref git_remote) if git_remote.url() != Some(other.url.as_str()) => {
+                info!("updating git remote url; remote={}", other.name);
+                self.repo.remote_set_url(&other.name, &other.url)?;
+                Ok(self.repo.find_remote(&other.name)?)
+            },
+            Ok(git_remote) => Ok(git_remote),
+            Err(_) => {
+                info!("adding git remote; remote={}", other.name);
+                // this remote is not added to git yet, we add it
+                Ok(self.repo.remote(&other.name, &other.url)?)
+            }
+        }
+    }
+
+    /// Reads the ops of every fetched branch we haven't acked yet and, when
+    /// `Options::quarantine` is on, moves the branches whose history is bad
+    /// aside, see `quarantined`. `pull` calls this once the branches are in,
+    /// `next` and `pending` only read refs and fail on a bad branch that was
+    /// not moved aside.
+    pub fn verify_remotes(&self) -> Result<()>
+        where A: FromStr + ToString
+    {
+        if !self.opts.quarantine {
+            return Ok(());
+        }
+
+        let op_keys = self.op_keys();
+        for (remote_branch, remote_oid) in self.remote_branches()? {
+            let actor = match self.remote_branch_actor(&remote_branch, remote_oid) {
+                Ok(Some(actor)) => actor,
+                Ok(None) => continue,
+                Err(ref err) if is_bad_actor(err) => {
+                    warn!("quarantining branch; branch={} error={}", remote_branch, err);
+                    self.quarantine(&remote_branch, remote_oid)?;
+                    continue;
+                },
+                // anything else is reported by `next` and `pending`
+                Err(_) => continue
+            };
+
+            // our own branch lags behind until the remote has what we
+            // pushed, reading it turns up nothing
+            let tracking_oid = self.branch_oid(&self.log_branch(&actor))?;
+            let res = Op::<A, C>::pending_from_oids(
+                actor,
+                &self.repo,
+                Some(remote_oid),
+                tracking_oid,
+                op_keys.as_ref()
+            );
+            if let Err(ref err) = res {
+                if is_bad_history(err) {
+                    warn!("quarantining branch; branch={} error={}", remote_branch, err);
+                    self.quarantine(&remote_branch, remote_oid)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets `remote_branch` aside at `tip`, the fetched ref is not read
+    /// again until the next fetch.
+    fn quarantine(&self, remote_branch: &str, tip: git2::Oid) -> Result<()> {
+        let quarantine_ref = format!("{}{}", self.opts.namespace.quarantine_prefix(), remote_branch);
+        self.repo.reference(&quarantine_ref, tip, true, "hermitdb: quarantine")?;
+
+        let remote_ref = format!("{}{}", self.opts.namespace.remotes_prefix(), remote_branch);
+        if let Ok(mut reference) = self.repo.find_reference(&remote_ref) {
+            reference.delete()?;
+        }
+        Ok(())
+    }
+
+    /// Remote branches set aside by `Options::quarantine`, as `<remote>/<branch>`
+    /// and the tip they had. They stay in the repo for inspection.
+    pub fn quarantined(&self) -> Result<Vec<(String, git2::Oid)>> {
+        self.refs_under(&self.opts.namespace.quarantine_prefix())
+    }
+
+    /// Forget a quarantined branch, once its remote is repaired the branch
+    /// is read again on the next fetch.
+    pub fn release(&self, remote_branch: &str) -> Result<()> {
+        let quarantine_ref = format!("{}{}", self.opts.namespace.quarantine_prefix(), remote_branch);
+        self.repo.find_reference(&quarantine_ref)?.delete()?;
+        Ok(())
+    }
+
+    /// The full ref name of one of our local branches
+    fn local_ref(&self, name: &str) -> String {
+        format!("{}{}", self.opts.namespace.local_prefix(), name)
+    }
+
+    fn branch_oid(&self, name: &str) -> Result<Option<git2::Oid>> {
+        match self.repo.find_reference(&self.local_ref(name)) {
+            Ok(reference) => {
+                let oid = reference.target()
+                    .ok_or(Error::BranchIsNotADirectReference)?;
+                Ok(Some(oid))
+            },
+            Err(_) => Ok(None)
+        }
+    }
+
+    fn set_branch(&self, name: &str, oid: git2::Oid) -> Result<()> {
+        self.repo.reference(&self.local_ref(name), oid, true, "hermitdb: move branch")?;
+        Ok(())
+    }
+
+    /// Move our view of `remote`'s copy of branch `name` to `oid`
+    fn set_remote_branch(&self, remote: &str, name: &str, oid: git2::Oid) -> Result<()> {
+        let remote_ref = format!("{}{}/{}", self.opts.namespace.remotes_prefix(), remote, name);
+        self.repo.reference(&remote_ref, oid, true, "hermitdb: pushed branch")?;
+        Ok(())
+    }
+
+    /// Branches under the local prefix of our namespace and their tips
+    fn local_branches(&self) -> Result<Vec<(String, git2::Oid)>> {
+        self.refs_under(&self.opts.namespace.local_prefix())
+    }
+
+    /// Fetched branches as `<remote>/<branch>` and their tips
+    fn remote_branches(&self) -> Result<Vec<(String, git2::Oid)>> {
+        self.refs_under(&self.opts.namespace.remotes_prefix())
+    }
+
+    /// Direct refs starting with `prefix`, named relative to it. Symbolic
+    /// refs like `refs/remotes/origin/HEAD` never hold ops and are skipped.
+    fn refs_under(&self, prefix: &str) -> Result<Vec<(String, git2::Oid)>> {
+        let mut refs = Vec::new();
+        for reference in self.repo.references()? {
+            let reference = reference?;
+            let name = reference.name()
+                .ok_or(Error::BranchNameEncodingError)?;
+            if !name.starts_with(prefix) {
+                continue;
+            }
+            if reference.kind() == Some(git2::ReferenceType::Symbolic) {
+                continue;
+            }
+            let oid = reference.target()
+                .ok_or(Error::BranchIsNotADirectReference)?;
+            refs.push((name[prefix.len()..].to_string(), oid));
+        }
+        Ok(refs)
+    }
+
+    pub fn git_callbacks(&self) -> git2::RemoteCallbacks<'_> {
         let mut cbs = git2::RemoteCallbacks::new();
         cbs.credentials(move |_, _, _| {
             match self.auth {
                 Some(Auth {ref user, ref pass} ) =>
                     git2::Cred::userpass_plaintext(user, pass),
-                None => {
-                    panic!("This should never be called!");
-                }
+                None => Err(git2::Error::from_str(
+                    "The remote asked for credentials but none were configured"))
             }
         });
         cbs
     }
 }
+
+/// Sealed ops are bound to their actor and the commit they follow
+fn op_context(actor: &str, parent: Option<git2::Oid>) -> Context {
+    let position = parent
+        .map(|oid| oid.as_bytes().to_vec())
+        .unwrap_or_default();
+    Context::named(OP_SCHEMA, b"op")
+        .with_actor(actor.as_bytes())
+        .at(&position)
+}
+
+/// The actor of a commit is bound to its position the same way as its op
+fn actor_context(parent: Option<git2::Oid>) -> Context {
+    let position = parent
+        .map(|oid| oid.as_bytes().to_vec())
+        .unwrap_or_default();
+    Context::named(OP_SCHEMA, b"actor").at(&position)
+}
+
+/// A snapshot's state is bound to the cursors it was built up to
+fn snapshot_context(cursors: &[(String, Vec<u8>)]) -> Result<Context> {
+    Ok(Context::named(OP_SCHEMA, b"snapshot").at(&bincode::serialize(&cursors)?))
+}
+
+/// Keyed hash naming an actor's branches, truncated to 128 bits
+fn ref_hash(key: &[u8], actor: &str) -> String {
+    let signing_key = hmac::SigningKey::new(&digest::SHA256, key);
+    let tag = hmac::sign(&signing_key, actor.as_bytes());
+    encoding::encode(&tag.as_ref()[..128 / 8])
+}
+
+/// True if `tip` holds nothing past `acked`, i.e. it is `acked` or one of
+/// its ancestors. A fetched branch lags behind our tracking branch until
+/// the remote catches up with what we pushed.
+fn is_behind(repo: &git2::Repository, tip: git2::Oid, acked: git2::Oid) -> Result<bool> {
+    Ok(tip == acked || repo.graph_descendant_of(acked, tip)?)
+}
@@ -1,5 +1,3 @@
-#![feature(box_patterns)]
-
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
@@ -10,6 +8,10 @@ pub extern crate sled;
 
 extern crate bincode;
 
+// renamed since our own `log` module holds the replicated log
+#[macro_use]
+extern crate log as logging;
+
 #[cfg(test)]
 #[macro_use]
 extern crate assert_matches;
@@ -21,6 +23,7 @@ extern crate quickcheck;
 pub mod git_helper;
 pub mod error;
 pub mod crypto;
+pub mod shamir;
 pub mod encoding;
 pub mod remote;
 pub mod db;
@@ -28,6 +31,7 @@ pub mod db;
 pub mod log;
 pub mod memory_log;
 pub mod git_log;
+pub mod store;
 pub mod map;
 pub mod data;
 
@@ -35,5 +39,6 @@ pub use error::Error;
 pub use db::DB;
 pub use crypto::{Session, Plaintext, Encrypted};
 pub use remote::Remote;
+pub use store::KvStore;
 // pub use dao::Dao;
-pub use log::{LogReplicable, TaggedOp};
+pub use log::{LogReplicable, TaggedOp, CmRDT, Progress};
@@ -1,9 +1,74 @@
 extern crate crdts;
 
 use std::fmt::Debug;
+use std::collections::BTreeMap;
 
-use crdts::{CmRDT, Actor};
-use error::Result;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crdts::vclock;
+use error::{Error, Result};
+use crypto::MasterKey;
+
+/// Actor Trait alias, actors are written out along with the ops they make.
+pub trait Actor: vclock::Actor + Serialize + DeserializeOwned {}
+impl<T: vclock::Actor + Serialize + DeserializeOwned> Actor for T {}
+
+/// The CRDT that ops pulled out of a log are applied to.
+///
+/// Unlike `crdts::CmRDT`, applying an op can fail (e.g. when the CRDT is
+/// kept in storage). Any `crdts::CmRDT` whose ops can be written to a log
+/// is also one of these.
+pub trait CmRDT {
+    type Op: Debug + Clone + Serialize + DeserializeOwned;
+
+    fn apply(&mut self, op: &Self::Op) -> Result<()>;
+
+    /// Decodes an op that a log recorded in an older `format` than it
+    /// writes today. CRDTs whose ops never changed shape have nothing to
+    /// upgrade from.
+    fn upgrade_op(format: u8, _bytes: &[u8]) -> Result<Self::Op> {
+        Err(Error::Version(format!("Unsupported op format: {}", format)))
+    }
+}
+
+impl<T: crdts::CmRDT> CmRDT for T
+    where T::Op: Clone + Serialize + DeserializeOwned
+{
+    type Op = T::Op;
+
+    fn apply(&mut self, op: &Self::Op) -> Result<()> {
+        crdts::CmRDT::apply(self, op.clone());
+        Ok(())
+    }
+}
+
+/// The position of the last acked op in an actor's log. Cursors are opaque
+/// to everyone but the log that produced them, they exist so that the ack
+/// positions can be persisted alongside state built from the log.
+pub type Cursor = Vec<u8>;
+
+/// Progress of a sync, see `DB::sync_with_progress`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Objects are being received from a remote
+    Fetch {
+        received_objects: usize,
+        indexed_objects: usize,
+        total_objects: usize,
+        received_bytes: usize
+    },
+    /// Refs are being updated on a remote. git2 doesn't report the objects
+    /// sent during a push, only each ref the remote took or refused.
+    Push {
+        pushed_refs: usize,
+        total_refs: usize
+    },
+    /// Ops that were fetched and are about to be applied
+    Apply {
+        ops_pending: usize
+    }
+}
 
 pub trait TaggedOp<C: CmRDT> {
     type ID: Eq;
@@ -16,8 +81,44 @@ pub trait LogReplicable<A: Actor, C: CmRDT> {
     type Op: Debug + TaggedOp<C>;
 
     fn next(&self) -> Result<Option<Self::Op>>;
+    /// All ops that successive `next` and `ack` calls would yield, in order.
+    /// Nothing is acked.
+    fn pending(&self) -> Result<Vec<Self::Op>>;
     fn ack(&mut self, op: &Self::Op) -> Result<()>;
+    /// The actor who committed `op` and the cursor that acking it would leave.
+    fn cursor(&self, op: &Self::Op) -> Result<(A, Cursor)>;
+    /// The cursor of every actor that has acked ops
+    fn cursors(&self) -> Result<BTreeMap<A, Cursor>>;
+    /// Move an actor's ack position to `cursor`, forwards or backwards.
+    /// With `None`, none of the actor's ops are acked.
+    fn reset_cursor(&mut self, actor: &A, cursor: Option<&Cursor>) -> Result<()>;
     fn commit(&mut self, op: C::Op) -> Result<Self::Op>;
     fn pull(&mut self, other: &Self) -> Result<()>;
     fn push(&self, other: &mut Self) -> Result<()>;
+
+    /// Records replicated along with the ops but never sealed, so they can
+    /// be read before the DB is unlocked. Each actor keeps their own set,
+    /// `None` removes a record. Only keep material that is already sealed
+    /// here, wrapped keys for instance.
+    fn put_clear(&mut self, name: &str, val: Option<Vec<u8>>) -> Result<()>;
+    /// The clear records of every actor we have synced with, when actors
+    /// disagree on a record ours wins.
+    fn clear_records(&self) -> Result<BTreeMap<String, Vec<u8>>>;
+
+    /// Seal ops committed from now on with `new_key`, ops sealed before
+    /// stay readable. Logs that don't seal their ops have nothing to do.
+    fn rotate_key(&mut self, _new_key: &MasterKey) -> Result<()> {
+        Ok(())
+    }
+
+    /// `pull` reporting transfer progress, logs that don't transfer
+    /// anything report nothing.
+    fn pull_with_progress(&mut self, other: &Self, _progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        self.pull(other)
+    }
+
+    /// `push` reporting transfer progress, see `pull_with_progress`
+    fn push_with_progress(&self, other: &mut Self, _progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        self.push(other)
+    }
 }
@@ -1,15 +1,17 @@
 use std::marker::PhantomData;
 use std::fmt::Debug;
+use std::collections::{BTreeMap, BTreeSet};
+use std::slice;
 
 use bincode;
 use sled;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 
-use error::{self, Error, Result};
-use crdts::traits::{Causal, CvRDT, CmRDT};
-use crdts::vclock::{VClock, Actor};
-use crdts;
+use error::{Error, Result};
+use store::{KvStore, Scan, Write};
+use crdts::{Causal, CvRDT, VClock};
+use log::{Actor, CmRDT};
 
 /// Key Trait alias to reduce redundancy in type decl.
 pub trait Key: Debug + Ord + Clone + Send + Serialize + DeserializeOwned {}
@@ -28,17 +30,17 @@ impl<A, T> Val<A> for T where
 {}
 
 #[derive(Debug)]
-pub struct Map<K: Key, V: Val<A>, A: Actor> {
+pub struct Map<K: Key, V: Val<A>, A: Actor, S: KvStore = sled::Tree> {
     // This clock stores the current version of the Map, it should
     // be greator or equal to all Entry clock's in the Map.
-    tree: sled::Tree,
+    store: S,
     phantom_key: PhantomData<K>,
     phantom_val: PhantomData<V>,
     phantom_actor: PhantomData<A>
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(bound(deserialize = ""))]
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Entry<V: Val<A>, A: Actor> {
     // The entry clock tells us which actors have last changed this entry.
     // This clock will tell us what to do with this entry in the case of a merge
@@ -56,12 +58,30 @@ pub struct Entry<V: Val<A>, A: Actor> {
     // operation so we keep the key.
     clock: VClock<A>,
 
+    // The dots of the updates to this entry that have not been superseded by
+    // a later update or a remove. More than one dot means the entry was
+    // updated concurrently.
+    writers: BTreeSet<(A, u64)>,
+
     // The nested CRDT
     val: V
 }
 
+/// Causal metadata of an entry, see `Map::get_with_meta`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta<A: Actor> {
+    /// Which actors have changed this entry, and how many times
+    pub clock: VClock<A>,
+    /// The actors whose updates are reflected in the current value, when
+    /// there is more than one, the entry has seen concurrent edits.
+    pub writers: Vec<A>,
+    /// The writer of the latest update, concurrent updates are ordered
+    /// by dot to give every replica the same answer.
+    pub last_writer: Option<A>
+}
+
 pub struct Iter<'a, K: Key, V: Val<A>, A: Actor> {
-    iter: sled::Iter<'a>,
+    iter: Scan<'a>,
     phantom_key: PhantomData<K>,
     phantom_val: PhantomData<V>,
     phantom_actor: PhantomData<A>
@@ -79,17 +99,17 @@ impl<'a, K: Key, V: Val<A>, A: Actor> Iterator for Iter<'a, K, V, A> {
                             .map(|val: Entry<V, A>| (key, val.val))
                     });
 
-                Some(res.map_err(|e| Error::from(e)))
+                Some(res.map_err(Error::from))
             },
-            Some(Err(e)) => Some(Err(Error::from(e))),
+            Some(Err(e)) => Some(Err(e)),
             None => None
         }
     }
 }
 
 /// Operations which can be applied to the Map CRDT
-#[serde(bound(deserialize = ""))]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(deserialize = ""))]
 pub enum Op<K: Key, V: Val<A>, A: Actor> {
     /// No change to the CRDT
     Nop,
@@ -104,6 +124,9 @@ pub enum Op<K: Key, V: Val<A>, A: Actor> {
     Up {
         /// Update context
         clock: VClock<A>,
+        /// The actor making this update, `None` for updates logged before
+        /// update ops named their actor.
+        actor: Option<A>,
         /// Key of the value to update
         key: K,
         /// The operation to apply on the value under `key`
@@ -111,61 +134,85 @@ pub enum Op<K: Key, V: Val<A>, A: Actor> {
     }
 }
 
-impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> CmRDT for Map<K, V, A> {
-    type Error = error::Error;
+/// An `Entry` as stored before entries recorded their writers (format 0)
+#[derive(Deserialize)]
+#[serde(bound(deserialize = ""))]
+struct EntryV0<V: Val<A>, A: Actor> {
+    clock: VClock<A>,
+    val: V
+}
+
+/// An `Op` as logged before update ops named their actor (op format 0)
+#[derive(Deserialize)]
+#[serde(bound(deserialize = ""))]
+enum OpV0<K: Key, V: Val<A>, A: Actor> {
+    Nop,
+    Rm { clock: VClock<A>, key: K },
+    Up { clock: VClock<A>, key: K, op: V::Op }
+}
+
+impl<K: Key, V: Val<A>, A: Actor> Op<K, V, A> {
+    /// Names the kind of op without revealing its contents
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Op::Nop => "nop",
+            Op::Rm { .. } => "rm",
+            Op::Up { .. } => "up"
+        }
+    }
+
+    /// The key this op touches, None for `Nop`
+    pub fn key(&self) -> Option<&K> {
+        match self {
+            Op::Nop => None,
+            Op::Rm { key, .. } => Some(key),
+            Op::Up { key, .. } => Some(key)
+        }
+    }
+}
+
+impl<K: Key, V: Val<A>, A: Actor> From<OpV0<K, V, A>> for Op<K, V, A> {
+    fn from(op: OpV0<K, V, A>) -> Self {
+        match op {
+            OpV0::Nop => Op::Nop,
+            OpV0::Rm { clock, key } => Op::Rm { clock, key },
+            // the writer of these updates was never recorded
+            OpV0::Up { clock, key, op } => Op::Up { clock, actor: None, key, op }
+        }
+    }
+}
+
+impl<K: Key + Debug, V: Val<A> + Debug, A: Actor, S: KvStore> CmRDT for Map<K, V, A, S> {
     type Op = Op<K, V, A>;
 
     fn apply(&mut self, op: &Self::Op) -> Result<()> {
-        let mut map_clock = self.get_clock()?;
-        match op.clone() {
-            Op::Nop => {/* do nothing */},
-            Op::Rm { clock, key } => {
-                if !(map_clock >= clock) {
-                    let key_bytes = self.key_bytes(&key)?;
-                    let del_res = self.tree.del(&key_bytes)?;
-                    if let Some(entry_bytes) = del_res {
-                        let mut entry: Entry<V, A> = bincode::deserialize(&entry_bytes)?;
-                        entry.clock.subtract(&clock);
-                        if !entry.clock.is_empty() {
-                            entry.val.truncate(&clock);
-                            let new_entry_bytes = bincode::serialize(&entry)?;
-                            self.tree.set(key_bytes, new_entry_bytes)?;
-                        } else {
-                            // the entry clock has been dominated by the
-                            // remove op clock, so we remove (already did)
-                        }
-                    }
-                    map_clock.merge(&clock);
-                    self.put_clock(map_clock)?;
-                    self.tree.flush()?;
-                }
+        self.apply_batch(slice::from_ref(op))
+    }
+
+    fn upgrade_op(format: u8, bytes: &[u8]) -> Result<Self::Op> {
+        match format {
+            0 => {
+                let op: OpV0<K, V, A> = bincode::deserialize(bytes)?;
+                Ok(op.into())
             },
-            Op::Up { clock, key, op } => {
-                if !(map_clock >= clock) {
-                    let key_bytes = self.key_bytes(&key)?;
-                    let entry_res = self.tree.del(&key_bytes)?;
+            _ => Err(Error::Version(format!("Unsupported map op format: {}", format)))
+        }
+    }
+}
 
-                    let mut entry = if let Some(entry_bytes) = entry_res {
-                        bincode::deserialize(&entry_bytes)?
-                    } else {
-                        Entry {
-                            clock: clock.clone(),
-                            val: V::default()
-                        }
-                    };
-
-                    entry.clock.merge(&clock);
-                    entry.val.apply(&op)
-                        .map_err(|_| crdts::Error::NestedOpFailed)?;
-                    let entry_bytes = bincode::serialize(&entry)?;
-                    self.tree.set(key_bytes, entry_bytes)?;
-                    map_clock.merge(&clock);
-                    self.put_clock(map_clock)?;
-                    self.tree.flush()?;
-                }
-            }
+/// Writes staged by `Map::apply_batch`, entries are kept deserialized until
+/// the batch is written out so that repeated updates to a key stay cheap.
+struct Batch<V: Val<A>, A: Actor> {
+    entries: BTreeMap<Vec<u8>, Option<Entry<V, A>>>,
+    clock_dirty: bool
+}
+
+impl<V: Val<A>, A: Actor> Batch<V, A> {
+    fn new() -> Self {
+        Batch {
+            entries: BTreeMap::new(),
+            clock_dirty: false
         }
-        Ok(())
     }
 }
 
@@ -175,17 +222,38 @@ const KEY_PREFIX: [u8; 1] = [1];
 /// Meta prefix is added to the front of all housekeeping keys created by the database
 const META_PREFIX: [u8; 1] = [0];
 
-impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
-    /// Constructs an empty Map
-    pub fn new(tree: sled::Tree) -> Map<K, V, A> {
+/// Version of the shape of stored entries. bincode can't tell one shape
+/// from another so it's bumped whenever `Entry` changes.
+///
+/// 0: stores written before formats were versioned, they carry no version.
+/// 1: entries record their writers.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Meta key holding the `FORMAT_VERSION` the store was written with
+const FORMAT_KEY: &[u8] = b"format";
+
+impl<K: Key + Debug, V: Val<A> + Debug, A: Actor, S: KvStore> Map<K, V, A, S> {
+    /// Constructs a Map backed by `store`
+    pub fn new(store: S) -> Map<K, V, A, S> {
         Map {
-            tree: tree,
+            store,
             phantom_key: PhantomData,
             phantom_val: PhantomData,
             phantom_actor: PhantomData
          }
     }
 
+    /// The store this Map is materialized into
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Mutable access to the store, writes that bypass the Map must keep
+    /// away from keys the Map manages.
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
     pub fn key_bytes(&self, key: &K) -> Result<Vec<u8>> {
         let mut bytes = bincode::serialize(&key)?;
         bytes.splice(0..0, KEY_PREFIX.iter().cloned());
@@ -197,11 +265,232 @@ impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
         key
     }
 
+    /// Apply a sequence of ops, the result is the same as applying each op
+    /// in order with `apply` but the store is written and flushed only once.
+    pub fn apply_batch(&mut self, ops: &[Op<K, V, A>]) -> Result<()> {
+        self.apply_batch_with_meta(ops, Vec::new())
+    }
+
+    /// Same as `apply_batch`, the given housekeeping entries are written in
+    /// the same store batch as the ops. Meta keys live apart from user keys,
+    /// see `meta_key_bytes`.
+    pub fn apply_batch_with_meta(
+        &mut self,
+        ops: &[Op<K, V, A>],
+        meta: Vec<(Vec<u8>, Vec<u8>)>
+    ) -> Result<()> {
+        let mut map_clock = self.get_clock()?;
+        let mut batch = Batch::new();
+        for op in ops.iter() {
+            self.stage_op(&mut batch, &mut map_clock, op)?;
+        }
+
+        if !batch.clock_dirty && meta.is_empty() {
+            // none of the ops were new to us
+            return Ok(());
+        }
+
+        let mut writes = Vec::with_capacity(batch.entries.len() + meta.len() + 1);
+        for (key_bytes, entry_opt) in batch.entries.into_iter() {
+            let entry_bytes = match entry_opt {
+                Some(entry) => Some(bincode::serialize(&entry)?),
+                None => None
+            };
+            writes.push((key_bytes, entry_bytes));
+        }
+        for (key, val) in meta.into_iter() {
+            writes.push((self.meta_key_bytes(key), Some(val)));
+        }
+        if batch.clock_dirty {
+            writes.push(self.clock_write(&map_clock)?);
+        }
+        self.store.batch(writes)?;
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn stage_op(
+        &self,
+        batch: &mut Batch<V, A>,
+        map_clock: &mut VClock<A>,
+        op: &Op<K, V, A>
+    ) -> Result<()> {
+        match op.clone() {
+            Op::Nop => {/* do nothing */},
+            Op::Rm { clock, key } => {
+                // the map clock dominates the clocks of ops it has seen
+                let seen = *map_clock >= clock;
+                if !seen {
+                    let key_bytes = self.key_bytes(&key)?;
+                    if let Some(mut entry) = self.staged_entry(batch, &key_bytes)? {
+                        entry.clock.forget(&clock);
+                        if !entry.clock.is_empty() {
+                            entry.val.forget(&clock);
+                            entry.writers.retain(|dot| !dominates(&clock, dot));
+                            batch.entries.insert(key_bytes, Some(entry));
+                        } else {
+                            // the entry clock has been dominated by the
+                            // remove op clock, so we remove
+                            batch.entries.insert(key_bytes, None);
+                        }
+                    }
+                    map_clock.merge(clock);
+                    batch.clock_dirty = true;
+                }
+            },
+            Op::Up { clock, actor, key, op } => {
+                // the map clock dominates the clocks of ops it has seen
+                let seen = *map_clock >= clock;
+                if !seen {
+                    let key_bytes = self.key_bytes(&key)?;
+                    let mut entry = self.staged_entry(batch, &key_bytes)?
+                        .unwrap_or_else(|| Entry {
+                            clock: clock.clone(),
+                            writers: BTreeSet::new(),
+                            val: V::default()
+                        });
+
+                    entry.clock.merge(clock.clone());
+                    entry.writers.retain(|dot| !dominates(&clock, dot));
+                    if let Some(actor) = actor {
+                        let counter = clock.get(&actor);
+                        entry.writers.insert((actor, counter));
+                    }
+                    entry.val.apply(&op)?;
+                    batch.entries.insert(key_bytes, Some(entry));
+                    map_clock.merge(clock);
+                    batch.clock_dirty = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads an entry, preferring writes staged in the batch over the store.
+    fn staged_entry(&self, batch: &Batch<V, A>, key_bytes: &[u8]) -> Result<Option<Entry<V, A>>> {
+        if let Some(staged) = batch.entries.get(key_bytes) {
+            return Ok(staged.clone());
+        }
+
+        let entry_opt = if let Some(entry_bytes) = self.store.get(key_bytes)? {
+            Some(bincode::deserialize(&entry_bytes)?)
+        } else {
+            None
+        };
+        Ok(entry_opt)
+    }
+
+    /// Merge the state of another Map into this one, the result is the same
+    /// as if we had applied every op that `other` has seen.
+    ///
+    /// `other` may use a different store, so a replica can be bootstrapped
+    /// from (or repaired against) another replica's materialized state.
+    pub fn merge<S2: KvStore>(&mut self, other: &Map<K, V, A, S2>) -> Result<()> {
+        let self_clock = self.get_clock()?;
+        let other_clock = other.get_clock()?;
+        let mut writes: Vec<Write> = Vec::new();
+
+        for res in self.store.scan(&KEY_PREFIX) {
+            let (key_bytes, entry_bytes) = res?;
+            let mut entry: Entry<V, A> = bincode::deserialize(&entry_bytes)?;
+
+            match other.store.get(&key_bytes)? {
+                None => {
+                    // other doesn't contain this entry because it has either
+                    //  1. witnessed it and removed it, or
+                    //  2. not witnessed it yet
+                    if other_clock >= entry.clock {
+                        writes.push((key_bytes, None));
+                    } else {
+                        // other has not seen this version of the entry, so we
+                        // keep it but drop whatever other had seen and removed.
+                        entry.clock.forget(&other_clock);
+                        entry.val.forget(&other_clock);
+                        entry.writers.retain(|dot| !dominates(&other_clock, dot));
+                        writes.push((key_bytes, Some(bincode::serialize(&entry)?)));
+                    }
+                },
+                Some(other_entry_bytes) => {
+                    // SUBTLE: this entry is present in both maps but that
+                    // doesn't mean it survives the merge, each side may have
+                    // removed versions of the entry that the other still has.
+                    let other_entry: Entry<V, A> = bincode::deserialize(&other_entry_bytes)?;
+                    let common = VClock::intersection(&entry.clock, &other_entry.clock);
+
+                    let mut self_only = entry.clock.clone();
+                    self_only.forget(&common);
+                    self_only.forget(&other_clock);
+
+                    let mut other_only = other_entry.clock.clone();
+                    other_only.forget(&common);
+                    other_only.forget(&self_clock);
+
+                    let mut merged_clock = common;
+                    merged_clock.merge(self_only);
+                    merged_clock.merge(other_only);
+
+                    if merged_clock.is_empty() {
+                        writes.push((key_bytes, None));
+                    } else {
+                        entry.clock = merged_clock;
+
+                        // a writer survives unless the other side has seen
+                        // it and since superseded or removed it.
+                        let writers: BTreeSet<(A, u64)> = {
+                            let self_writers = entry.writers.iter()
+                                .filter(|dot| {
+                                    other_entry.writers.contains(*dot)
+                                        || !dominates(&other_clock, dot)
+                                });
+                            let other_writers = other_entry.writers.iter()
+                                .filter(|dot| {
+                                    entry.writers.contains(*dot)
+                                        || !dominates(&self_clock, dot)
+                                });
+                            self_writers.chain(other_writers)
+                                .cloned()
+                                .collect()
+                        };
+                        entry.writers = writers;
+                        entry.val.merge(other_entry.val);
+                        writes.push((key_bytes, Some(bincode::serialize(&entry)?)));
+                    }
+                }
+            }
+        }
+
+        for res in other.store.scan(&KEY_PREFIX) {
+            let (key_bytes, entry_bytes) = res?;
+            if self.store.get(&key_bytes)?.is_some() {
+                // entries present in both maps were handled above
+                continue;
+            }
+
+            let mut entry: Entry<V, A> = bincode::deserialize(&entry_bytes)?;
+            // the map clock dominates the clocks of ops it has seen
+            let seen = self_clock >= entry.clock;
+            if !seen {
+                entry.clock.forget(&self_clock);
+                entry.val.forget(&self_clock);
+                entry.writers.retain(|dot| !dominates(&self_clock, dot));
+                writes.push((key_bytes, Some(bincode::serialize(&entry)?)));
+            }
+        }
+
+        let mut map_clock = self_clock;
+        map_clock.merge(other_clock);
+        writes.push(self.clock_write(&map_clock)?);
+
+        self.store.batch(writes)?;
+        self.store.flush()?;
+        Ok(())
+    }
+
     /// Get a value stored under a key
     pub fn get(&self, key: &K) -> Result<Option<V>> {
-        let key_bytes = self.key_bytes(&key)?;
+        let key_bytes = self.key_bytes(key)?;
 
-        let val_opt = if let Some(val_bytes) = self.tree.get(&key_bytes)? {
+        let val_opt = if let Some(val_bytes) = self.store.get(&key_bytes)? {
             let entry: Entry<V, A> = bincode::deserialize(&val_bytes)?;
             Some(entry.val)
         } else {
@@ -211,6 +500,33 @@ impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
         Ok(val_opt)
     }
 
+    /// Get a value along with the causal metadata of its entry
+    pub fn get_with_meta(&self, key: &K) -> Result<Option<(V, Meta<A>)>> {
+        let key_bytes = self.key_bytes(key)?;
+
+        let val_opt = if let Some(val_bytes) = self.store.get(&key_bytes)? {
+            let entry: Entry<V, A> = bincode::deserialize(&val_bytes)?;
+            let last_writer = entry.writers.iter()
+                .max_by_key(|(actor, counter)| (*counter, actor.clone()))
+                .map(|(actor, _)| actor.clone());
+            let mut writers: Vec<A> = entry.writers.iter()
+                .map(|(actor, _)| actor.clone())
+                .collect();
+            writers.dedup();
+
+            let meta = Meta {
+                clock: entry.clock,
+                writers,
+                last_writer
+            };
+            Some((entry.val, meta))
+        } else {
+            None
+        };
+
+        Ok(val_opt)
+    }
+
     /// Update a value under some key, if the key is not present in the map,
     /// the updater will be given `None`, otherwise `Some(val)` is given.
     ///
@@ -223,14 +539,15 @@ impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
         updater: impl FnOnce(V) -> Option<V::Op>
     ) -> Result<Op<K, V, A>> {
         let mut clock = self.get_clock()?;
-        clock.increment(actor.clone());
+        let dot = clock.inc(actor.clone());
+        clock.apply(&dot)?;
 
         let val_opt = self.get(&key)?;
         let val_exists = val_opt.is_some();
         let val = val_opt.unwrap_or(V::default());
 
         let op = if let Some(op) = updater(val) {
-            Op::Up { clock, key, op }
+            Op::Up { clock, actor: Some(actor), key, op }
         } else if val_exists {
             Op::Rm { clock, key }
         } else {
@@ -242,23 +559,36 @@ impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
     /// Remove an entry from the Map
     pub fn rm(&mut self, key: K, actor: A) -> Result<Op<K, V, A>> {
         let mut clock = self.get_clock()?;
-        clock.increment(actor.clone());
+        let dot = clock.inc(actor);
+        clock.apply(&dot)?;
         let op = Op::Rm { clock, key };
         Ok(op)
     }
 
     pub fn iter<'a>(&'a self) -> Iter<'a, K, V, A> {
         Iter {
-            iter: self.tree.scan(&KEY_PREFIX),
+            iter: self.store.scan(&KEY_PREFIX),
             phantom_key: PhantomData,
             phantom_val: PhantomData,
             phantom_actor: PhantomData
         }
     }
 
-    fn get_clock(&self) -> Result<crdts::VClock<A>> {
+    /// Read a housekeeping entry written with `apply_batch_with_meta`
+    pub fn get_meta(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.get(&self.meta_key_bytes(key))
+    }
+
+    /// Iterate over the housekeeping entries whose key starts with `prefix`
+    pub fn scan_meta<'a>(&'a self, prefix: Vec<u8>) -> Scan<'a> {
+        let iter = self.store.scan(&self.meta_key_bytes(prefix))
+            .map(|res| res.map(|(k, v)| (k[META_PREFIX.len()..].to_vec(), v)));
+        Box::new(iter)
+    }
+
+    fn get_clock(&self) -> Result<VClock<A>> {
         let clock_key = self.meta_key_bytes("clock".as_bytes().to_vec());
-        let clock = if let Some(clock_bytes) = self.tree.get(&clock_key)? {
+        let clock = if let Some(clock_bytes) = self.store.get(&clock_key)? {
             bincode::deserialize(&clock_bytes)?
         } else {
             VClock::new()
@@ -266,44 +596,87 @@ impl<K: Key + Debug, V: Val<A> + Debug, A: Actor> Map<K, V, A> {
         Ok(clock)
     }
 
-    fn put_clock(&self, clock: VClock<A>) -> Result<()> {
+    /// Brings a store written by an older version of the map up to
+    /// `FORMAT_VERSION`, empty stores are marked with it.
+    ///
+    /// Err(Version) if the store was written by a newer version.
+    pub fn migrate(&mut self) -> Result<()> {
+        let format_key = self.meta_key_bytes(FORMAT_KEY.to_vec());
+        let format = match self.store.get(&format_key)? {
+            Some(ref version) if version.len() == 1 => version[0],
+            Some(version) => return Err(Error::Version(
+                format!("Unreadable map format version: {:?}", version)
+            )),
+            None => 0
+        };
+
+        if format == FORMAT_VERSION {
+            return Ok(());
+        } else if format > FORMAT_VERSION {
+            return Err(Error::Version(
+                format!("Unsupported map format version: {}", format)
+            ));
+        }
+
+        let mut writes: Vec<Write> = Vec::new();
+        for res in self.store.scan(&KEY_PREFIX) {
+            let (key_bytes, entry_bytes) = res?;
+            let old: EntryV0<V, A> = bincode::deserialize(&entry_bytes)?;
+            // format 0 didn't record who wrote an entry
+            let entry = Entry {
+                clock: old.clock,
+                writers: BTreeSet::new(),
+                val: old.val
+            };
+            writes.push((key_bytes, Some(bincode::serialize(&entry)?)));
+        }
+        writes.push((format_key, Some(vec![FORMAT_VERSION])));
+
+        self.store.batch(writes)?;
+        self.store.flush()
+    }
+
+    fn clock_write(&self, clock: &VClock<A>) -> Result<Write> {
         let clock_key = self.meta_key_bytes("clock".as_bytes().to_vec());
         let clock_bytes = bincode::serialize(&clock)?;
-        self.tree.set(clock_key, clock_bytes)?;
-        Ok(())
+        Ok((clock_key, Some(clock_bytes)))
     }
 }
 
+/// True if `clock` has seen the update with this dot
+fn dominates<A: Actor>(clock: &VClock<A>, dot: &(A, u64)) -> bool {
+    let (ref actor, counter) = *dot;
+    clock.get(actor) >= counter
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use quickcheck::{Arbitrary, Gen, TestResult};
 
-    use crdts::lwwreg::LWWReg;
+    use store::MemoryStore;
+
+    use crdts::{self, Dot, MVReg};
 
     type TActor = u8;
     type TKey = u8;
-    type TVal = LWWReg<u8, (u64, TActor)>;
+    type TVal = MVReg<u8, TActor>;
     type InnerMap = crdts::Map<TKey, TVal, TActor>;
-    type TOp = Op<TKey, crdts::Map<TKey, TVal, TActor>, TActor>;
-    type TMap =  Map<TKey, InnerMap, TActor>;
+    type TOp = Op<TKey, InnerMap, TActor>;
+    type TMap =  Map<TKey, InnerMap, TActor, MemoryStore>;
 
     // We can't impl on types outside this module ie. '(u8, Vec<_>)' so we wrap.
     #[derive(Debug, Clone)]
     struct OpVec(TActor, Vec<TOp>);
 
-    fn mk_tree() -> sled::Tree {
-        let config = sled::ConfigBuilder::new().temporary(true).build();
-        sled::Tree::start(config).unwrap()
-    }
 
     impl Arbitrary for OpVec {
         fn arbitrary<G: Gen>(g: &mut G) -> Self {
             let actor = TActor::arbitrary(g);
             let num_ops: u8 = g.gen_range(0, 50);
 
-            let mut map = TMap::new(mk_tree());
+            let mut map = TMap::new(MemoryStore::new());
             let mut ops = Vec::with_capacity(num_ops as usize);
             for _ in 0..num_ops {
                 let die_roll: u8 = g.gen();
@@ -311,26 +684,26 @@ mod tests {
                 let op = match die_roll % 3 {
                     0 => {
                         // update inner map
-                        map.update(key, actor.clone(), |mut inner_map| {
+                        map.update(key, actor, |inner_map| {
                             let die_roll: u8 = g.gen();
                             let inner_key = g.gen();
                             match die_roll % 4 {
                                 0 => {
-                                    // update key inner rm
-                                    let op = inner_map.update(inner_key, actor.clone(), |_| None);
-                                    Some(op)
+                                    // update key with an inner nop
+                                    Some(crdts::map::Op::Nop)
                                 },
                                 1 => {
                                     // update key and val update
-                                    let op = inner_map.update(inner_key, actor.clone(), |mut reg| {
-                                        reg.update(g.gen(), (g.gen(), actor.clone())).unwrap();
-                                        Some(reg)
+                                    let ctx = inner_map.len().derive_add_ctx(actor);
+                                    let op = inner_map.update(inner_key, ctx, |reg, ctx| {
+                                        reg.write(g.gen(), ctx)
                                     });
                                     Some(op)
                                 },
                                 2 => {
                                     // inner rm
-                                    let op = inner_map.rm(inner_key, actor.clone());
+                                    let ctx = inner_map.get(&inner_key).derive_rm_ctx();
+                                    let op = inner_map.rm(inner_key, ctx);
                                     Some(op)
                                 },
                                 _ => {
@@ -342,24 +715,25 @@ mod tests {
                     },
                     1 => {
                         // rm
-                        map.rm(key, actor.clone()).unwrap()
+                        map.rm(key, actor).unwrap()
                     },
                     _ => {
                         // nop
                         Op::Nop
                     }
                 };
+                map.apply(&op).unwrap();
                 ops.push(op);
             }
             OpVec(actor, ops)
         }
 
-        fn shrink(&self) -> Box<Iterator<Item = Self>> {
+        fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
             let mut shrunk: Vec<Self> = Vec::new();
             for i in 0..self.1.len() {
                 let mut vec = self.1.clone();
                 vec.remove(i);
-                shrunk.push(OpVec(self.0.clone(), vec))
+                shrunk.push(OpVec(self.0, vec))
             }
             Box::new(shrunk.into_iter())
         }
@@ -367,46 +741,60 @@ mod tests {
 
     #[test]
     fn test_new() {
-        let m: TMap = Map::new(mk_tree());
+        let m: TMap = Map::new(MemoryStore::new());
         assert_eq!(m.get(&0).unwrap(), None);
     }
 
+    fn read_reg(map: &InnerMap, key: &TKey) -> Option<Vec<u8>> {
+        map.get(key).val.map(|reg| reg.read().val)
+    }
+
+    #[test]
+    fn test_sled_backed_map() {
+        let config = sled::ConfigBuilder::new().temporary(true).build();
+        let mut m: Map<TKey, InnerMap, TActor> = Map::new(sled::Tree::start(config).unwrap());
+
+        let op = m.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+        m.apply(&op).unwrap();
+        assert_eq!(m.iter().count(), 1);
+
+        let rm_op = m.rm(3, 1).unwrap();
+        m.apply(&rm_op).unwrap();
+        assert_eq!(m.get(&3).unwrap(), None);
+        assert_eq!(m.iter().count(), 0);
+    }
+
     #[test]
     fn test_update() {
-        let mut m: TMap = Map::new(mk_tree());
+        let mut m: TMap = Map::new(MemoryStore::new());
 
         // constructs a default value if does not exist
-        let up_op = m.update(101, 1, |mut map| {
-            Some(map.update(110, 1, |mut reg| {
-                let new_val = (reg.val + 1) * 2;
-                let new_dot = (reg.dot.0 + 1, 1);
-                assert!(reg.update(new_val, new_dot).is_ok());
-                Some(reg)
+        let up_op = m.update(101, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(110, ctx, |reg, ctx| {
+                let val = reg.read().val.first().cloned().unwrap_or(0);
+                reg.write((val + 1) * 2, ctx)
             }))
         }).unwrap();
         m.apply(&up_op).unwrap();
 
-        assert_eq!(
-            m.get(&101).unwrap().unwrap().get(&110),
-            Some(&LWWReg { val: 2, dot: (1, 1) })
-        );
+        assert_eq!(read_reg(&m.get(&101).unwrap().unwrap(), &110), Some(vec![2]));
 
         // the map should give the latest val to the closure
-        let up_op2 = m.update(101, 1, |mut map| {
-            Some(map.update(110, 1, |mut reg| {
-                assert_eq!(reg, LWWReg { val: 2, dot: (1, 1) });
-                let new_val = (reg.val + 1) * 2;
-                let new_dot = (reg.dot.0 + 1, 1);
-                assert!(reg.update(new_val, new_dot).is_ok());
-                Some(reg)
+        let up_op2 = m.update(101, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(110, ctx, |reg, ctx| {
+                assert_eq!(reg.read().val, vec![2]);
+                let val = reg.read().val.first().cloned().unwrap_or(0);
+                reg.write((val + 1) * 2, ctx)
             }))
         }).unwrap();
         m.apply(&up_op2).unwrap();
-        
-        assert_eq!(
-            m.get(&101).unwrap().unwrap().get(&110),
-            Some(&LWWReg { val: 6, dot: (2, 1) })
-        );
+
+        assert_eq!(read_reg(&m.get(&101).unwrap().unwrap(), &110), Some(vec![6]));
 
         // Returning None from the closure should remove the element
         let up_op3 = m.update(101, 1, |_| None).unwrap();
@@ -417,7 +805,7 @@ mod tests {
 
     #[test]
     fn test_key_bytes() {
-        let m: TMap = Map::new(mk_tree());
+        let m: TMap = Map::new(MemoryStore::new());
         let bytes = m.key_bytes(&101).unwrap();
 
         assert_eq!(bytes, vec![KEY_PREFIX[0], 101]);
@@ -429,13 +817,16 @@ mod tests {
 
     #[test]
     fn test_remove() {
-        let mut m: TMap = Map::new(mk_tree());
+        let mut m: TMap = Map::new(MemoryStore::new());
 
-        let up_op = m.update(101, 1, |mut m| Some(m.update(110, 1, |r| Some(r)))).unwrap();
+        let mut inner_map: InnerMap = crdts::Map::new();
+        let ctx = inner_map.len().derive_add_ctx(1);
+        let inner_op = inner_map.update(110, ctx, |reg, ctx| reg.write(0, ctx));
+
+        let up_op = m.update(101, 1, |_| Some(inner_op.clone())).unwrap();
         m.apply(&up_op).unwrap();
 
-        let mut inner_map: InnerMap = crdts::Map::new();
-        inner_map.update(110, 1, |r| Some(r));
+        inner_map.apply(&inner_op).unwrap();
         assert_eq!(m.get(&101).unwrap(), Some(inner_map));
 
         let rm_op = m.rm(101, 1).unwrap();
@@ -445,50 +836,45 @@ mod tests {
 
     #[test]
     fn test_reset_remove_semantics() {
-        let mut m1 = TMap::new(mk_tree());
-        let m1_op1 = m1.update(101, 74, |mut map| {
-            Some(map.update(110, 74, |mut reg| {
-                reg.update(32, (0, 74)).unwrap();
-                Some(reg)
-            }))
+        let mut m1 = TMap::new(MemoryStore::new());
+        let m1_op1 = m1.update(101, 74, |map| {
+            let ctx = map.len().derive_add_ctx(74);
+            Some(map.update(110, ctx, |reg, ctx| reg.write(32, ctx)))
         }).unwrap();
+        m1.apply(&m1_op1).unwrap();
 
-        let mut m2 = TMap::new(mk_tree());
+        let mut m2 = TMap::new(MemoryStore::new());
         m2.apply(&m1_op1).unwrap();
 
         let m1_op2 = m1.rm(101, 74).unwrap();
+        m1.apply(&m1_op2).unwrap();
 
-        let m2_op1 = m2.update(101, 37, |mut map| {
-            Some(map.update(220, 37, |mut reg| {
-                reg.update(5, (0, 37)).unwrap();
-                Some(reg)
-            }))
+        let m2_op1 = m2.update(101, 37, |map| {
+            let ctx = map.len().derive_add_ctx(37);
+            Some(map.update(220, ctx, |reg, ctx| reg.write(5, ctx)))
         }).unwrap();
+        m2.apply(&m2_op1).unwrap();
 
         m1.apply(&m2_op1).unwrap();
         m2.apply(&m1_op2).unwrap();
 
         let inner_map = m1.get(&101).unwrap().unwrap();
-        assert_matches!(inner_map.get(&220), Some(&LWWReg { val: 5, dot: (0, 37) }));
-        assert_matches!(inner_map.get(&110), None);
-        assert_eq!(inner_map.len(), 1);
+        assert_eq!(read_reg(&inner_map, &220), Some(vec![5]));
+        assert_eq!(read_reg(&inner_map, &110), None);
+        assert_eq!(inner_map.len().val, 1);
     }
 
     #[test]
     fn test_updating_with_current_clock_should_be_a_nop() {
-        let mut m1 = TMap::new(mk_tree());
+        let mut m1 = TMap::new(MemoryStore::new());
 
+        let inner_map = InnerMap::new();
+        let ctx = inner_map.len().derive_add_ctx(0);
         let res = m1.apply(&Op::Up {
             clock: VClock::new(),
+            actor: Some(0),
             key: 0,
-            op: crdts::map::Op::Up {
-                clock: VClock::new(),
-                key: 1,
-                op: LWWReg {
-                    val: 0,
-                    dot: (0, 0)
-                }
-            }
+            op: inner_map.update(1, ctx, |reg, ctx| reg.write(0, ctx))
         });
 
         assert!(res.is_ok());
@@ -497,8 +883,8 @@ mod tests {
 
     #[test]
     fn test_concurrent_add_and_remove_biases_towards_add() {
-        let mut m1 = TMap::new(mk_tree());
-        let mut m2 = TMap::new(mk_tree());
+        let mut m1 = TMap::new(MemoryStore::new());
+        let mut m2 = TMap::new(MemoryStore::new());
 
         let op1 = m1.rm(102, 75).unwrap();
         let op2 = m2.update(102, 61, |_| Some(crdts::map::Op::Nop)).unwrap();
@@ -512,8 +898,8 @@ mod tests {
 
     #[test]
     fn test_order_of_remove_and_update_does_not_matter() {
-        let mut m1 = TMap::new(mk_tree());
-        let mut m2 = TMap::new(mk_tree());
+        let mut m1 = TMap::new(MemoryStore::new());
+        let mut m2 = TMap::new(MemoryStore::new());
 
         let op1 = m1.update(0, 35, |_| Some(crdts::map::Op::Nop)).unwrap();
         m1.apply(&op1).unwrap();
@@ -533,14 +919,233 @@ mod tests {
         }
     }
 
+    fn map_state(map: &TMap) -> Vec<(u8, InnerMap)> {
+        map.iter()
+            .map(|v| v.unwrap())
+            .collect()
+    }
+
+    fn meta_state(map: &TMap) -> Vec<(u8, Meta<TActor>)> {
+        map_state(map).into_iter()
+            .map(|(key, _)| (key, map.get_with_meta(&key).unwrap().unwrap().1))
+            .collect()
+    }
+
+    #[test]
+    fn test_get_with_meta() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        assert_eq!(m.get_with_meta(&3).unwrap(), None);
+
+        let op = m.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+        m.apply(&op).unwrap();
+
+        let (val, meta) = m.get_with_meta(&3).unwrap().unwrap();
+        assert_eq!(Some(val), m.get(&3).unwrap());
+        assert_eq!(meta.clock, vec![Dot::new(1, 1)].into_iter().collect());
+        assert_eq!(meta.writers, vec![1]);
+        assert_eq!(meta.last_writer, Some(1));
+
+        // a later update by another actor supersedes the first writer
+        let op2 = m.update(3, 2, |map| {
+            let ctx = map.len().derive_add_ctx(2);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(2, ctx)))
+        }).unwrap();
+        m.apply(&op2).unwrap();
+
+        let (_, meta) = m.get_with_meta(&3).unwrap().unwrap();
+        assert_eq!(meta.clock, vec![Dot::new(1, 1), Dot::new(2, 1)].into_iter().collect());
+        assert_eq!(meta.writers, vec![2]);
+        assert_eq!(meta.last_writer, Some(2));
+    }
+
+    #[test]
+    fn test_get_with_meta_concurrent_writers() {
+        let mut m1: TMap = Map::new(MemoryStore::new());
+        let mut m2: TMap = Map::new(MemoryStore::new());
+
+        let op1 = m1.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+        let op2 = m2.update(3, 2, |map| {
+            let ctx = map.len().derive_add_ctx(2);
+            Some(map.update(5, ctx, |reg, ctx| reg.write(2, ctx)))
+        }).unwrap();
+
+        m1.apply(&op1).unwrap();
+        m1.apply(&op2).unwrap();
+        m2.apply(&op2).unwrap();
+        m2.apply(&op1).unwrap();
+
+        let (_, meta1) = m1.get_with_meta(&3).unwrap().unwrap();
+        let (_, meta2) = m2.get_with_meta(&3).unwrap().unwrap();
+        assert_eq!(meta1, meta2);
+        assert_eq!(meta1.writers, vec![1, 2]);
+        assert_eq!(meta1.last_writer, Some(2));
+    }
+
+    #[test]
+    fn test_migrate_format_0_store() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        let op = m.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+        m.apply(&op).unwrap();
+        let (val, meta) = m.get_with_meta(&3).unwrap().unwrap();
+
+        // rewrite the entry the way it was stored before formats were versioned
+        let key_bytes = m.key_bytes(&3).unwrap();
+        let v0_entry = bincode::serialize(&(meta.clock.clone(), val.clone())).unwrap();
+        m.store.set(key_bytes, v0_entry).unwrap();
+
+        m.migrate().unwrap();
+        let (migrated_val, migrated_meta) = m.get_with_meta(&3).unwrap().unwrap();
+        assert_eq!(migrated_val, val);
+        assert_eq!(migrated_meta.clock, meta.clock);
+        assert_eq!(migrated_meta.writers, vec![]);
+        assert_eq!(migrated_meta.last_writer, None);
+
+        // migrating a current store changes nothing
+        m.migrate().unwrap();
+        assert_eq!(m.get_with_meta(&3).unwrap(), Some((migrated_val, migrated_meta)));
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_formats() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        m.migrate().unwrap();
+
+        let format_key = m.meta_key_bytes(FORMAT_KEY.to_vec());
+        m.store.set(format_key, vec![FORMAT_VERSION + 1]).unwrap();
+        assert_matches!(m.migrate(), Err(Error::Version(_)));
+    }
+
+    #[test]
+    fn test_upgrade_format_0_op() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        let op = m.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+
+        // format 0 update ops had no actor, bincode writes the variant
+        // index followed by the fields
+        let v0_bytes = match op.clone() {
+            Op::Up { clock, key, op, .. } => bincode::serialize(&(2u32, clock, key, op)).unwrap(),
+            _ => panic!("expected an update op")
+        };
+        let upgraded = TMap::upgrade_op(0, &v0_bytes).unwrap();
+        match (upgraded, op) {
+            (Op::Up { clock, actor, key, op }, Op::Up { clock: c, key: k, op: o, .. }) => {
+                assert_eq!((clock, actor, key, op), (c, None, k, o));
+            },
+            _ => panic!("expected an update op")
+        }
+
+        assert_matches!(TMap::upgrade_op(1, &v0_bytes), Err(Error::Version(_)));
+
+        // an upgraded op still applies, its writer is unknown
+        let upgraded = TMap::upgrade_op(0, &v0_bytes).unwrap();
+        m.apply(&upgraded).unwrap();
+        let (_, meta) = m.get_with_meta(&3).unwrap().unwrap();
+        assert_eq!(meta.writers, vec![]);
+        assert_eq!(meta.last_writer, None);
+    }
+
+    #[test]
+    fn test_meta_is_written_with_ops() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        let op = m.update(3, 1, |map| {
+            let ctx = map.len().derive_add_ctx(1);
+            Some(map.update(4, ctx, |reg, ctx| reg.write(1, ctx)))
+        }).unwrap();
+        m.apply_batch_with_meta(std::slice::from_ref(&op), vec![(vec![7, 1], vec![42])]).unwrap();
+
+        assert_matches!(m.get(&3), Ok(Some(_)));
+        assert_eq!(m.get_meta(vec![7, 1]).unwrap(), Some(vec![42]));
+
+        // meta is written even when the ops have already been applied
+        m.apply_batch_with_meta(&[op], vec![(vec![7, 2], vec![43])]).unwrap();
+        let scanned: Vec<(Vec<u8>, Vec<u8>)> = m.scan_meta(vec![7])
+            .map(|res| res.unwrap())
+            .collect();
+        assert_eq!(scanned, vec![(vec![7, 1], vec![42]), (vec![7, 2], vec![43])]);
+
+        // meta entries are not user entries
+        assert_eq!(m.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_apply_batch_of_nothing_is_a_nop() {
+        let mut m: TMap = Map::new(MemoryStore::new());
+        assert!(m.apply_batch(&[]).is_ok());
+        assert!(m.apply_batch(&[Op::Nop, Op::Nop]).is_ok());
+        assert_eq!(m.get_clock().unwrap(), VClock::new());
+        assert_eq!(map_state(&m), vec![]);
+    }
+
     quickcheck! {
+        fn prop_apply_batch_matches_apply(ops1: OpVec, ops2: OpVec) -> bool {
+            let mut m1: TMap = Map::new(MemoryStore::new());
+            let mut m2: TMap = Map::new(MemoryStore::new());
+
+            apply_ops(&mut m1, &ops1.1);
+            apply_ops(&mut m1, &ops2.1);
+
+            m2.apply_batch(&ops1.1).unwrap();
+            m2.apply_batch(&ops2.1).unwrap();
+
+            assert_eq!(map_state(&m1), map_state(&m2));
+            assert_eq!(m1.get_clock().unwrap(), m2.get_clock().unwrap());
+            true
+        }
+
+        fn prop_merge_agrees_with_exchanging_ops(ops1: OpVec, ops2: OpVec) -> TestResult {
+            if ops1.0 == ops2.0 {
+                return TestResult::discard();
+            }
+
+            let mut m1: TMap = Map::new(MemoryStore::new());
+            let mut m2: TMap = Map::new(MemoryStore::new());
+            let mut exchanged: TMap = Map::new(MemoryStore::new());
+
+            apply_ops(&mut m1, &ops1.1);
+            apply_ops(&mut m2, &ops2.1);
+
+            apply_ops(&mut exchanged, &ops1.1);
+            apply_ops(&mut exchanged, &ops2.1);
+
+            m1.merge(&m2).unwrap();
+
+            assert_eq!(map_state(&m1), map_state(&exchanged));
+            assert_eq!(meta_state(&m1), meta_state(&exchanged));
+            assert_eq!(m1.get_clock().unwrap(), exchanged.get_clock().unwrap());
+            TestResult::from_bool(true)
+        }
+
+        fn prop_merge_idempotent(ops: OpVec) -> bool {
+            let mut m: TMap = Map::new(MemoryStore::new());
+            let mut m_clone: TMap = Map::new(MemoryStore::new());
+            apply_ops(&mut m, &ops.1);
+            apply_ops(&mut m_clone, &ops.1);
+
+            m.merge(&m_clone).unwrap();
+
+            assert_eq!(map_state(&m), map_state(&m_clone));
+            true
+        }
+
         fn prop_exchange_ops_converges(ops1: OpVec, ops2: OpVec) -> TestResult {
             if ops1.0 == ops2.0 {
                 return TestResult::discard();
             }
 
-            let mut m1: TMap = Map::new(mk_tree());
-            let mut m2: TMap = Map::new(mk_tree());
+            let mut m1: TMap = Map::new(MemoryStore::new());
+            let mut m2: TMap = Map::new(MemoryStore::new());
 
             apply_ops(&mut m1, &ops1.1);
             apply_ops(&mut m2, &ops2.1);
@@ -570,8 +1175,8 @@ mod tests {
                 return TestResult::discard();
             }
 
-            let mut m1: TMap = Map::new(mk_tree());
-            let mut m2: TMap = Map::new(mk_tree());
+            let mut m1: TMap = Map::new(MemoryStore::new());
+            let mut m2: TMap = Map::new(MemoryStore::new());
 
             apply_ops(&mut m1, &ops1.1);
             apply_ops(&mut m2, &ops2.1);
@@ -600,8 +1205,8 @@ mod tests {
         }
 
         fn prop_idempotent(ops: OpVec) -> bool {
-            let mut m: TMap = Map::new(mk_tree());
-            let mut m_clone: TMap = Map::new(mk_tree());
+            let mut m: TMap = Map::new(MemoryStore::new());
+            let mut m_clone: TMap = Map::new(MemoryStore::new());
             apply_ops(&mut m, &ops.1);
             apply_ops(&mut m_clone, &ops.1);
 
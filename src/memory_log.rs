@@ -1,14 +1,16 @@
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 
-use crdts::{CmRDT, Actor};
-use log::{TaggedOp, LogReplicable};
+use bincode;
+
+use log::{Actor, CmRDT, TaggedOp, LogReplicable, Cursor};
 use error::Result;
 
 #[derive(Debug, Clone)]
 pub struct Log<A: Actor, C: Debug + CmRDT> {
     actor: A,
-    logs: BTreeMap<A, (u64, Vec<C::Op>)>
+    logs: BTreeMap<A, (u64, Vec<C::Op>)>,
+    clear: BTreeMap<A, BTreeMap<String, Vec<u8>>>
 }
 
 #[derive(Debug, Clone)]
@@ -35,7 +37,7 @@ impl<A: Actor, C: Debug + CmRDT> LogReplicable<A, C> for Log<A, C> {
 
     fn next(&self) -> Result<Option<Self::Op>> {
         let largest_lag = self.logs.iter()
-            .max_by_key(|(_, (index, log))| (log.len() as u64) - *index);
+            .max_by_key(|(_, (index, log))| (log.len() as u64).saturating_sub(*index));
 
         if let Some((actor, (index, log))) = largest_lag {
             if *index >= log.len() as u64 {
@@ -52,6 +54,33 @@ impl<A: Actor, C: Debug + CmRDT> LogReplicable<A, C> for Log<A, C> {
         }
     }
 
+    fn pending(&self) -> Result<Vec<Self::Op>> {
+        // replay `next` against a scratch copy of the ack indices
+        let mut indices: BTreeMap<A, u64> = self.logs.iter()
+            .map(|(actor, (index, _))| (actor.clone(), *index))
+            .collect();
+
+        let mut ops = Vec::new();
+        loop {
+            let largest_lag = self.logs.iter()
+                .max_by_key(|(actor, (_, log))| (log.len() as u64).saturating_sub(indices[*actor]));
+
+            match largest_lag {
+                Some((actor, (_, log))) if indices[actor] < log.len() as u64 => {
+                    let index = indices[actor];
+                    ops.push(Op {
+                        actor: actor.clone(),
+                        index,
+                        op: log[index as usize].clone()
+                    });
+                    indices.insert(actor.clone(), index + 1);
+                },
+                _ => break
+            }
+        }
+        Ok(ops)
+    }
+
     fn ack(&mut self, op: &Self::Op) -> Result<()> {
         // We can ack ops that are not present in the log
         
@@ -64,6 +93,36 @@ impl<A: Actor, C: Debug + CmRDT> LogReplicable<A, C> for Log<A, C> {
         Ok(())
     }
 
+    fn cursor(&self, op: &Self::Op) -> Result<(A, Cursor)> {
+        let cursor = bincode::serialize(&op.index)?;
+        Ok((op.actor.clone(), cursor))
+    }
+
+    fn cursors(&self) -> Result<BTreeMap<A, Cursor>> {
+        let mut cursors = BTreeMap::new();
+        for (actor, (index, _)) in self.logs.iter() {
+            if *index > 0 {
+                cursors.insert(actor.clone(), bincode::serialize(&(*index - 1))?);
+            }
+        }
+        Ok(cursors)
+    }
+
+    fn reset_cursor(&mut self, actor: &A, cursor: Option<&Cursor>) -> Result<()> {
+        let next_index = match cursor {
+            Some(cursor) => {
+                let index: u64 = bincode::deserialize(cursor)?;
+                index + 1
+            },
+            None => 0
+        };
+
+        let log = self.logs.entry(actor.clone())
+            .or_insert_with(|| (0, Vec::new()));
+        log.0 = next_index;
+        Ok(())
+    }
+
     fn commit(&mut self, op: C::Op) -> Result<Self::Op> {
         let log = self.logs.entry(self.actor.clone())
             .or_insert_with(|| (0, Vec::new()));
@@ -73,19 +132,48 @@ impl<A: Actor, C: Debug + CmRDT> LogReplicable<A, C> for Log<A, C> {
         Ok(Op {
             actor: self.actor.clone(),
             index: log.0,
-            op: op
+            op
         })
     }
 
+    fn put_clear(&mut self, name: &str, val: Option<Vec<u8>>) -> Result<()> {
+        let records = self.clear.entry(self.actor.clone())
+            .or_default();
+        match val {
+            Some(val) => records.insert(name.to_string(), val),
+            None => records.remove(name)
+        };
+        Ok(())
+    }
+
+    fn clear_records(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut merged = BTreeMap::new();
+        for (actor, records) in self.clear.iter() {
+            if actor != &self.actor {
+                merged.extend(records.clone());
+            }
+        }
+        if let Some(records) = self.clear.get(&self.actor) {
+            merged.extend(records.clone());
+        }
+        Ok(merged)
+    }
+
     fn pull(&mut self, other: &Self) -> Result<()> {
+        for (actor, records) in other.clear.iter() {
+            // only the actor writes their own records
+            if actor != &self.actor {
+                self.clear.insert(actor.clone(), records.clone());
+            }
+        }
+
         for (actor, (_, log)) in other.logs.iter() {
             let entry = self.logs.entry(actor.clone())
                 .or_insert_with(|| (0, vec![]));
 
             if log.len() > entry.1.len() {
-                for i in (entry.1.len())..log.len() {
-                    entry.1.push(log[i as usize].clone());
-                }
+                let missing = &log[entry.1.len()..];
+                entry.1.extend_from_slice(missing);
             }
         }
         Ok(())
@@ -99,8 +187,9 @@ impl<A: Actor, C: Debug + CmRDT> LogReplicable<A, C> for Log<A, C> {
 impl<A: Actor, C: Debug + CmRDT> Log<A, C> {
     pub fn new(actor: A) -> Self {
         Log {
-            actor: actor,
-            logs: BTreeMap::new()
+            actor,
+            logs: BTreeMap::new(),
+            clear: BTreeMap::new()
         }
     }
 }
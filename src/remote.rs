@@ -28,21 +28,21 @@ pub struct Remote {
 impl Remote {
     pub fn auth(name: String, url: String, user: String, pass: String) -> Self {
         Remote {
-            name: name,
-            url: url,
+            name,
+            url,
             auth: Some(Auth { user, pass })
         }
     }
 
     pub fn no_auth(name: String, url: String) -> Self {
         Remote {
-            name: name,
-            url: url,
+            name,
+            url,
             auth: None
         }
     }
 
-    pub fn git_callbacks(&self) -> git2::RemoteCallbacks {
+    pub fn git_callbacks(&self) -> git2::RemoteCallbacks<'_> {
         let mut cbs = git2::RemoteCallbacks::new();
         cbs.credentials(move |_, _, _| {
             match self.auth {
@@ -0,0 +1,172 @@
+// Shamir secret sharing over GF(2^8).
+//
+// Each byte of the secret is the constant term of a random polynomial of
+// degree `threshold - 1`, a share is that polynomial evaluated at the
+// share's x coordinate. Any `threshold` shares interpolate back to the
+// secret, fewer reveal nothing about it.
+//
+// Field arithmetic avoids secret dependent branches and table lookups.
+
+use error::{Error, Result};
+
+/// Split `secret` into `num_shares` shares, any `threshold` of which rebuild it.
+/// `coefficients` supplies the random polynomial coefficients, it's called
+/// once per secret byte and must fill its argument with random bytes.
+///
+/// Shares are returned as `(x, y)` with x in `1..=num_shares`.
+pub fn split(
+    secret: &[u8],
+    threshold: u8,
+    num_shares: u8,
+    mut coefficients: impl FnMut(&mut [u8]) -> Result<()>
+) -> Result<Vec<(u8, Vec<u8>)>> {
+    if threshold == 0 || threshold > num_shares {
+        return Err(Error::State(
+            format!("Can't split into {} shares with threshold {}", num_shares, threshold)));
+    }
+
+    let mut shares: Vec<(u8, Vec<u8>)> = (1..=num_shares)
+        .map(|x| (x, Vec::with_capacity(secret.len())))
+        .collect();
+
+    let mut poly = vec![0u8; threshold as usize];
+    for byte in secret.iter() {
+        poly[0] = *byte;
+        coefficients(&mut poly[1..])?;
+        for (x, ys) in shares.iter_mut() {
+            ys.push(eval(&poly, *x));
+        }
+    }
+
+    for coefficient in poly.iter_mut() {
+        *coefficient = 0;
+    }
+    Ok(shares)
+}
+
+/// Rebuild the secret from at least `threshold` distinct shares, with fewer
+/// shares the result is garbage.
+pub fn combine(shares: &[(u8, Vec<u8>)]) -> Result<Vec<u8>> {
+    let secret_len = match shares.first() {
+        Some((_, ys)) => ys.len(),
+        None => return Err(Error::State("No shares to combine".into()))
+    };
+
+    for (i, (x, ys)) in shares.iter().enumerate() {
+        if *x == 0 {
+            return Err(Error::State("Share has x coordinate 0".into()));
+        }
+        if ys.len() != secret_len {
+            return Err(Error::State("Shares have different lengths".into()));
+        }
+        if shares[..i].iter().any(|(other_x, _)| other_x == x) {
+            return Err(Error::State(format!("Share {} was given twice", x)));
+        }
+    }
+
+    // lagrange interpolation at x = 0
+    let mut secret = vec![0u8; secret_len];
+    for (i, (x_i, ys)) in shares.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, (x_j, _)) in shares.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, mul(*x_j, inv(*x_i ^ *x_j)));
+            }
+        }
+        for (byte, y) in secret.iter_mut().zip(ys.iter()) {
+            *byte ^= mul(*y, basis);
+        }
+    }
+    Ok(secret)
+}
+
+fn eval(poly: &[u8], x: u8) -> u8 {
+    // horner's method, highest coefficient first
+    poly.iter().rev().fold(0u8, |acc, coefficient| mul(acc, x) ^ coefficient)
+}
+
+/// Multiplication modulo the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn mul(a: u8, b: u8) -> u8 {
+    let mut a = a;
+    let mut b = b;
+    let mut product = 0u8;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    product
+}
+
+/// a^254 is the inverse of a, 0 maps to 0
+fn inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quickcheck::TestResult;
+
+    fn counter_coefficients() -> impl FnMut(&mut [u8]) -> Result<()> {
+        let mut counter = 0u8;
+        move |buf: &mut [u8]| {
+            for b in buf.iter_mut() {
+                counter = counter.wrapping_mul(31).wrapping_add(17);
+                *b = counter;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn field_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn bad_thresholds() {
+        assert_matches!(split(b"s", 0, 3, counter_coefficients()), Err(Error::State(_)));
+        assert_matches!(split(b"s", 4, 3, counter_coefficients()), Err(Error::State(_)));
+    }
+
+    #[test]
+    fn duplicate_shares() {
+        let shares = split(b"secret", 2, 3, counter_coefficients()).unwrap();
+        let dupes = vec![shares[0].clone(), shares[0].clone()];
+        assert_matches!(combine(&dupes), Err(Error::State(_)));
+    }
+
+    quickcheck! {
+        fn prop_any_threshold_subset_combines(secret: Vec<u8>, threshold: u8, extra: u8) -> TestResult {
+            let threshold = threshold % 8 + 1;
+            let num_shares = threshold + extra % 4;
+            let shares = split(&secret, threshold, num_shares, counter_coefficients()).unwrap();
+
+            let from_first = combine(&shares[..threshold as usize]).unwrap();
+            let from_last = combine(&shares[(num_shares - threshold) as usize..]).unwrap();
+            TestResult::from_bool(from_first == secret && from_last == secret)
+        }
+
+        fn prop_too_few_shares_dont_combine(secret: Vec<u8>) -> TestResult {
+            if secret.len() < 4 {
+                return TestResult::discard();
+            }
+            let shares = split(&secret, 3, 5, counter_coefficients()).unwrap();
+            TestResult::from_bool(combine(&shares[..2]).unwrap() != secret)
+        }
+    }
+}
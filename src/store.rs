@@ -0,0 +1,636 @@
+use std::collections::BTreeMap;
+
+use bincode;
+use sled;
+
+use error::{Error, Result};
+use crypto::{Session, MasterKey, Plaintext, Encrypted, Context};
+
+/// A write staged in a batch, `None` deletes the key.
+pub type Write = (Vec<u8>, Option<Vec<u8>>);
+
+/// The entries yielded by `KvStore::scan`, in key order.
+pub type Scan<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// The ordered key/value storage a `map::Map` materializes its state into.
+pub trait KvStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()>;
+    fn del(&mut self, key: &[u8]) -> Result<()>;
+
+    /// Iterate in key order over all entries whose key starts with `prefix`
+    fn scan<'a>(&'a self, prefix: &[u8]) -> Scan<'a>;
+
+    /// Apply a group of writes in order. Backends that can apply a group
+    /// atomically should override this.
+    fn batch(&mut self, writes: Vec<Write>) -> Result<()> {
+        for (key, val) in writes.into_iter() {
+            match val {
+                Some(val) => self.set(key, val)?,
+                None => self.del(&key)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Block until all previous writes are durable
+    fn flush(&mut self) -> Result<()>;
+}
+
+impl KvStore for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let val = sled::Tree::get(self, key)?;
+        Ok(val.map(|v| v.to_vec()))
+    }
+
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        sled::Tree::set(self, key, val)?;
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        sled::Tree::del(self, key)?;
+        Ok(())
+    }
+
+    fn scan<'a>(&'a self, prefix: &[u8]) -> Scan<'a> {
+        let prefix = prefix.to_vec();
+        let iter = sled::Tree::scan(self, &prefix)
+            .map(|res| {
+                res.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                    .map_err(Error::from)
+            })
+            .take_while(move |res| match res {
+                Ok((k, _)) => k.starts_with(&prefix),
+                Err(_) => true
+            });
+        Box::new(iter)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+/// A store that lives only as long as the process, useful for tests and
+/// for replicas that rebuild their state from the log on each start.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore { entries: BTreeMap::new() }
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.entries.insert(key, val);
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.entries.remove(key);
+        Ok(())
+    }
+
+    fn scan<'a>(&'a self, prefix: &[u8]) -> Scan<'a> {
+        let prefix = prefix.to_vec();
+        let iter = self.entries.range(prefix.clone()..)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(|(k, v)| Ok((k.clone(), v.clone())));
+        Box::new(iter)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // nothing to persist
+        Ok(())
+    }
+}
+
+/// Raw keys in the wrapped store start with one of these namespaces
+const CHECK_NS: u8 = 0;
+const ENTRY_NS: u8 = 1;
+const CLEAR_NS: u8 = 2;
+
+/// `EncryptedStore` writes keys under this prefix in the clear. They can be
+/// read before the store is unlocked, see `EncryptedStore::get_clear`, so
+/// only records that are already sealed belong here. Scans skip them.
+pub const CLEAR_PREFIX: &[u8] = b"\xffclear/";
+
+/// Plaintext sealed in the key-check entry, see `EncryptedStore::unlock`
+const CHECK_PLAINTEXT: &[u8] = b"hermitdb encrypted store";
+
+/// Layout version of the sealed entries, authenticated with each of them
+const STORE_SCHEMA: u8 = 1;
+
+/// Wraps a store so that nothing is written to it in the clear, apart from
+/// keys under `CLEAR_PREFIX`.
+///
+/// Keys are replaced with a keyed hash, values are sealed together with the
+/// key they were stored under.
+///
+/// The first byte of each key is kept in the clear so that scans only need to
+/// decrypt entries sharing the first byte of the prefix. Anyone reading the
+/// wrapped store learns how many entries start with each byte. With a
+/// `map::Map` on top that is the number of user entries against housekeeping
+/// entries, for other uses pick keys whose first byte says nothing.
+///
+/// Since hashed keys are unordered, a scan decrypts and sorts every entry
+/// sharing that first byte before yielding anything. `Map::iter` decrypts
+/// every user entry, a scan is O(n) in the entries under its first byte no
+/// matter how long the prefix is. Point lookups with `get` stay O(1).
+///
+/// While the session holds old keys (see `Session::rotate_to`) entries written
+/// under those keys stay readable, `rotate_key` moves everything to the
+/// current key.
+#[derive(Debug)]
+pub struct EncryptedStore<S: KvStore> {
+    store: S,
+    sess: Session
+}
+
+impl<S: KvStore> EncryptedStore<S> {
+    /// Unlocks an encrypted store. A store seen for the first time is bound
+    /// to `sess`, afterwards only a session holding the same key unlocks it.
+    pub fn unlock(mut store: S, sess: Session) -> Result<Self> {
+        sess.master_key()?;
+
+        let check_key = vec![CHECK_NS];
+        match store.get(&check_key)? {
+            Some(check_bytes) => {
+                let encrypted: Encrypted = bincode::deserialize(&check_bytes)?;
+                match encrypted.decrypt(&sess, &check_context()) {
+                    Ok(ref plain) if plain.0 == CHECK_PLAINTEXT => (),
+                    _ => return Err(Error::Crypto("Session does not unlock this store".into()))
+                }
+            },
+            None => {
+                let encrypted = Plaintext(CHECK_PLAINTEXT.to_vec()).encrypt(&sess, &check_context())?;
+                store.set(check_key, bincode::serialize(&encrypted)?)?;
+                store.flush()?;
+            }
+        }
+
+        Ok(EncryptedStore { store, sess })
+    }
+
+    /// Read a `CLEAR_PREFIX` key from a store that may not be unlocked yet
+    pub fn get_clear(store: &S, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !key.starts_with(CLEAR_PREFIX) {
+            return Err(Error::State("Only keys under CLEAR_PREFIX are readable in the clear".into()));
+        }
+        store.get(&clear_raw_key(key))
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.sess
+    }
+
+    pub fn session_mut(&mut self) -> &mut Session {
+        &mut self.sess
+    }
+
+    /// Lock the store again, handing back the wrapped store and session
+    pub fn lock(self) -> (S, Session) {
+        (self.store, self.sess)
+    }
+
+    /// Re-encrypt every entry under `new_key`. Until this returns, entries
+    /// are readable under either key. If interrupted, unlock with a session
+    /// holding both keys and rotate again.
+    pub fn rotate_key(&mut self, new_key: MasterKey) -> Result<()> {
+        self.sess.rotate_to(new_key)?;
+
+        let mut raw_writes = Vec::new();
+        for res in self.store.scan(&[ENTRY_NS]) {
+            let (raw_key, raw_val) = res?;
+            let encrypted: Encrypted = bincode::deserialize(&raw_val)?;
+            if encrypted.is_current(&self.sess) {
+                continue;
+            }
+
+            let (key, val) = self.open(&raw_key, &raw_val)?;
+            let new_raw_key = self.raw_key(&key)?;
+            let new_raw_val = self.seal(&new_raw_key, key, val)?;
+            raw_writes.push((raw_key, None));
+            raw_writes.push((new_raw_key, Some(new_raw_val)));
+        }
+
+        let check = Plaintext(CHECK_PLAINTEXT.to_vec()).encrypt(&self.sess, &check_context())?;
+        raw_writes.push((vec![CHECK_NS], Some(bincode::serialize(&check)?)));
+
+        self.store.batch(raw_writes)?;
+        self.store.flush()?;
+        self.sess.retire_old_keys();
+        Ok(())
+    }
+
+    fn raw_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.raw_key_under(&self.sess.master_key()?, key))
+    }
+
+    fn raw_key_under(&self, master_key: &MasterKey, key: &[u8]) -> Vec<u8> {
+        let mut raw_key = vec![ENTRY_NS];
+        raw_key.extend(key.first());
+        raw_key.extend(master_key.keyed_hash(key));
+        raw_key
+    }
+
+    /// Raw keys that `key` may still be stored under from before a rotation
+    fn old_raw_keys(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let raw_keys = self.sess.old_keys()?.iter()
+            .map(|old_key| self.raw_key_under(old_key, key))
+            .collect();
+        Ok(raw_keys)
+    }
+
+    /// Values are sealed to the raw key they are stored under, moving a
+    /// value to another slot makes it fail to open.
+    fn seal(&self, raw_key: &[u8], key: Vec<u8>, val: Vec<u8>) -> Result<Vec<u8>> {
+        let plain_bytes = bincode::serialize(&(key, val))?;
+        let encrypted = Plaintext(plain_bytes).encrypt(&self.sess, &entry_context(raw_key))?;
+        Ok(bincode::serialize(&encrypted)?)
+    }
+
+    fn open(&self, raw_key: &[u8], raw_val: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let encrypted: Encrypted = bincode::deserialize(raw_val)?;
+        let plain = encrypted.decrypt(&self.sess, &entry_context(raw_key))?;
+        Ok(bincode::deserialize(&plain.0)?)
+    }
+}
+
+fn check_context() -> Context {
+    Context::named(STORE_SCHEMA, &[CHECK_NS])
+}
+
+fn entry_context(raw_key: &[u8]) -> Context {
+    Context::named(STORE_SCHEMA, raw_key)
+}
+
+fn clear_raw_key(key: &[u8]) -> Vec<u8> {
+    let mut raw_key = vec![CLEAR_NS];
+    raw_key.extend_from_slice(key);
+    raw_key
+}
+
+impl<S: KvStore> KvStore for EncryptedStore<S> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if key.starts_with(CLEAR_PREFIX) {
+            return self.store.get(&clear_raw_key(key));
+        }
+
+        let mut raw_keys = vec![self.raw_key(key)?];
+        raw_keys.extend(self.old_raw_keys(key)?);
+
+        for raw_key in raw_keys.iter() {
+            if let Some(raw_val) = self.store.get(raw_key)? {
+                let (stored_key, val) = self.open(raw_key, &raw_val)?;
+                if stored_key != key {
+                    // the entry was moved to another key's slot
+                    return Err(Error::Crypto("Entry is sealed under a different key".into()));
+                }
+                return Ok(Some(val));
+            }
+        }
+        Ok(None)
+    }
+
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.batch(vec![(key, Some(val))])
+    }
+
+    fn del(&mut self, key: &[u8]) -> Result<()> {
+        self.batch(vec![(key.to_vec(), None)])
+    }
+
+    fn scan<'a>(&'a self, prefix: &[u8]) -> Scan<'a> {
+        if let Err(e) = self.sess.master_key() {
+            return Box::new(Some(Err(e)).into_iter());
+        }
+
+        let mut raw_prefix = vec![ENTRY_NS];
+        raw_prefix.extend(prefix.first());
+
+        // plaintext key -> (sealed under current key, val)
+        let mut entries: BTreeMap<Vec<u8>, (bool, Vec<u8>)> = BTreeMap::new();
+        for res in self.store.scan(&raw_prefix) {
+            let opened = res.and_then(|(raw_key, raw_val)| {
+                let encrypted: Encrypted = bincode::deserialize(&raw_val)?;
+                let is_current = encrypted.is_current(&self.sess);
+                self.open(&raw_key, &raw_val).map(|(key, val)| (key, is_current, val))
+            });
+
+            match opened {
+                Ok((key, is_current, val)) => {
+                    if !key.starts_with(prefix) {
+                        continue;
+                    }
+                    // a copy under an old key is stale if one under the current key exists
+                    let keep_existing = entries.get(&key)
+                        .map(|(existing_is_current, _)| *existing_is_current)
+                        .unwrap_or(false);
+                    if !keep_existing {
+                        entries.insert(key, (is_current, val));
+                    }
+                },
+                Err(e) => return Box::new(Some(Err(e)).into_iter())
+            }
+        }
+        Box::new(entries.into_iter().map(|(key, (_, val))| Ok((key, val))))
+    }
+
+    fn batch(&mut self, writes: Vec<Write>) -> Result<()> {
+        if self.sess.lock_if_idle() {
+            return Err(Error::Locked);
+        }
+
+        let mut raw_writes = Vec::with_capacity(writes.len());
+        for (key, val) in writes.into_iter() {
+            if key.starts_with(CLEAR_PREFIX) {
+                raw_writes.push((clear_raw_key(&key), val));
+                continue;
+            }
+
+            for old_raw_key in self.old_raw_keys(&key)? {
+                raw_writes.push((old_raw_key, None));
+            }
+
+            let raw_key = self.raw_key(&key)?;
+            let raw_val = match val {
+                Some(val) => Some(self.seal(&raw_key, key, val)?),
+                None => None
+            };
+            raw_writes.push((raw_key, raw_val));
+        }
+        self.store.batch(raw_writes)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.store.flush()
+    }
+}
+
+/// Checks that any `KvStore` implementation must pass.
+///
+/// A backend can run the whole suite with `conformance::check_all(|| mk_store())`,
+/// each check is given a freshly constructed, empty store.
+pub mod conformance {
+    use super::KvStore;
+
+    pub fn check_all<S: KvStore>(mut mk_store: impl FnMut() -> S) {
+        get_missing_key(mk_store());
+        set_then_get(mk_store());
+        set_overwrites(mk_store());
+        del_removes(mk_store());
+        del_missing_key(mk_store());
+        scan_is_ordered_and_prefixed(mk_store());
+        scan_empty_prefix_is_everything(mk_store());
+        batch_applies_writes_in_order(mk_store());
+        flush(mk_store());
+    }
+
+    pub fn get_missing_key<S: KvStore>(store: S) {
+        assert_eq!(store.get(b"missing").unwrap(), None);
+    }
+
+    pub fn set_then_get<S: KvStore>(mut store: S) {
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+
+    pub fn set_overwrites<S: KvStore>(mut store: S) {
+        store.set(b"k".to_vec(), b"v1".to_vec()).unwrap();
+        store.set(b"k".to_vec(), b"v2".to_vec()).unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v2".to_vec()));
+    }
+
+    pub fn del_removes<S: KvStore>(mut store: S) {
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+        store.del(b"k").unwrap();
+        assert_eq!(store.get(b"k").unwrap(), None);
+    }
+
+    pub fn del_missing_key<S: KvStore>(mut store: S) {
+        assert!(store.del(b"missing").is_ok());
+    }
+
+    pub fn scan_is_ordered_and_prefixed<S: KvStore>(mut store: S) {
+        store.set(vec![1, 3], vec![13]).unwrap();
+        store.set(vec![0, 9], vec![9]).unwrap();
+        store.set(vec![1, 1], vec![11]).unwrap();
+        store.set(vec![2, 0], vec![20]).unwrap();
+        store.set(vec![1], vec![1]).unwrap();
+
+        let scanned: Vec<(Vec<u8>, Vec<u8>)> = store.scan(&[1])
+            .map(|res| res.unwrap())
+            .collect();
+
+        assert_eq!(scanned, vec![
+            (vec![1], vec![1]),
+            (vec![1, 1], vec![11]),
+            (vec![1, 3], vec![13])
+        ]);
+    }
+
+    pub fn scan_empty_prefix_is_everything<S: KvStore>(mut store: S) {
+        store.set(vec![2], vec![2]).unwrap();
+        store.set(vec![0], vec![0]).unwrap();
+
+        let keys: Vec<Vec<u8>> = store.scan(&[])
+            .map(|res| res.unwrap().0)
+            .collect();
+
+        assert_eq!(keys, vec![vec![0], vec![2]]);
+    }
+
+    pub fn batch_applies_writes_in_order<S: KvStore>(mut store: S) {
+        store.set(b"gone".to_vec(), b"v".to_vec()).unwrap();
+        store.batch(vec![
+            (b"a".to_vec(), Some(b"1".to_vec())),
+            (b"gone".to_vec(), None),
+            (b"b".to_vec(), Some(b"2".to_vec())),
+            (b"a".to_vec(), Some(b"3".to_vec())),
+            (b"b".to_vec(), None)
+        ]).unwrap();
+
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"gone").unwrap(), None);
+    }
+
+    pub fn flush<S: KvStore>(mut store: S) {
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crypto::{KDF, KdfAlgo};
+
+    #[test]
+    fn test_memory_store_conformance() {
+        conformance::check_all(MemoryStore::new);
+    }
+
+    fn mk_sess(pass: &str) -> Session {
+        let kdf = KDF {
+            algo: KdfAlgo::Pbkdf2Sha256 { iters: 1 },
+            salt: [7u8; 256 / 8],
+            entropy: [3u8; 256 / 8]
+        };
+        Session::new(0, kdf.master_key(pass.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn test_encrypted_store_conformance() {
+        conformance::check_all(|| {
+            EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_encrypted_store_hides_keys_and_values() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap();
+        store.set(b"secret key".to_vec(), b"secret val".to_vec()).unwrap();
+
+        let (inner, _) = store.lock();
+        for (k, v) in inner.entries.iter() {
+            let leaks = |bytes: &Vec<u8>| {
+                bytes.windows(6).any(|w| w == b"secret")
+            };
+            assert!(!leaks(k));
+            assert!(!leaks(v));
+        }
+    }
+
+    #[test]
+    fn test_encrypted_store_detects_swapped_values() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap();
+        store.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let raw_a = store.raw_key(b"a").unwrap();
+        let raw_b = store.raw_key(b"b").unwrap();
+        let (mut inner, sess) = store.lock();
+
+        let val_b = inner.get(&raw_b).unwrap().unwrap();
+        inner.set(raw_a, val_b).unwrap();
+
+        let store = EncryptedStore::unlock(inner, sess).unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_matches!(store.get(b"a"), Err(Error::Crypto(_)));
+        assert_matches!(store.scan(&[]).next(), Some(Err(Error::Crypto(_))));
+    }
+
+    #[test]
+    fn test_encrypted_store_needs_the_right_session() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap();
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+        let (inner, _) = store.lock();
+
+        assert_matches!(
+            EncryptedStore::unlock(inner.clone(), mk_sess("imposter")),
+            Err(Error::Crypto(_))
+        );
+
+        let store = EncryptedStore::unlock(inner, mk_sess("pass")).unwrap();
+        assert_eq!(store.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+
+    #[test]
+    fn test_encrypted_store_key_rotation() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("old")).unwrap();
+        store.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+
+        let new_key = mk_sess("new").master_key().unwrap();
+        store.rotate_key(new_key).unwrap();
+        assert!(store.sess.old_keys().unwrap().is_empty());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.scan(&[]).count(), 2);
+
+        let (inner, _) = store.lock();
+        assert_matches!(
+            EncryptedStore::unlock(inner.clone(), mk_sess("old")),
+            Err(Error::Crypto(_))
+        );
+        let store = EncryptedStore::unlock(inner, mk_sess("new")).unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_encrypted_store_readable_mid_rotation() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("old")).unwrap();
+        store.set(b"a".to_vec(), b"1".to_vec()).unwrap();
+        store.set(b"b".to_vec(), b"2".to_vec()).unwrap();
+
+        // the session moved to a new key but nothing was re-encrypted yet
+        store.sess.rotate_to(mk_sess("new").master_key().unwrap()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        // writes go under the new key and replace the old copy
+        store.set(b"a".to_vec(), b"3".to_vec()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+
+        let scanned: Vec<(Vec<u8>, Vec<u8>)> = store.scan(&[])
+            .map(|res| res.unwrap())
+            .collect();
+        assert_eq!(scanned, vec![
+            (b"a".to_vec(), b"3".to_vec()),
+            (b"b".to_vec(), b"2".to_vec())
+        ]);
+        assert_eq!(store.store.entries.len(), 3); // check entry + 2 entries
+    }
+
+    #[test]
+    fn test_sled_conformance() {
+        conformance::check_all(|| {
+            let config = sled::ConfigBuilder::new().temporary(true).build();
+            sled::Tree::start(config).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_encrypted_store_locked_session() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap();
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+
+        store.session_mut().lock();
+        assert_matches!(store.get(b"k"), Err(Error::Locked));
+        assert_matches!(store.set(b"k".to_vec(), b"w".to_vec()), Err(Error::Locked));
+        assert_matches!(store.scan(&[]).next(), Some(Err(Error::Locked)));
+
+        let (inner, sess) = store.lock();
+        assert_matches!(EncryptedStore::unlock(inner, sess), Err(Error::Locked));
+    }
+
+    #[test]
+    fn test_encrypted_store_clear_prefix() {
+        let mut store = EncryptedStore::unlock(MemoryStore::new(), mk_sess("pass")).unwrap();
+        let mut clear_key = CLEAR_PREFIX.to_vec();
+        clear_key.extend(b"record");
+        store.set(clear_key.clone(), b"public".to_vec()).unwrap();
+        store.set(b"k".to_vec(), b"v".to_vec()).unwrap();
+
+        assert_eq!(store.get(&clear_key).unwrap(), Some(b"public".to_vec()));
+        assert_eq!(store.scan(&[]).count(), 1);
+
+        let (inner, _) = store.lock();
+        assert_eq!(
+            EncryptedStore::get_clear(&inner, &clear_key).unwrap(),
+            Some(b"public".to_vec())
+        );
+        assert_matches!(EncryptedStore::get_clear(&inner, b"k"), Err(Error::State(_)));
+    }
+}
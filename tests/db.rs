@@ -4,15 +4,22 @@ extern crate tempfile;
 #[macro_use]
 extern crate assert_matches;
 
+use std::fs;
+use std::time::Duration;
+use std::thread;
+
 use gitdb::data::{Prim, Op, Kind, Actor};
-use gitdb::{memory_log, map, sled, db, DB};
+use gitdb::{memory_log, git_log, git2, map, sled, db, crypto, store, DB, Error, LogReplicable};
 
-fn mk_db(actor: Actor) -> DB<memory_log::Log<Actor, db::Map>> {
-    let config = sled::ConfigBuilder::new().temporary(true).build();
+fn mk_map() -> db::Map {
+    let config = sled::ConfigBuilder::new().temporary(true).flush_every_ms(None).build();
     let tree = sled::Tree::start(config).unwrap();
+    map::Map::new(tree)
+}
+
+fn mk_db(actor: Actor) -> DB<memory_log::Log<Actor, db::Map>> {
     let log = memory_log::Log::new(actor);
-    let map = map::Map::new(tree);
-    DB::new(log, map)
+    DB::new(log, mk_map()).unwrap()
 }
 
 #[test]
@@ -23,14 +30,646 @@ fn test_write_read_set() {
 
     assert_matches!(
         db.update(("x".as_bytes().to_vec(), Kind::Set), 1, |data| {
-            let mut set = data.set().unwrap();
-            Some(Op::Set(set.add(Prim::Float(57.18), 1)))
+            let set = data.set().unwrap();
+            let ctx = set.read().derive_add_ctx(1);
+            Some(Op::Set(set.add(Prim::Float(57.18), ctx)))
         }),
         Ok(())
     );
 
     assert_eq!(
-        db.get(&("x".as_bytes().to_vec(), Kind::Set)).unwrap().unwrap().set().unwrap().value(),
+        db.get(&("x".as_bytes().to_vec(), Kind::Set)).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
         vec![Prim::Float(57.18)]
     );
 }
+
+#[test]
+fn test_get_with_meta() {
+    let mut db = mk_db(1);
+    let key = ("x".as_bytes().to_vec(), Kind::Reg);
+
+    assert_matches!(db.get_with_meta(&key), Ok(None));
+
+    assert_matches!(
+        db.update(key.clone(), 7, |data| {
+            let mut reg = data.reg().unwrap();
+            reg.update(Prim::Int(3), (1, 7)).unwrap();
+            Some(Op::Reg(reg))
+        }),
+        Ok(())
+    );
+
+    let (data, meta) = db.get_with_meta(&key).unwrap().unwrap();
+    assert_eq!(data.reg().unwrap().val, Prim::Int(3));
+    assert_eq!(meta.last_writer, Some(7));
+    assert_eq!(meta.writers, vec![7]);
+}
+
+#[test]
+fn test_ops_acked_but_never_applied_are_redelivered() {
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+
+    // simulate losing the map after the log acked an op
+    let mut lost_map = mk_map();
+    let op = lost_map.update(key.clone(), 1, |data| {
+        let set = data.set().unwrap();
+        let ctx = set.read().derive_add_ctx(1);
+        Some(Op::Set(set.add(Prim::Int(5), ctx)))
+    }).unwrap();
+
+    let mut log: memory_log::Log<Actor, db::Map> = memory_log::Log::new(1);
+    let tagged_op = log.commit(op).unwrap();
+    log.ack(&tagged_op).unwrap();
+    assert_matches!(log.next(), Ok(None));
+
+    let mut db = DB::new(log, mk_map()).unwrap();
+    assert_matches!(db.get(&key), Ok(None));
+
+    assert_matches!(db.sync(), Ok(()));
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(5)]
+    );
+}
+
+#[test]
+fn test_encrypted_db() {
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let sess = crypto::Session::new(1, kdf.master_key("secret".as_bytes()).unwrap());
+
+    let config = sled::ConfigBuilder::new().temporary(true).build();
+    let tree = sled::Tree::start(config).unwrap();
+    let log: memory_log::Log<Actor, db::Map<store::EncryptedStore<sled::Tree>>> =
+        memory_log::Log::new(1);
+    let mut db = DB::unlock(log, tree, sess).unwrap();
+
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+    assert_matches!(
+        db.update(key.clone(), 1, |data| {
+            let set = data.set().unwrap();
+            let ctx = set.read().derive_add_ctx(1);
+            Some(Op::Set(set.add(Prim::Str("hidden".into()), ctx)))
+        }),
+        Ok(())
+    );
+
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Str("hidden".into())]
+    );
+}
+
+#[test]
+fn test_share_with_recipients() {
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let master_key = kdf.master_key("secret".as_bytes()).unwrap();
+    let sess = crypto::Session::new(1, master_key.clone());
+
+    let log: memory_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>> =
+        memory_log::Log::new(1);
+    let mut db = DB::unlock(log, store::MemoryStore::new(), sess).unwrap();
+
+    let bob = crypto::SecretKey::generate().unwrap();
+    let carol = crypto::SecretKey::generate().unwrap();
+    assert_eq!(db.recipients().unwrap(), vec![]);
+
+    db.share_with(&bob.public_key()).unwrap();
+    db.share_with(&carol.public_key()).unwrap();
+
+    let recipients = db.recipients().unwrap();
+    assert_eq!(recipients.len(), 2);
+    for wrapped in recipients.iter() {
+        let secret = if wrapped.recipient == bob.public_key() { &bob } else { &carol };
+        assert_eq!(secret.unwrap_key(wrapped).unwrap(), master_key);
+    }
+
+    db.unshare(&carol.public_key()).unwrap();
+    let new_key = kdf.master_key("new secret".as_bytes()).unwrap();
+    db.rotate_key(new_key.clone()).unwrap();
+
+    let recipients = db.recipients().unwrap();
+    assert_eq!(recipients.len(), 1);
+    assert_eq!(recipients[0].recipient, bob.public_key());
+    assert_eq!(bob.unwrap_key(&recipients[0]).unwrap(), new_key);
+}
+
+#[test]
+fn test_unlock_with_secret_key() {
+    type SharedDB = DB<
+        memory_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>>,
+        store::EncryptedStore<store::MemoryStore>
+    >;
+
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let data_key = kdf.master_key("secret".as_bytes()).unwrap();
+    let mut alice: SharedDB = DB::unlock(
+        memory_log::Log::new(1), store::MemoryStore::new(), crypto::Session::new(1, data_key)
+    ).unwrap();
+
+    let key = ("hermitdb/recipient/x".as_bytes().to_vec(), Kind::Set);
+    assert_matches!(
+        alice.update(key.clone(), 1, |data| {
+            let set = data.set().unwrap();
+            let ctx = set.read().derive_add_ctx(1);
+            Some(Op::Set(set.add(Prim::Int(42), ctx)))
+        }),
+        Ok(())
+    );
+
+    let bob = crypto::SecretKey::generate().unwrap();
+    let carol = crypto::SecretKey::generate().unwrap();
+    alice.share_with(&bob.public_key()).unwrap();
+
+    // wrapped keys stay out of the user's keyspace
+    assert_eq!(
+        alice.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(42)]
+    );
+
+    let mut bob_log = memory_log::Log::new(2);
+    bob_log.pull(alice.log()).unwrap();
+    let mut carol_log = memory_log::Log::new(3);
+    carol_log.pull(alice.log()).unwrap();
+
+    let unlocked: Result<SharedDB, _> =
+        DB::unlock_with_secret_key(carol_log, store::MemoryStore::new(), 3, &carol);
+    assert_matches!(unlocked.err(), Some(Error::NotFound));
+
+    let mut bob_db: SharedDB =
+        DB::unlock_with_secret_key(bob_log, store::MemoryStore::new(), 2, &bob).unwrap();
+    assert_matches!(bob_db.sync(), Ok(()));
+    assert_eq!(
+        bob_db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(42)]
+    );
+    assert_eq!(bob_db.recipients().unwrap().len(), 1);
+}
+
+#[test]
+fn test_locked_db() {
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let keyfile = crypto::Keyfile::new(1, &kdf, b"secret").unwrap();
+    let sess = keyfile.unlock(b"secret").unwrap();
+
+    let log: memory_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>> =
+        memory_log::Log::new(1);
+    let mut db = DB::unlock(log, store::MemoryStore::new(), sess).unwrap();
+
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+    let add = |member: i64| move |data: gitdb::data::Data| {
+        let set = data.set().unwrap();
+        let ctx = set.read().derive_add_ctx(1);
+        Some(Op::Set(set.add(Prim::Int(member), ctx)))
+    };
+    assert_matches!(db.update(key.clone(), 1, add(1)), Ok(()));
+
+    db.lock();
+    assert!(db.is_locked());
+    assert_matches!(db.get(&key), Err(Error::Locked));
+    assert_matches!(db.update(key.clone(), 1, add(2)), Err(Error::Locked));
+
+    assert_matches!(db.unlock_session(b"wrong"), Err(Error::WrongPassword));
+    assert_matches!(db.unlock_session(b"secret"), Ok(()));
+    assert_matches!(db.update(key.clone(), 1, add(2)), Ok(()));
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(1), Prim::Int(2)]
+    );
+}
+
+#[test]
+fn test_idle_db_locks_itself() {
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let keyfile = crypto::Keyfile::new(1, &kdf, b"secret").unwrap();
+    let mut sess = keyfile.unlock(b"secret").unwrap();
+    sess.set_idle_timeout(Some(Duration::from_millis(50)));
+
+    let log: memory_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>> =
+        memory_log::Log::new(1);
+    let mut db = DB::unlock(log, store::MemoryStore::new(), sess).unwrap();
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+    assert_matches!(db.get(&key), Ok(None));
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(db.is_locked());
+    assert_matches!(db.get(&key), Err(Error::Locked));
+
+    assert_matches!(db.unlock_session(b"secret"), Ok(()));
+    assert_matches!(db.get(&key), Ok(None));
+}
+
+#[test]
+fn test_change_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let mk_tree = || {
+        let config = sled::ConfigBuilder::new().path(dir.path().join("map")).build();
+        sled::Tree::start(config).unwrap()
+    };
+    type PassDB = DB<memory_log::Log<Actor, db::Map<store::EncryptedStore<sled::Tree>>>, store::EncryptedStore<sled::Tree>>;
+
+    let entropy = crypto::rand_256().unwrap();
+    let kdf = crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy
+    };
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+
+    {
+        let mut db: PassDB = DB::create_with_passphrase(
+            memory_log::Log::new(1), mk_tree(), 1, &kdf, b"old"
+        ).unwrap();
+        assert_matches!(
+            db.update(key.clone(), 1, |data| {
+                let set = data.set().unwrap();
+                let ctx = set.read().derive_add_ctx(1);
+                Some(Op::Set(set.add(Prim::Int(1), ctx)))
+            }),
+            Ok(())
+        );
+
+        let data_key = db.key_record().unwrap().unwrap().unseal(entropy, b"old").unwrap();
+        assert_matches!(db.change_passphrase(entropy, b"wrong", b"new"), Err(Error::WrongPassword));
+        assert_matches!(db.change_passphrase(entropy, b"old", b"new"), Ok(()));
+
+        // only the record changed, the data key is the same
+        let record = db.key_record().unwrap().unwrap();
+        assert_eq!(record.unseal(entropy, b"new").unwrap(), data_key);
+        assert_matches!(record.unseal(entropy, b"old"), Err(Error::WrongPassword));
+
+        db.lock();
+        assert_matches!(db.unlock_session(b"old"), Err(Error::WrongPassword));
+        assert_matches!(db.unlock_session(b"new"), Ok(()));
+    }
+
+    let reopened: Result<PassDB, _> = DB::unlock_with_passphrase(
+        memory_log::Log::new(1), mk_tree(), 1, entropy, b"old"
+    );
+    assert_matches!(reopened.err(), Some(Error::WrongPassword));
+
+    let mut db: PassDB = DB::unlock_with_passphrase(
+        memory_log::Log::new(1), mk_tree(), 1, entropy, b"new"
+    ).unwrap();
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(1)]
+    );
+
+    let old_data_key = db.key_record().unwrap().unwrap().unseal(entropy, b"new").unwrap();
+    assert_matches!(db.rotate_data_key(entropy, b"new"), Ok(()));
+    let new_data_key = db.key_record().unwrap().unwrap().unseal(entropy, b"new").unwrap();
+    assert_ne!(old_data_key, new_data_key);
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(1)]
+    );
+}
+
+#[test]
+fn test_recover_with_code_and_shares() {
+    let dir = tempfile::tempdir().unwrap();
+    let mk_tree = || {
+        let config = sled::ConfigBuilder::new().path(dir.path().join("map")).build();
+        sled::Tree::start(config).unwrap()
+    };
+    type PassDB = DB<memory_log::Log<Actor, db::Map<store::EncryptedStore<sled::Tree>>>, store::EncryptedStore<sled::Tree>>;
+    let mk_kdf = || crypto::KDF {
+        algo: crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 },
+        salt: crypto::rand_256().unwrap(),
+        entropy: crypto::rand_256().unwrap()
+    };
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+
+    let code = {
+        let mut db: PassDB = DB::create_with_passphrase(
+            memory_log::Log::new(1), mk_tree(), 1, &mk_kdf(), b"forgotten"
+        ).unwrap();
+        assert_matches!(
+            db.update(key.clone(), 1, |data| {
+                let set = data.set().unwrap();
+                let ctx = set.read().derive_add_ctx(1);
+                Some(Op::Set(set.add(Prim::Int(1), ctx)))
+            }),
+            Ok(())
+        );
+        db.recovery_code().unwrap()
+    };
+
+    // the entropy went with the passphrase, a new kdf is picked
+    let kdf = mk_kdf();
+    let shares = {
+        let mut db: PassDB = DB::recover_with_code(
+            memory_log::Log::new(1), mk_tree(), 1, &code, &kdf, b"new"
+        ).unwrap();
+        assert_eq!(
+            db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+            vec![Prim::Int(1)]
+        );
+
+        // the recovered session locks and unlocks with the new passphrase
+        db.lock();
+        assert_matches!(db.unlock_session(b"forgotten"), Err(Error::WrongPassword));
+        assert_matches!(db.unlock_session(b"new"), Ok(()));
+
+        let data_key = db.key_record().unwrap().unwrap().unseal(kdf.entropy, b"new").unwrap();
+        crypto::split_key(&data_key, 2, 3).unwrap()
+    };
+
+    {
+        let db: PassDB = DB::unlock_with_passphrase(
+            memory_log::Log::new(1), mk_tree(), 1, kdf.entropy, b"new"
+        ).unwrap();
+        assert_eq!(db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(), vec![Prim::Int(1)]);
+    }
+
+    let kdf = mk_kdf();
+    let mut db: PassDB = DB::recover_with_shares(
+        memory_log::Log::new(1), mk_tree(), 1, &shares[1..], &kdf, b"newer"
+    ).unwrap();
+    assert_eq!(db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(), vec![Prim::Int(1)]);
+
+    // rotating the data key retires codes printed before it
+    assert_matches!(db.rotate_data_key(kdf.entropy, b"newer"), Ok(()));
+    let new_code = db.recovery_code().unwrap();
+    assert_ne!(new_code, code);
+    drop(db);
+    let stale: Result<PassDB, _> = DB::recover_with_code(
+        memory_log::Log::new(1), mk_tree(), 1, &code, &mk_kdf(), b"stale"
+    );
+    assert_matches!(stale.err(), Some(Error::Crypto(_)));
+    let recovered: Result<PassDB, _> = DB::recover_with_code(
+        memory_log::Log::new(1), mk_tree(), 1, &new_code, &mk_kdf(), b"fresh"
+    );
+    assert!(recovered.is_ok());
+}
+
+#[test]
+fn test_reserved_keys_are_refused() {
+    let mut db = mk_db(1);
+    let mut key = db::RESERVED_PREFIX.to_vec();
+    key.extend_from_slice(b"key_record");
+    let key = (key, Kind::Reg);
+
+    assert_matches!(db.get(&key), Err(Error::State(_)));
+    assert_matches!(db.get_with_meta(&key), Err(Error::State(_)));
+    assert_matches!(
+        db.update(key.clone(), 1, |data| {
+            let mut reg = data.reg().unwrap();
+            reg.update(Prim::Int(1), (1, 1)).unwrap();
+            Some(Op::Reg(reg))
+        }),
+        Err(Error::State(_))
+    );
+    assert_matches!(db.rm(key, 1), Err(Error::State(_)));
+}
+
+#[test]
+fn test_rotate_key_rotates_the_log_key() {
+    type SealedDB = DB<
+        git_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>>,
+        store::EncryptedStore<store::MemoryStore>
+    >;
+
+    let dir = tempfile::tempdir().unwrap();
+    let git = git2::Repository::init_bare(dir.path()).unwrap();
+    let old_key = crypto::MasterKey::generate().unwrap();
+    let new_key = crypto::MasterKey::generate().unwrap();
+    let log = git_log::Log::no_auth(1, git, "a".into(), dir.path().to_str().unwrap().to_string())
+        .with_options(git_log::Options::default().sealed(old_key.clone()));
+    let sess = crypto::Session::new(1, old_key.clone());
+    let mut db: SealedDB = DB::unlock(log, store::MemoryStore::new(), sess).unwrap();
+
+    let key = ("x".as_bytes().to_vec(), Kind::Reg);
+    let put = |db: &mut SealedDB, val: i64| {
+        db.update(key.clone(), 1, |data| {
+            let mut reg = data.reg().ok()?;
+            let marker = (reg.marker.0 + 1, 1);
+            reg.update(Prim::Int(val), marker).ok()?;
+            Some(Op::Reg(reg))
+        })
+    };
+
+    assert_matches!(put(&mut db, 1), Ok(()));
+    assert_matches!(db.rotate_key(new_key.clone()), Ok(()));
+    assert_eq!(db.log().options().op_key, Some(new_key));
+    assert_eq!(db.log().options().retired_op_keys, vec![old_key]);
+
+    assert_matches!(put(&mut db, 2), Ok(()));
+    assert_eq!(db.get(&key).unwrap().unwrap().reg().unwrap().val, Prim::Int(2));
+
+    // a log opened with the rotated options reads ops from before and after
+    let git = git2::Repository::open_bare(dir.path()).unwrap();
+    let mut reopened: git_log::Log<Actor, db::Map<store::EncryptedStore<store::MemoryStore>>> =
+        git_log::Log::no_auth(1, git, "a".into(), dir.path().to_str().unwrap().to_string())
+            .with_options(db.log().options().clone());
+    reopened.reset_cursor(&1, None).unwrap();
+    assert_eq!(reopened.pending().unwrap().len(), 2);
+}
+
+#[test]
+fn test_sync_reports_ops_pending() {
+    let mut db = mk_db(1);
+    let mut remote: memory_log::Log<Actor, db::Map> = memory_log::Log::new(2);
+    remote.commit(map::Op::Nop).unwrap();
+    db.add_remote(remote);
+
+    let mut reports = Vec::new();
+    assert_matches!(db.sync_with_progress(|p| reports.push(p)), Ok(()));
+    assert_eq!(reports, vec![gitdb::Progress::Apply { ops_pending: 1 }]);
+}
+
+#[test]
+fn test_open_creates_and_reopens_a_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+
+    let actor = {
+        let mut db = DB::open(dir.path(), db::Options::default()).unwrap();
+        let actor = *db.log().actor();
+        assert_matches!(
+            db.update(key.clone(), actor, |data| {
+                let set = data.set().unwrap();
+                let ctx = set.read().derive_add_ctx(actor);
+                Some(Op::Set(set.add(Prim::Int(7), ctx)))
+            }),
+            Ok(())
+        );
+        actor
+    };
+
+    assert!(dir.path().join("git").is_dir());
+
+    let db = DB::open(dir.path(), db::Options::default()).unwrap();
+    assert_eq!(*db.log().actor(), actor);
+    assert_eq!(db.log().cursors().unwrap().len(), 1);
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(7)]
+    );
+
+    let other_dir = tempfile::tempdir().unwrap();
+    let other = DB::open(other_dir.path(), db::Options::default()).unwrap();
+    assert_ne!(*other.log().actor(), actor);
+}
+
+#[test]
+fn test_open_with_passphrase() {
+    let dir = tempfile::tempdir().unwrap();
+    let opts = db::Options {
+        url: Some("https://example.com/db.git".into()),
+        kdf_algo: Some(crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 }),
+        ..db::Options::default()
+    };
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+
+    {
+        let mut db = DB::open_with_passphrase(dir.path(), opts.clone(), b"pass").unwrap();
+        assert_eq!(db.log().url(), "https://example.com/db.git");
+        assert!(db.log().options().op_key.is_some());
+        let actor = *db.log().actor();
+        assert_matches!(
+            db.update(key.clone(), actor, |data| {
+                let set = data.set().unwrap();
+                let ctx = set.read().derive_add_ctx(actor);
+                Some(Op::Set(set.add(Prim::Int(7), ctx)))
+            }),
+            Ok(())
+        );
+    }
+
+    assert_matches!(
+        DB::open_with_passphrase(dir.path(), opts.clone(), b"wrong").err(),
+        Some(Error::WrongPassword)
+    );
+
+    let db = DB::open_with_passphrase(dir.path(), opts, b"pass").unwrap();
+    assert_eq!(
+        db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect::<Vec<_>>(),
+        vec![Prim::Int(7)]
+    );
+
+    // without a url the log is reached at its git dir
+    let other_dir = tempfile::tempdir().unwrap();
+    let other = DB::open(other_dir.path(), db::Options::default()).unwrap();
+    assert_eq!(other.log().url(), other_dir.path().join("git").to_str().unwrap());
+}
+
+#[test]
+fn test_open_with_passphrase_joins_a_synced_db() {
+    let hub = tempfile::tempdir().unwrap();
+    git2::Repository::init_bare(hub.path()).unwrap();
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let opts = db::Options {
+        remotes: vec![("hub".into(), hub.path().to_str().unwrap().to_string())],
+        kdf_algo: Some(crypto::KdfAlgo::Pbkdf2Sha256 { iters: 1000 }),
+        ..db::Options::default()
+    };
+    let key = ("x".as_bytes().to_vec(), Kind::Set);
+    let add = |db: &mut DB<_, _>, actor: Actor, val: i64| {
+        db.update(key.clone(), actor, |data| {
+            let set = data.set().unwrap();
+            let ctx = set.read().derive_add_ctx(actor);
+            Some(Op::Set(set.add(Prim::Int(val), ctx)))
+        })
+    };
+    let read = |db: &DB<_, _>| {
+        let mut vals: Vec<Prim> = db.get(&key).unwrap().unwrap().set().unwrap().read().val.into_iter().collect();
+        vals.sort();
+        vals
+    };
+
+    let mut a = DB::open_with_passphrase(dir_a.path(), opts.clone(), b"pass").unwrap();
+    let actor_a = *a.log().actor();
+    assert_matches!(add(&mut a, actor_a, 7), Ok(()));
+    assert_matches!(a.sync(), Ok(()));
+
+    // an unreachable remote fails the open rather than picking a new key
+    let missing_opts = db::Options {
+        remotes: vec![("hub".into(), hub.path().join("missing").to_str().unwrap().to_string())],
+        ..opts.clone()
+    };
+    assert!(DB::open_with_passphrase(dir_b.path(), missing_opts, b"pass").is_err());
+    assert!(!dir_b.path().join("entropy_file").exists());
+
+    fs::copy(dir_a.path().join("entropy_file"), dir_b.path().join("entropy_file")).unwrap();
+    let mut b = DB::open_with_passphrase(dir_b.path(), opts, b"pass").unwrap();
+    let actor_b = *b.log().actor();
+    assert_eq!(b.log().options().op_key, a.log().options().op_key);
+    assert_eq!(b.key_record().unwrap(), a.key_record().unwrap());
+    assert_eq!(read(&b), vec![Prim::Int(7)]);
+
+    assert_matches!(add(&mut b, actor_b, 8), Ok(()));
+    assert_matches!(b.sync(), Ok(()));
+    assert_matches!(a.sync(), Ok(()));
+    assert_eq!(read(&a), vec![Prim::Int(7), Prim::Int(8)]);
+}
+
+#[test]
+fn test_join_from_a_snapshot() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let c_dir = tempfile::tempdir().unwrap();
+    let usb_dir = tempfile::tempdir().unwrap();
+    let put = |db: &mut DB<git_log::Log<Actor, db::Map>>, key: &str, val: i64| {
+        let actor = *db.log().actor();
+        db.update((key.as_bytes().to_vec(), Kind::Set), actor, |data| {
+            let set = data.set().unwrap();
+            let ctx = set.read().derive_add_ctx(actor);
+            Some(Op::Set(set.add(Prim::Int(val), ctx)))
+        })
+    };
+    let get = |db: &DB<git_log::Log<Actor, db::Map>>, key: &str| {
+        db.get(&(key.as_bytes().to_vec(), Kind::Set)).unwrap().map(|data| data.set().unwrap().read().val.into_iter().collect::<Vec<_>>())
+    };
+
+    let mut a = DB::open(&a_dir.path().join("db"), db::Options::default()).unwrap();
+    let a_actor = *a.log().actor();
+    for i in 0..10 {
+        assert!(put(&mut a, "x", i).is_ok());
+    }
+    let snapshot = usb_dir.path().join("snapshot.bundle");
+    assert!(a.export_snapshot(&snapshot).is_ok());
+
+    let mut c = DB::open(&c_dir.path().join("db"), db::Options::default()).unwrap();
+    assert!(c.join_snapshot(&snapshot, "usb").is_ok());
+    assert_eq!(get(&c, "x").map(|vals| vals.len()), Some(10));
+    assert_eq!(c.log().cursors().unwrap().len(), 1);
+
+    // the ops the snapshot holds are not applied again, later ones are
+    assert!(put(&mut a, "y", 1).is_ok());
+    let a_git_dir = a_dir.path().join("db").join("git");
+    let a_remote = git_log::Log::no_auth(
+        a_actor,
+        git2::Repository::open_bare(&a_git_dir).unwrap(),
+        "a".into(),
+        a_git_dir.to_str().unwrap().to_string()
+    );
+    c.add_remote(a_remote);
+    let mut reports = Vec::new();
+    assert!(c.sync_with_progress(|p| reports.push(p)).is_ok());
+    assert!(reports.contains(&gitdb::Progress::Apply { ops_pending: 1 }));
+    assert_eq!(get(&c, "y"), Some(vec![Prim::Int(1)]));
+    assert_eq!(get(&c, "x").map(|vals| vals.len()), Some(10));
+
+    assert_matches!(c.join_snapshot(&snapshot, "usb").err(), Some(Error::State(_)));
+}
@@ -1,5 +1,6 @@
 extern crate gitdb;
 extern crate tempfile;
+extern crate bincode;
 
 #[macro_use]
 extern crate assert_matches;
@@ -9,8 +10,8 @@ extern crate quickcheck;
 
 use quickcheck::{Arbitrary, Gen, TestResult};
 
-use gitdb::crdts::{map, orswot, Map, Orswot, CmRDT};
-use gitdb::{LogReplicable, TaggedOp};
+use gitdb::crdts::{map, orswot, Map, Orswot, VClock, Dot};
+use gitdb::{LogReplicable, TaggedOp, CmRDT};
 use gitdb::memory_log;
 use gitdb::git_log;
 
@@ -35,47 +36,48 @@ impl Arbitrary for OpVec {
             let op = match die_roll % 3 {
                 0 => {
                     // update Orswot
-                    map.update(key, actor.clone(), |mut set| {
-                        let die_roll: u8 = g.gen();
-                        let member = g.gen();
-                        match die_roll % 3 {
-                            0 => {
-                                // add member
-                                Some(set.add(member, actor.clone()))
-                            },
-                            1 => {
-                                // remove member
-                                let mut clock = set.precondition_context();
-                                clock.increment(actor);
-                                Some(set.remove_with_context(member, &clock))
-                            },
-                            _ => {
-                                // rm this entry
-                                None
-                            }
+                    let die_roll: u8 = g.gen();
+                    let member = g.gen();
+                    let add_ctx = map.len().derive_add_ctx(actor);
+                    match die_roll % 3 {
+                        0 => {
+                            // add member
+                            map.update(key, add_ctx, |set, ctx| set.add(member, ctx))
+                        },
+                        1 => {
+                            // remove member
+                            map.update(key, add_ctx, |set, _| {
+                                let rm_ctx = set.contains(&member).derive_rm_ctx();
+                                set.rm(member, rm_ctx)
+                            })
+                        },
+                        _ => {
+                            // rm this entry
+                            map.rm(key, map.get(&key).derive_rm_ctx())
                         }
-                    })
+                    }
                 },
                 1 => {
                     // rm
-                    map.rm(key, actor.clone())
+                    map.rm(key, map.get(&key).derive_rm_ctx())
                 },
                 _ => {
                     // nop
                     map::Op::Nop
                 }
             };
+            map.apply(&op).unwrap();
             ops.push(op);
         }
         OpVec(actor, ops)
     }
 
-    fn shrink(&self) -> Box<Iterator<Item = Self>> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
         let mut shrunk: Vec<Self> = Vec::new();
         for i in 0..self.1.len() {
             let mut vec = self.1.clone();
             vec.remove(i);
-            shrunk.push(OpVec(self.0.clone(), vec))
+            shrunk.push(OpVec(self.0, vec))
         }
         Box::new(shrunk.into_iter())
     }    
@@ -98,7 +100,7 @@ fn p2p_pull_converge<L: LogReplicable<TActor, TMap>>(
 
     for op in b_ops {
         let tagged_op = b_log.commit(op).unwrap();
-        assert_eq!(b_map.apply(tagged_op.op()), Ok(()));
+        assert_matches!(b_map.apply(tagged_op.op()), Ok(()));
         assert_matches!(b_log.ack(&tagged_op), Ok(()));
     }
 
@@ -137,7 +139,7 @@ fn centralized_converge<L: LogReplicable<TActor, TMap>>(
 
     for op in b_ops {
         let tagged_op = b_log.commit(op).unwrap();
-        assert_eq!(b_map.apply(tagged_op.op()), Ok(()));
+        assert_matches!(b_map.apply(tagged_op.op()), Ok(()));
         assert_matches!(b_log.ack(&tagged_op), Ok(()));
     }
 
@@ -186,7 +188,109 @@ fn log_preserves_order(mut log: impl LogReplicable<TActor, TMap>, ops: Vec<TOp>)
     assert_matches!(log.next(), Ok(None));
 }
 
+fn pending_matches_next<L: LogReplicable<TActor, TMap>>(
+    mut a_log: L,
+    mut b_log: L,
+    a_ops: Vec<TOp>,
+    b_ops: Vec<TOp>
+) {
+    for op in a_ops {
+        assert_matches!(a_log.commit(op), Ok(_));
+    }
+
+    for op in b_ops {
+        assert_matches!(b_log.commit(op), Ok(_));
+    }
+
+    assert_matches!(a_log.pull(&b_log), Ok(()));
+
+    let pending: Vec<TOp> = a_log.pending().unwrap()
+        .iter()
+        .map(|tagged_op| tagged_op.op().clone())
+        .collect();
+
+    let mut acked = Vec::new();
+    while let Some(tagged_op) = a_log.next().unwrap() {
+        acked.push(tagged_op.op().clone());
+        assert_matches!(a_log.ack(&tagged_op), Ok(()));
+    }
+
+    assert_eq!(pending, acked);
+    assert_eq!(a_log.pending().unwrap().len(), 0);
+}
+
+fn cursors_round_trip<L: LogReplicable<TActor, TMap>>(mut log: L, actor: TActor, ops: Vec<TOp>) {
+    for op in ops.iter() {
+        assert_matches!(log.commit(op.clone()), Ok(_));
+    }
+
+    let half = ops.len() / 2;
+    let mut last_cursor = None;
+    for _ in 0..half {
+        let tagged_op = log.next().unwrap().unwrap();
+        last_cursor = Some(log.cursor(&tagged_op).unwrap());
+        assert_matches!(log.ack(&tagged_op), Ok(()));
+    }
+
+    let cursors = log.cursors().unwrap();
+    match last_cursor {
+        Some((cursor_actor, cursor)) => {
+            assert_eq!(cursor_actor, actor);
+            assert_eq!(cursors.get(&actor), Some(&cursor));
+        },
+        None => assert_eq!(cursors.get(&actor), None)
+    }
+
+    assert_matches!(log.reset_cursor(&actor, None), Ok(()));
+    assert_eq!(log.pending().unwrap().len(), ops.len());
+
+    assert_matches!(log.reset_cursor(&actor, cursors.get(&actor)), Ok(()));
+    assert_eq!(log.pending().unwrap().len(), ops.len() - half);
+}
+
 quickcheck! {
+    fn prop_pending_matches_next_memory(a_ops: OpVec, b_ops: OpVec) -> TestResult {
+        if a_ops.0 == b_ops.0 {
+            return TestResult::discard();
+        }
+
+        let a_log = memory_log::Log::new(a_ops.0);
+        let b_log = memory_log::Log::new(b_ops.0);
+        pending_matches_next(a_log, b_log, a_ops.1, b_ops.1);
+        TestResult::from_bool(true)
+    }
+
+    fn prop_pending_matches_next_git(a_ops: OpVec, b_ops: OpVec) -> TestResult {
+        if a_ops.0 == b_ops.0 {
+            return TestResult::discard();
+        }
+
+        let a_dir = tempfile::tempdir().unwrap();
+        let b_dir = tempfile::tempdir().unwrap();
+        let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+        let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+        let a_log = git_log::Log::no_auth(a_ops.0, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string());
+        let b_log = git_log::Log::no_auth(b_ops.0, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string());
+
+        pending_matches_next(a_log, b_log, a_ops.1, b_ops.1);
+        TestResult::from_bool(true)
+    }
+
+    fn prop_cursors_round_trip_memory(ops: OpVec) -> bool {
+        let log: memory_log::Log<u8, TMap> = memory_log::Log::new(ops.0);
+        cursors_round_trip(log, ops.0, ops.1);
+        true
+    }
+
+    fn prop_cursors_round_trip_git(ops: OpVec) -> bool {
+        let log_dir = tempfile::tempdir().unwrap();
+        let log_git = gitdb::git2::Repository::init_bare(log_dir.path()).unwrap();
+        let log_path_string = log_dir.path().to_str().unwrap().to_string();
+        let log: git_log::Log<u8, TMap> = git_log::Log::no_auth(ops.0, log_git, "log".into(), log_path_string);
+        cursors_round_trip(log, ops.0, ops.1);
+        true
+    }
+
     fn prop_replication_strategies_converges_memory(a_ops: OpVec, b_ops: OpVec) -> TestResult {
         let (actor1, a_ops) = (a_ops.0, a_ops.1);
         let (actor2, b_ops) = (b_ops.0, b_ops.1);
@@ -225,11 +329,11 @@ quickcheck! {
         let b_central_dir = tempfile::tempdir().unwrap();
         let c_central_dir = tempfile::tempdir().unwrap();
         
-        let a_pull_git = gitdb::git2::Repository::init_bare(&a_pull_dir.path()).unwrap();
-        let b_pull_git = gitdb::git2::Repository::init_bare(&b_pull_dir.path()).unwrap();
-        let a_central_git = gitdb::git2::Repository::init_bare(&a_central_dir.path()).unwrap();
-        let b_central_git = gitdb::git2::Repository::init_bare(&b_central_dir.path()).unwrap();
-        let c_central_git = gitdb::git2::Repository::init_bare(&c_central_dir.path()).unwrap();
+        let a_pull_git = gitdb::git2::Repository::init_bare(a_pull_dir.path()).unwrap();
+        let b_pull_git = gitdb::git2::Repository::init_bare(b_pull_dir.path()).unwrap();
+        let a_central_git = gitdb::git2::Repository::init_bare(a_central_dir.path()).unwrap();
+        let b_central_git = gitdb::git2::Repository::init_bare(b_central_dir.path()).unwrap();
+        let c_central_git = gitdb::git2::Repository::init_bare(c_central_dir.path()).unwrap();
         
         let a_pull = git_log::Log::no_auth(actor1, a_pull_git, "a_pull".into(), a_pull_dir.path().to_str().unwrap().to_string());
         let b_pull = git_log::Log::no_auth(actor2, b_pull_git, "b_pull".into(), b_pull_dir.path().to_str().unwrap().to_string());
@@ -245,6 +349,30 @@ quickcheck! {
         TestResult::from_bool(true)
     }
 
+    fn prop_replication_strategies_converge_git_private_refs(a_ops: OpVec, b_ops: OpVec) -> TestResult {
+        let (actor1, a_ops) = (a_ops.0, a_ops.1);
+        let (actor2, b_ops) = (b_ops.0, b_ops.1);
+
+        if actor1 == actor2 {
+            return TestResult::discard();
+        }
+
+        let dirs: Vec<_> = (0..5).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mk_log = |actor: TActor, name: &str, dir: &tempfile::TempDir| {
+            let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+            git_log::Log::no_auth(actor, git, name.into(), dir.path().to_str().unwrap().to_string())
+                .with_options(git_log::Options::private(b"ref key".to_vec()))
+        };
+
+        all_replication_strategies_converge(
+            mk_log(actor1, "a_pull", &dirs[0]), mk_log(actor2, "b_pull", &dirs[1]),
+            mk_log(actor1, "a_central", &dirs[2]), mk_log(actor2, "b_central", &dirs[3]),
+            mk_log(0, "c_central", &dirs[4]),
+            a_ops, b_ops
+        );
+        TestResult::from_bool(true)
+    }
+
     fn prop_log_preserves_order_memory(ops: OpVec) -> bool {
         let log: memory_log::Log<u8, TMap> = memory_log::Log::new(ops.0);
         log_preserves_order(log, ops.1);
@@ -254,9 +382,9 @@ quickcheck! {
     fn prop_log_preserves_order_git(ops: OpVec) -> bool {
         let log_dir = tempfile::tempdir().unwrap();
         let log_path = log_dir.path();
-        let log_git = gitdb::git2::Repository::init_bare(&log_path).unwrap();
+        let log_git = gitdb::git2::Repository::init_bare(log_path).unwrap();
         let log_path_string = log_path.to_str().unwrap().to_string();
-        let log = git_log::Log::no_auth(ops.0, log_git, "log".into(), log_path_string);;
+        let log = git_log::Log::no_auth(ops.0, log_git, "log".into(), log_path_string);
         
         log_preserves_order(log, ops.1);
 
@@ -273,11 +401,11 @@ fn test_quickcheck_1() {
     let mut b_map = TMap::new();
 
     let op = map::Op::Up {
-        clock: vec![(51, 5)].into_iter().collect(),
+        dot: Dot::new(51, 5),
         key: 3,
         op: orswot::Op::Rm {
-            clock: gitdb::crdts::VClock::new(),
-            member: 21
+            clock: VClock::new(),
+            members: vec![21].into_iter().collect()
         }
     };
     let tagged_op = b_log.commit(op).unwrap();
@@ -312,8 +440,8 @@ fn test_quickcheck_2() {
     let mut b_map = TMap::new();
 
     let op = map::Op::Rm {
-        clock: vec![(44, 17)].into_iter().collect(),
-        key: 196
+        clock: vec![Dot::new(44, 17)].into_iter().collect(),
+        keyset: vec![196].into_iter().collect()
     };
     let tagged_op = b_log.commit(op).unwrap();
     
@@ -350,8 +478,8 @@ fn test_quickcheck_3() {
     let b_log_path = b_log_dir.path();
     
     
-    let a_log_git = gitdb::git2::Repository::init_bare(&a_log_path).unwrap();
-    let b_log_git = gitdb::git2::Repository::init_bare(&b_log_path).unwrap();
+    let a_log_git = gitdb::git2::Repository::init_bare(a_log_path).unwrap();
+    let b_log_git = gitdb::git2::Repository::init_bare(b_log_path).unwrap();
 
 
     let actor1 = 1;
@@ -381,3 +509,738 @@ fn test_quickcheck_3() {
     assert_matches!(b_log.next(), Ok(None));
     assert_eq!(a_map, b_map);
 }
+
+#[test]
+fn test_commits_without_an_op_format_go_through_upgrade_op() {
+    let dir = tempfile::tempdir().unwrap();
+    let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+
+    {
+        // an op committed the way logs were written before op formats
+        let nop: TOp = map::Op::Nop;
+        let op_bytes = bincode::serialize(&nop).unwrap();
+        let mut builder = git.treebuilder(None).unwrap();
+        builder.insert("op", git.blob(&op_bytes).unwrap(), 0o100644).unwrap();
+        let tree = git.find_tree(builder.write().unwrap()).unwrap();
+        let sig = gitdb::git2::Signature::now("app", "app@example.com").unwrap();
+        git.commit(Some("refs/heads/actor_1"), &sig, &sig, "db op", &tree, &[]).unwrap();
+    }
+
+    // crdts::Map ops never changed shape, there's nothing to upgrade them from
+    let log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, git, "a".into(), dir.path().to_str().unwrap().to_string()
+    );
+    assert_matches!(log.next(), Err(gitdb::Error::Version(_)));
+}
+
+#[test]
+fn test_memory_log_cursor_past_the_end() {
+    let mut log: memory_log::Log<TActor, TMap> = memory_log::Log::new(1);
+    let op: TOp = map::Op::Nop;
+    log.commit(op).unwrap();
+
+    // state built from a longer log than this one
+    let cursor = bincode::serialize(&5u64).unwrap();
+    assert_matches!(log.reset_cursor(&1, Some(&cursor)), Ok(()));
+    assert_matches!(log.next(), Ok(None));
+    assert_eq!(log.pending().unwrap().len(), 0);
+}
+
+#[test]
+fn test_clear_records_replicate_with_the_log() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let mk_log = |actor: TActor, name: &str, dir: &tempfile::TempDir| -> git_log::Log<TActor, TMap> {
+        let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        git_log::Log::no_auth(actor, git, name.into(), path)
+            .with_options(git_log::Options::private(b"ref key".to_vec()))
+    };
+    let mut a_log = mk_log(1, "a", &a_dir);
+    let mut b_log = mk_log(2, "b", &b_dir);
+
+    a_log.put_clear("x", Some(b"from a".to_vec())).unwrap();
+    a_log.put_clear("y", Some(b"gone soon".to_vec())).unwrap();
+    a_log.put_clear("y", None).unwrap();
+    assert_matches!(a_log.put_clear("never written", None), Ok(()));
+    b_log.put_clear("x", Some(b"from b".to_vec())).unwrap();
+
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    assert_matches!(a_log.pull(&b_log), Ok(()));
+
+    // each side prefers its own copy, records are not ops
+    assert_eq!(a_log.clear_records().unwrap().get("x"), Some(&b"from a".to_vec()));
+    assert_eq!(b_log.clear_records().unwrap().get("x"), Some(&b"from b".to_vec()));
+    assert_eq!(b_log.clear_records().unwrap().get("y"), None);
+    assert_matches!(b_log.next(), Ok(None));
+}
+
+#[test]
+fn test_private_refs_hide_actors_and_acks() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let c_dir = tempfile::tempdir().unwrap();
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let c_git = gitdb::git2::Repository::init_bare(c_dir.path()).unwrap();
+
+    let opts = git_log::Options::private(b"ref key".to_vec());
+    let mut a_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        42, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string()
+    ).with_options(opts.clone());
+    let mut c_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        0, c_git, "c".into(), c_dir.path().to_str().unwrap().to_string()
+    ).with_options(opts);
+
+    let tagged_op = a_log.commit(map::Op::Nop).unwrap();
+    assert_matches!(a_log.ack(&tagged_op), Ok(()));
+    assert_eq!(a_log.cursors().unwrap().keys().collect::<Vec<_>>(), vec![&42]);
+    assert_matches!(a_log.push(&mut c_log), Ok(()));
+
+    let c_git = gitdb::git2::Repository::open_bare(c_dir.path()).unwrap();
+    let branches: Vec<String> = c_git.branches(Some(gitdb::git2::BranchType::Local)).unwrap()
+        .map(|b| b.unwrap().0.name().unwrap().unwrap().to_string())
+        .collect();
+    assert_eq!(branches.len(), 1);
+    assert!(branches[0].starts_with("log_"));
+    assert!(!branches[0].contains("42"));
+
+    // the remote's replicas recover the actor from the commits
+    let b_dir = tempfile::tempdir().unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    ).with_options(git_log::Options::private(b"ref key".to_vec()));
+    assert_matches!(b_log.pull(&c_log), Ok(()));
+    let remote_op = b_log.next().unwrap().unwrap();
+    assert_eq!(remote_op.op(), &map::Op::Nop);
+    assert_eq!(b_log.cursor(&remote_op).unwrap().0, 42);
+}
+
+#[test]
+fn test_private_refs_keep_the_actor_out_of_commits() {
+    let actor: u128 = 0x1234_5678_9abc_def0_1122_3344_5566_7788;
+    let actor_str = actor.to_string();
+    let contains = |bytes: &[u8]| {
+        bytes.windows(actor_str.len()).any(|w| w == actor_str.as_bytes())
+    };
+
+    // the actor stays out of commits whether or not the ops are sealed
+    let private = git_log::Options::private(b"ref key".to_vec());
+    let op_key = gitdb::crypto::MasterKey::generate().unwrap();
+    for opts in [private.clone(), private.sealed(op_key)] {
+        let dir = tempfile::tempdir().unwrap();
+        let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+        let mut log: git_log::Log<u128, TMap> = git_log::Log::no_auth(
+            actor, git, "a".into(), dir.path().to_str().unwrap().to_string()
+        ).with_options(opts.clone());
+        for _ in 0..2 {
+            let tagged_op = log.commit(map::Op::Nop).unwrap();
+            assert_matches!(log.ack(&tagged_op), Ok(()));
+        }
+
+        let git = gitdb::git2::Repository::open_bare(dir.path()).unwrap();
+        let mut walk = git.revwalk().unwrap();
+        walk.push_glob("*").unwrap();
+        let mut commits = 0;
+        for oid in walk {
+            let commit = git.find_commit(oid.unwrap()).unwrap();
+            commits += 1;
+            assert!(!contains(commit.message_bytes()));
+            assert!(!contains(commit.author().name_bytes()));
+            assert!(!contains(commit.author().email_bytes()));
+            for entry in commit.tree().unwrap().iter() {
+                let blob = git.find_blob(entry.id()).unwrap();
+                assert!(!contains(blob.content()), "{} blob names the actor", entry.name().unwrap());
+            }
+        }
+        assert_eq!(commits, 2);
+
+        // replicas holding the key still recover the actor
+        let git = gitdb::git2::Repository::open_bare(dir.path()).unwrap();
+        let reopened: git_log::Log<u128, TMap> = git_log::Log::no_auth(
+            actor, git, "a".into(), dir.path().to_str().unwrap().to_string()
+        ).with_options(opts);
+        assert_eq!(reopened.cursors().unwrap().keys().collect::<Vec<_>>(), vec![&actor]);
+    }
+}
+
+#[test]
+fn test_sealed_ops_are_bound_to_their_position() {
+    let dir = tempfile::tempdir().unwrap();
+    let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+    let key = gitdb::crypto::MasterKey::generate().unwrap();
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        7, git, "log".into(), dir.path().to_str().unwrap().to_string()
+    ).with_options(git_log::Options::default().sealed(key));
+
+    let first = log.commit(map::Op::Nop).unwrap();
+    let second = log.commit(map::Op::Nop).unwrap();
+    let ids: Vec<_> = log.pending().unwrap().iter().map(|op| op.id()).collect();
+    assert_eq!(ids, vec![first.id(), second.id()]);
+
+    // the remote host replays the first op on top of the second
+    let git = gitdb::git2::Repository::open_bare(dir.path()).unwrap();
+    let replayed_tree = git.find_commit(first.id()).unwrap().tree().unwrap();
+    let parent = git.find_commit(second.id()).unwrap();
+    let sig = gitdb::git2::Signature::now("host", "host@example.com").unwrap();
+    git.commit(Some("refs/heads/actor_7"), &sig, &sig, "db op", &replayed_tree, &[&parent])
+        .unwrap();
+
+    assert_matches!(log.pending(), Err(gitdb::Error::Crypto(_)));
+}
+
+#[test]
+fn test_rotated_op_keys_open_older_ops() {
+    let dir = tempfile::tempdir().unwrap();
+    let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+    let old_key = gitdb::crypto::MasterKey::generate().unwrap();
+    let new_key = gitdb::crypto::MasterKey::generate().unwrap();
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        7, git, "log".into(), dir.path().to_str().unwrap().to_string()
+    ).with_options(git_log::Options::default().sealed(old_key.clone()));
+
+    let before = log.commit(map::Op::Nop).unwrap();
+    assert_matches!(log.rotate_key(&new_key), Ok(()));
+    let after = log.commit(map::Op::Nop).unwrap();
+    assert_eq!(log.options().op_key, Some(new_key.clone()));
+    assert_eq!(log.options().retired_op_keys, vec![old_key.clone()]);
+
+    let ids: Vec<_> = log.pending().unwrap().iter().map(|op| op.id()).collect();
+    assert_eq!(ids, vec![before.id(), after.id()]);
+
+    let git = gitdb::git2::Repository::open_bare(dir.path()).unwrap();
+    let sealed_with = |id: gitdb::git2::Oid| {
+        let tree = git.find_commit(id).unwrap().tree().unwrap();
+        let blob = git.find_blob(tree.get_name("op").unwrap().id()).unwrap();
+        let sealed: gitdb::crypto::Encrypted = bincode::deserialize(blob.content()).unwrap();
+        sealed.key_id
+    };
+    assert_eq!(sealed_with(before.id()), old_key.id());
+    assert_eq!(sealed_with(after.id()), new_key.id());
+
+    // without the retired key the older op no longer opens
+    let git = gitdb::git2::Repository::open_bare(dir.path()).unwrap();
+    let fresh: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        7, git, "log".into(), dir.path().to_str().unwrap().to_string()
+    ).with_options(git_log::Options::default().sealed(new_key));
+    assert_matches!(fresh.pending(), Err(gitdb::Error::Crypto(_)));
+}
+
+#[test]
+fn test_namespaced_logs_share_a_repo() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a_path = a_dir.path().to_str().unwrap().to_string();
+    let b_path = b_dir.path().to_str().unwrap().to_string();
+
+    // the app's own branch lives next to two databases
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let sig = gitdb::git2::Signature::now("app", "app@example.com").unwrap();
+    let empty_tree = a_git.find_tree(a_git.treebuilder(None).unwrap().write().unwrap()).unwrap();
+    let app_commit = a_git.commit(Some("refs/heads/main"), &sig, &sig, "app data", &empty_tree, &[])
+        .unwrap();
+
+    let open = |path: &std::path::Path| gitdb::git2::Repository::open_bare(path).unwrap();
+    let mut notes: git_log::Log<TActor, TMap> = git_log::Log::no_auth(1, open(a_dir.path()), "a".into(), a_path.clone())
+        .with_options(git_log::Options::default().namespaced("notes"));
+    let mut todo: git_log::Log<TActor, TMap> = git_log::Log::no_auth(1, open(a_dir.path()), "a".into(), a_path.clone())
+        .with_options(git_log::Options::default().namespaced("todo"));
+
+    notes.commit(map::Op::Nop).unwrap();
+    todo.commit(map::Op::Nop).unwrap();
+    todo.commit(map::Op::Nop).unwrap();
+    assert_eq!(notes.pending().unwrap().len(), 1);
+    assert_eq!(todo.pending().unwrap().len(), 2);
+
+    let ref_names = |git: &gitdb::git2::Repository| -> Vec<String> {
+        git.references().unwrap()
+            .map(|r| r.unwrap().name().unwrap().to_string())
+            .collect()
+    };
+    let a_refs = ref_names(&a_git);
+    assert!(a_refs.contains(&"refs/hermitdb/notes/local/actor_1".to_string()));
+    assert!(a_refs.contains(&"refs/hermitdb/todo/local/actor_1".to_string()));
+    assert!(!a_refs.iter().any(|r| r.starts_with("refs/heads/actor_")));
+    assert_eq!(a_git.refname_to_id("refs/heads/main").unwrap(), app_commit);
+
+    // a replica of the notes db only sees the notes refs
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut b_notes: git_log::Log<TActor, TMap> = git_log::Log::no_auth(2, b_git, "b".into(), b_path)
+        .with_options(git_log::Options::default().namespaced("notes"));
+    assert_matches!(b_notes.pull(&notes), Ok(()));
+    assert_eq!(b_notes.pending().unwrap().len(), 1);
+
+    assert_matches!(notes.push(&mut b_notes), Ok(()));
+    let b_refs = ref_names(&open(b_dir.path()));
+    assert!(b_refs.iter().all(|r| r.starts_with("refs/hermitdb/notes/")));
+}
+
+#[test]
+fn test_namespaced_logs_sync_repeatedly() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a_path = a_dir.path().to_str().unwrap().to_string();
+    let b_path = b_dir.path().to_str().unwrap().to_string();
+
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut a: git_log::Log<TActor, TMap> = git_log::Log::no_auth(1, a_git, "a".into(), a_path)
+        .with_options(git_log::Options::default().namespaced("notes"));
+    let mut b: git_log::Log<TActor, TMap> = git_log::Log::no_auth(2, b_git, "b".into(), b_path)
+        .with_options(git_log::Options::default().namespaced("notes"));
+
+    // commit, then sync: pull, ack what is pending, push
+    for _ in 0..2 {
+        let tagged_op = a.commit(map::Op::Nop).unwrap();
+        assert_matches!(a.pull(&b), Ok(()));
+        let pending = a.pending().unwrap();
+        assert_eq!(pending.iter().map(|op| op.id()).collect::<Vec<_>>(), vec![tagged_op.id()]);
+        assert_matches!(a.ack(&tagged_op), Ok(()));
+        assert_matches!(a.push(&mut b), Ok(()));
+
+        // our branch on the remote is where we left it, nothing to read
+        let a_git = gitdb::git2::Repository::open_bare(a_dir.path()).unwrap();
+        assert_eq!(
+            a_git.refname_to_id("refs/hermitdb/notes/remotes/b/actor_1").unwrap(),
+            tagged_op.id()
+        );
+        assert_eq!(a.pending().unwrap().len(), 0);
+    }
+}
+
+#[test]
+fn test_bad_histories_are_errors_or_quarantined() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut a_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string()
+    );
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        2, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    );
+
+    // someone merges an unrelated commit into a's log
+    let first = a_log.commit(map::Op::Nop).unwrap();
+    let a_git = gitdb::git2::Repository::open_bare(a_dir.path()).unwrap();
+    let sig = gitdb::git2::Signature::now("host", "host@example.com").unwrap();
+    let first_commit = a_git.find_commit(first.id()).unwrap();
+    let tree = first_commit.tree().unwrap();
+    let stray = a_git.find_commit(a_git.commit(None, &sig, &sig, "db op", &tree, &[]).unwrap()).unwrap();
+    a_git.commit(Some("refs/heads/actor_1"), &sig, &sig, "merge", &tree, &[&first_commit, &stray])
+        .unwrap();
+
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    assert_matches!(b_log.next(), Err(gitdb::Error::NonLinearHistory(_)));
+    assert_matches!(b_log.pending(), Err(gitdb::Error::NonLinearHistory(_)));
+
+    let b_git = gitdb::git2::Repository::open_bare(b_dir.path()).unwrap();
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        2, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    ).with_options(git_log::Options { quarantine: true, ..git_log::Options::default() });
+    // reading the log never moves refs, the branch is set aside when verified
+    assert_matches!(b_log.next(), Err(gitdb::Error::NonLinearHistory(_)));
+    assert!(b_log.quarantined().unwrap().is_empty());
+    assert_matches!(b_log.verify_remotes(), Ok(()));
+    assert_matches!(b_log.next(), Ok(None));
+    assert_matches!(b_log.pending(), Ok(ref ops) if ops.is_empty());
+    let quarantined: Vec<String> = b_log.quarantined().unwrap().into_iter().map(|(b, _)| b).collect();
+    assert_eq!(quarantined, vec!["a/actor_1".to_string()]);
+
+    // a force pushes a history that no longer holds what b acked
+    a_git.reference("refs/heads/actor_1", first.id(), true, "repair").unwrap();
+    b_log.release("a/actor_1").unwrap();
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    let op = b_log.next().unwrap().unwrap();
+    assert_matches!(b_log.ack(&op), Ok(()));
+
+    a_git.reference("refs/heads/actor_1", stray.id(), true, "rewrite").unwrap();
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    assert_matches!(b_log.next(), Ok(None));
+    assert_eq!(b_log.quarantined().unwrap().len(), 1);
+
+    // our own acks pointing past a missing log can't be quarantined
+    a_git.find_reference("refs/heads/actor_1").unwrap().delete().unwrap();
+    a_git.reference("refs/heads/acked_actor_1", first.id(), true, "ack").unwrap();
+    assert_matches!(a_log.next(), Err(gitdb::Error::RewrittenHistory(_)));
+}
+
+#[test]
+fn test_ops_with_malformed_commit_ids_fail_to_deserialize() {
+    let dir = tempfile::tempdir().unwrap();
+    let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        7, git, "log".into(), dir.path().to_str().unwrap().to_string()
+    );
+    let tagged_op = log.commit(map::Op::Nop).unwrap();
+
+    let bytes = bincode::serialize(&tagged_op).unwrap();
+    let round_trip: git_log::Op<TActor, TMap> = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(round_trip, tagged_op);
+
+    // actor, then the commit id cut down to 3 bytes, then the op
+    let mut truncated = vec![7u8];
+    truncated.extend(bincode::serialize(&vec![1u8, 2, 3]).unwrap());
+    truncated.extend_from_slice(&bytes[1 + 8 + 20..]);
+    assert!(bincode::deserialize::<git_log::Op<TActor, TMap>>(&truncated).is_err());
+}
+
+#[test]
+fn test_quarantine_spares_our_lagging_branch_and_catches_misnamed_ones() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let opts = git_log::Options {
+        quarantine: true,
+        ..git_log::Options::private(b"ref key".to_vec())
+    };
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut a_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string()
+    ).with_options(opts.clone());
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        2, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    ).with_options(opts);
+
+    // b's branch on a lags behind b once b commits again
+    let pushed = b_log.commit(map::Op::Nop).unwrap();
+    assert_matches!(b_log.ack(&pushed), Ok(()));
+    assert_matches!(b_log.push(&mut a_log), Ok(()));
+    let newer = b_log.commit(map::Op::Nop).unwrap();
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    assert!(b_log.quarantined().unwrap().is_empty());
+    let ids: Vec<_> = b_log.pending().unwrap().iter().map(|op| op.id()).collect();
+    assert_eq!(ids, vec![newer.id()]);
+
+    // the host copies a's ops under a branch name that isn't a's
+    let first = a_log.commit(map::Op::Nop).unwrap();
+    let a_git = gitdb::git2::Repository::open_bare(a_dir.path()).unwrap();
+    a_git.reference("refs/heads/log_0000", first.id(), true, "misname").unwrap();
+    assert_matches!(b_log.pull(&a_log), Ok(()));
+    let quarantined: Vec<String> = b_log.quarantined().unwrap().into_iter().map(|(b, _)| b).collect();
+    assert_eq!(quarantined, vec!["a/log_0000".to_string()]);
+    let ids: Vec<_> = b_log.pending().unwrap().iter().map(|op| op.id()).collect();
+    assert_eq!(ids, vec![newer.id(), first.id()]);
+}
+
+#[test]
+fn test_push_rejections_are_reported_per_ref() {
+    let dirs: Vec<_> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+    let mk_log = |actor: TActor, i: usize, name: &str| -> git_log::Log<TActor, TMap> {
+        let git = gitdb::git2::Repository::init_bare(dirs[i].path()).unwrap();
+        git_log::Log::no_auth(actor, git, name.into(), dirs[i].path().to_str().unwrap().to_string())
+    };
+    // one actor id restored from a backup onto two devices
+    let mut device_1 = mk_log(1, 0, "device_1");
+    let mut device_2 = mk_log(1, 1, "device_2");
+    let mut other = mk_log(2, 2, "other");
+    let mut central = mk_log(0, 3, "central");
+
+    device_1.commit(map::Op::Nop).unwrap();
+    assert_matches!(device_1.push(&mut central), Ok(()));
+
+    // other falls behind on actor 1's log and retries once it sees it's behind
+    assert_matches!(other.pull(&central), Ok(()));
+    let op = other.next().unwrap().unwrap();
+    assert_matches!(other.ack(&op), Ok(()));
+    device_1.commit(map::Op::Nop).unwrap();
+    assert_matches!(device_1.push(&mut central), Ok(()));
+    other.commit(map::Op::Nop).unwrap();
+    let results = other.push_refs(&central, &mut |_| ()).unwrap();
+    assert!(results.contains(&git_log::PushResult {
+        branch: "actor_1".into(),
+        status: git_log::PushStatus::UpToDate
+    }));
+    assert!(results.contains(&git_log::PushResult {
+        branch: "actor_2".into(),
+        status: git_log::PushStatus::Updated
+    }));
+
+    // the second device's history diverged from the first's
+    let map = TMap::new();
+    device_2.commit(map.rm(9, map.get(&9).derive_rm_ctx())).unwrap();
+    match device_2.push(&mut central) {
+        Err(gitdb::Error::PushRejected(rejected)) => {
+            assert_eq!(rejected.len(), 1);
+            assert_eq!(rejected[0].branch, "actor_1");
+        },
+        res => panic!("expected a push rejection, got {:?}", res)
+    }
+}
+
+#[test]
+fn test_fetch_and_push_report_progress() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut a_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string()
+    );
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        2, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    );
+    let map = TMap::new();
+    for i in 0..20 {
+        a_log.commit(map.rm(i, map.get(&i).derive_rm_ctx())).unwrap();
+    }
+
+    let mut fetched = Vec::new();
+    assert_matches!(b_log.pull_with_progress(&a_log, &mut |p| fetched.push(p)), Ok(()));
+    let last_fetch = fetched.into_iter().filter_map(|p| match p {
+        gitdb::Progress::Fetch { received_objects, total_objects, .. } =>
+            Some((received_objects, total_objects)),
+        _ => None
+    }).next_back();
+    match last_fetch {
+        Some((received, total)) => {
+            assert!(total > 0);
+            assert_eq!(received, total);
+        },
+        None => panic!("no fetch progress was reported")
+    }
+    assert_eq!(b_log.pending().unwrap().len(), 20);
+
+    b_log.commit(map::Op::Nop).unwrap();
+    let mut pushed = Vec::new();
+    assert_matches!(b_log.push_with_progress(&mut a_log, &mut |p| pushed.push(p)), Ok(()));
+    assert!(pushed.iter().all(|p| matches!(p, gitdb::Progress::Push { .. } | gitdb::Progress::Fetch { .. })));
+    let last_push = pushed.into_iter().filter_map(|p| match p {
+        gitdb::Progress::Push { pushed_refs, total_refs } => Some((pushed_refs, total_refs)),
+        _ => None
+    }).next_back();
+    assert_matches!(last_push, Some((pushed, total)) if total > 0 && pushed == total);
+}
+
+fn summarize(op: &TOp) -> git_log::OpSummary {
+    let kind = match op {
+        map::Op::Nop => "nop",
+        _ => "edit"
+    };
+    git_log::OpSummary { kind: kind.into(), key: Some("the key".into()) }
+}
+
+#[test]
+fn test_commit_identity_and_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_str().unwrap().to_string();
+    let open = || gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+    let author = |log: &mut git_log::Log<TActor, TMap>| -> (String, String, String) {
+        let oid = log.commit(map::Op::Nop).unwrap().id();
+        let git = open();
+        let commit = git.find_commit(oid).unwrap();
+        let author = commit.author();
+        (
+            author.name().unwrap().to_string(),
+            author.email().unwrap().to_string(),
+            commit.message().unwrap().to_string()
+        )
+    };
+
+    // no user.name or user.email needed, and no real name leaks
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(3, open(), "log".into(), path.clone());
+    assert_eq!(author(&mut log), ("hermitdb actor-3".into(), "actor-3@hermitdb.invalid".into(), "db op".into()));
+
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(4, open(), "log".into(), path.clone())
+        .with_options(git_log::Options::private(b"ref key".to_vec()));
+    let (name, email, _) = author(&mut log);
+    // hashed ref names hide the actor in the identity too
+    assert!(!name.contains("actor"));
+    assert!(!email.contains("actor"));
+    assert!(email.ends_with("@hermitdb.invalid"));
+
+    let opts = git_log::Options {
+        identity: git_log::Identity::Named { name: "Ada".into(), email: "ada@example.com".into() },
+        message: "{kind} of {key}".into(),
+        ..git_log::Options::default()
+    };
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(5, open(), "log".into(), path.clone())
+        .with_options(opts.clone())
+        .with_summary(summarize);
+    assert_eq!(author(&mut log), ("Ada".into(), "ada@example.com".into(), "nop of".into()));
+
+    // keys are only named when asked for
+    let opts = git_log::Options { disclose_keys: true, ..opts };
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(5, open(), "log".into(), path.clone())
+        .with_options(opts.clone())
+        .with_summary(summarize);
+    assert_eq!(author(&mut log).2, "nop of the key".to_string());
+
+    // sealed ops keep their keys out of commit messages regardless
+    let key = gitdb::crypto::MasterKey::generate().unwrap();
+    let mut log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(6, open(), "log".into(), path)
+        .with_options(opts.sealed(key))
+        .with_summary(summarize);
+    assert_eq!(author(&mut log).2, "nop of".to_string());
+}
+
+#[test]
+fn test_bundles_carry_ops_between_offline_repos() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let c_dir = tempfile::tempdir().unwrap();
+    let usb_dir = tempfile::tempdir().unwrap();
+
+    let mk_log = |actor: TActor, name: &str, dir: &tempfile::TempDir| -> git_log::Log<TActor, TMap> {
+        let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        git_log::Log::no_auth(actor, git, name.into(), path)
+    };
+    let mut a_log = mk_log(1, "a", &a_dir);
+    let mut b_log = mk_log(2, "b", &b_dir);
+    let mut c_log = mk_log(3, "c", &c_dir);
+
+    a_log.commit(map::Op::Nop).unwrap();
+    a_log.commit(map::Op::Nop).unwrap();
+    let full = usb_dir.path().join("full.bundle");
+    assert_matches!(a_log.export_bundle(&full, &std::collections::BTreeMap::new()), Ok(()));
+
+    assert_matches!(b_log.import_bundle(&full, "usb"), Ok(()));
+    let pending = b_log.pending().unwrap();
+    assert_eq!(pending.len(), 2);
+    for op in pending.iter() {
+        b_log.ack(op).unwrap();
+    }
+
+    // only what b has not acked goes into the next bundle
+    a_log.commit(map::Op::Nop).unwrap();
+    let incremental = usb_dir.path().join("incremental.bundle");
+    assert_matches!(a_log.export_bundle(&incremental, &b_log.cursors().unwrap()), Ok(()));
+    assert_matches!(b_log.import_bundle(&incremental, "usb"), Ok(()));
+    assert_eq!(b_log.pending().unwrap().len(), 1);
+
+    // c never saw the commits the incremental bundle builds on
+    assert_matches!(c_log.import_bundle(&incremental, "usb"), Err(gitdb::Error::State(_)));
+    assert_eq!(c_log.pending().unwrap().len(), 0);
+
+    let junk = usb_dir.path().join("junk.bundle");
+    std::fs::write(&junk, b"not a bundle at all").unwrap();
+    assert_matches!(c_log.import_bundle(&junk, "usb"), Err(gitdb::Error::Parse(_)));
+
+    // the importer names the remote the branches land under
+    let b_git = gitdb::git2::Repository::open_bare(b_dir.path()).unwrap();
+    assert!(b_git.find_reference("refs/remotes/usb/actor_1").is_ok());
+    assert!(b_git.find_reference("refs/remotes/a/actor_1").is_err());
+    assert_matches!(b_log.import_bundle(&full, "../a"), Err(gitdb::Error::State(_)));
+
+    // bundles that inflate past the limit are refused
+    let small = git_log::Options { max_bundle_size: 64, ..git_log::Options::default() };
+    let d_dir = tempfile::tempdir().unwrap();
+    let mut d_log = mk_log(4, "d", &d_dir).with_options(small.clone());
+    assert_matches!(d_log.import_bundle(&full, "usb"), Err(gitdb::Error::Corrupt(_)));
+    let a_small = mk_log(1, "a", &a_dir).with_options(small);
+    let too_big = usb_dir.path().join("too_big.bundle");
+    assert_matches!(
+        a_small.export_bundle(&too_big, &std::collections::BTreeMap::new()),
+        Err(gitdb::Error::State(_))
+    );
+}
+
+#[test]
+fn test_snapshots_skip_the_ops_before_their_cursors() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let c_dir = tempfile::tempdir().unwrap();
+    let usb_dir = tempfile::tempdir().unwrap();
+
+    let mk_log = |actor: TActor, name: &str, dir: &tempfile::TempDir| -> git_log::Log<TActor, TMap> {
+        let git = gitdb::git2::Repository::init_bare(dir.path()).unwrap();
+        let path = dir.path().to_str().unwrap().to_string();
+        git_log::Log::no_auth(actor, git, name.into(), path)
+    };
+    let mut a_log = mk_log(1, "a", &a_dir);
+    let mut b_log = mk_log(2, "b", &b_dir);
+    let mut c_log = mk_log(3, "c", &c_dir);
+
+    for i in 0..20 {
+        a_log.commit(TMap::new().rm(i, TMap::new().get(&i).derive_rm_ctx())).unwrap();
+    }
+    b_log.pull(&a_log).unwrap();
+    for op in b_log.pending().unwrap().iter() {
+        b_log.ack(op).unwrap();
+    }
+
+    let snapshot = usb_dir.path().join("snapshot.bundle");
+    let b_cursors = b_log.cursors().unwrap();
+    assert_matches!(b_log.export_snapshot(&snapshot, b"state", &b_cursors), Ok(()));
+
+    // snapshots are joined from, not imported as plain bundles
+    assert_matches!(c_log.import_bundle(&snapshot, "usb"), Err(gitdb::Error::State(_)));
+    let (state, cursors) = c_log.import_snapshot(&snapshot, "usb").unwrap();
+    assert_eq!(state, b"state".to_vec());
+    assert_eq!(cursors, b_cursors);
+    for (actor, cursor) in cursors.iter() {
+        c_log.reset_cursor(actor, Some(cursor)).unwrap();
+    }
+    assert_eq!(c_log.pending().unwrap().len(), 0);
+
+    // the commits before the cursor came without their ops
+    let c_git = gitdb::git2::Repository::open_bare(c_dir.path()).unwrap();
+    let cursor = gitdb::git2::Oid::from_bytes(&cursors[&1]).unwrap();
+    let mut walk = c_git.revwalk().unwrap();
+    walk.push(cursor).unwrap();
+    let history: Vec<gitdb::git2::Oid> = walk.map(|oid| oid.unwrap()).collect();
+    assert_eq!(history.len(), 20);
+    for oid in history.iter().filter(|oid| **oid != cursor) {
+        let commit = c_git.find_commit(*oid).unwrap();
+        assert!(c_git.find_tree(commit.tree_id()).is_err());
+    }
+
+    // fetching from the source only brings what came after the snapshot
+    a_log.commit(TMap::new().rm(20, TMap::new().get(&20).derive_rm_ctx())).unwrap();
+    c_log.pull(&a_log).unwrap();
+    let pending = c_log.pending().unwrap();
+    assert_eq!(pending.len(), 1);
+    c_log.ack(&pending[0]).unwrap();
+    assert_eq!(c_log.pending().unwrap().len(), 0);
+
+    // a snapshot only seeds actors we know nothing about
+    assert_matches!(c_log.import_snapshot(&snapshot, "usb"), Err(gitdb::Error::State(_)));
+    assert_matches!(b_log.import_snapshot(&snapshot, "usb"), Err(gitdb::Error::State(_)));
+    let plain = usb_dir.path().join("plain.bundle");
+    a_log.export_bundle(&plain, &std::collections::BTreeMap::new()).unwrap();
+    let d_dir = tempfile::tempdir().unwrap();
+    let mut d_log = mk_log(4, "d", &d_dir);
+    assert_matches!(d_log.import_snapshot(&plain, "usb"), Err(gitdb::Error::State(_)));
+}
+
+#[test]
+fn test_refetch_only_transfers_new_commits() {
+    let a_dir = tempfile::tempdir().unwrap();
+    let b_dir = tempfile::tempdir().unwrap();
+    let a_git = gitdb::git2::Repository::init_bare(a_dir.path()).unwrap();
+    let b_git = gitdb::git2::Repository::init_bare(b_dir.path()).unwrap();
+    let mut a_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        1, a_git, "a".into(), a_dir.path().to_str().unwrap().to_string()
+    );
+    let mut b_log: git_log::Log<TActor, TMap> = git_log::Log::no_auth(
+        2, b_git, "b".into(), b_dir.path().to_str().unwrap().to_string()
+    );
+
+    let fetch_total = |b_log: &mut git_log::Log<TActor, TMap>, a_log: &git_log::Log<TActor, TMap>| {
+        let mut total = 0;
+        b_log.pull_with_progress(a_log, &mut |p| {
+            if let gitdb::Progress::Fetch { total_objects, .. } = p {
+                total = total_objects;
+            }
+        }).unwrap();
+        total
+    };
+
+    for i in 0..20 {
+        a_log.commit(TMap::new().rm(i, TMap::new().get(&i).derive_rm_ctx())).unwrap();
+    }
+    let first = fetch_total(&mut b_log, &a_log);
+    for op in b_log.pending().unwrap().iter() {
+        b_log.ack(op).unwrap();
+    }
+
+    a_log.commit(TMap::new().rm(20, TMap::new().get(&20).derive_rm_ctx())).unwrap();
+    let second = fetch_total(&mut b_log, &a_log);
+
+    // the new commit, its tree, op blob and the op format blob, which
+    // libgit2 packs again although we have it
+    assert!(second > 0 && second <= 4, "refetched {} objects", second);
+    assert!(second < first);
+    assert_eq!(b_log.pending().unwrap().len(), 1);
+}